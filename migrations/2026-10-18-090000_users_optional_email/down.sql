@@ -0,0 +1,19 @@
+CREATE TABLE users_new (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  username VARCHAR(40) NOT NULL UNIQUE,
+  password VARCHAR(64) NOT NULL,
+  register_date DATETIME NOT NULL,
+  last_login DATETIME NOT NULL,
+  last_ip VARCHAR(15) NOT NULL,
+  last_agent VARCHAR(254) NOT NULL,
+  last_sys_id VARCHAR(16) NOT NULL,
+  last_mac_id VARCHAR(16) NOT NULL,
+  ingame_time INTEGER NOT NULL,
+  access VARCHAR(32) NOT NULL,
+  email VARCHAR(254) NOT NULL UNIQUE,
+  bot INTEGER NOT NULL
+);
+INSERT INTO users_new SELECT id, username, password, register_date, last_login, last_ip,
+  last_agent, last_sys_id, last_mac_id, ingame_time, access, COALESCE(email, username), bot FROM users;
+DROP TABLE users;
+ALTER TABLE users_new RENAME TO users;
@@ -0,0 +1,8 @@
+CREATE TABLE ignores (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  ignored_user_id INTEGER NOT NULL REFERENCES users(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  reason VARCHAR(128),
+  time DATETIME NOT NULL,
+  UNIQUE (user_id, ignored_user_id)
+);
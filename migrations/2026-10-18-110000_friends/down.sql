@@ -0,0 +1,2 @@
+DROP TABLE friend_requests;
+DROP TABLE friends;
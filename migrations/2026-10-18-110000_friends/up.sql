@@ -0,0 +1,14 @@
+CREATE TABLE friends (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  first_user_id INTEGER NOT NULL REFERENCES users(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  second_user_id INTEGER NOT NULL REFERENCES users(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  time DATETIME NOT NULL
+);
+CREATE TABLE friend_requests (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  friend_user_id INTEGER NOT NULL REFERENCES users(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  msg VARCHAR(128),
+  time DATETIME NOT NULL,
+  UNIQUE (user_id, friend_user_id)
+);
@@ -0,0 +1,2 @@
+DROP TABLE channel_history;
+DROP TABLE channels;
@@ -0,0 +1,22 @@
+CREATE TABLE channels (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name VARCHAR(40) NOT NULL UNIQUE,
+  key VARCHAR(32),
+  owner_user_id INTEGER REFERENCES users(id) ON UPDATE CASCADE ON DELETE SET NULL,
+  topic TEXT,
+  topic_user_id INTEGER REFERENCES users(id) ON UPDATE CASCADE ON DELETE SET NULL,
+  antispam BOOLEAN NOT NULL DEFAULT 0,
+  censor BOOLEAN NOT NULL DEFAULT 0,
+  store_history BOOLEAN NOT NULL DEFAULT 0,
+  last_used DATETIME NOT NULL
+);
+CREATE TABLE channel_history (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  channel_id INTEGER NOT NULL REFERENCES channels(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  user_id INTEGER REFERENCES users(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  time DATETIME NOT NULL,
+  msg TEXT NOT NULL,
+  ex_msg BOOLEAN NOT NULL
+);
+CREATE INDEX channel_history_channel_id ON channel_history (channel_id, id);
+CREATE INDEX channel_history_time ON channel_history (time);
@@ -0,0 +1,16 @@
+CREATE TABLE channels_new (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name VARCHAR(40) NOT NULL UNIQUE,
+  key VARCHAR(32),
+  owner_user_id INTEGER REFERENCES users(id) ON UPDATE CASCADE ON DELETE SET NULL,
+  topic TEXT,
+  topic_user_id INTEGER REFERENCES users(id) ON UPDATE CASCADE ON DELETE SET NULL,
+  antispam BOOLEAN NOT NULL DEFAULT 0,
+  censor BOOLEAN NOT NULL DEFAULT 0,
+  store_history BOOLEAN NOT NULL DEFAULT 0,
+  last_used DATETIME NOT NULL
+);
+INSERT INTO channels_new SELECT id, name, key, owner_user_id, topic, topic_user_id,
+  antispam, censor, store_history, last_used FROM channels;
+DROP TABLE channels;
+ALTER TABLE channels_new RENAME TO channels;
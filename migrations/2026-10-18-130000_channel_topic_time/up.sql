@@ -0,0 +1 @@
+ALTER TABLE channels ADD COLUMN topic_time DATETIME;
@@ -0,0 +1,19 @@
+CREATE TABLE channel_mutes (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  channel_id INTEGER NOT NULL REFERENCES channels(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  issuer_user_id INTEGER REFERENCES users(id) ON UPDATE CASCADE ON DELETE SET NULL,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  expires DATETIME NOT NULL,
+  reason TEXT NOT NULL,
+  UNIQUE (channel_id, user_id)
+);
+CREATE TABLE channel_bans (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  channel_id INTEGER NOT NULL REFERENCES channels(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  issuer_user_id INTEGER REFERENCES users(id) ON UPDATE CASCADE ON DELETE SET NULL,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  ip_address VARCHAR(15) NOT NULL,
+  expires DATETIME NOT NULL,
+  reason TEXT NOT NULL,
+  UNIQUE (channel_id, user_id)
+);
@@ -0,0 +1,6 @@
+CREATE TABLE channel_forwards (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  channel_from_id INTEGER NOT NULL REFERENCES channels(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  channel_to_id INTEGER NOT NULL REFERENCES channels(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  UNIQUE (channel_from_id, channel_to_id)
+);
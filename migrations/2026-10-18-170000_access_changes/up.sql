@@ -0,0 +1,9 @@
+CREATE TABLE access_changes (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON UPDATE CASCADE ON DELETE CASCADE,
+  issuer_user_id INTEGER REFERENCES users(id) ON UPDATE CASCADE ON DELETE SET NULL,
+  old_access VARCHAR(32) NOT NULL,
+  new_access VARCHAR(32) NOT NULL,
+  time DATETIME NOT NULL
+);
+CREATE INDEX access_changes_user_id ON access_changes (user_id);
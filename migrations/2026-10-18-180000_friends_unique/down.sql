@@ -0,0 +1 @@
+DROP INDEX friends_users;
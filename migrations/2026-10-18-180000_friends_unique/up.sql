@@ -0,0 +1,4 @@
+-- friendships are stored with the lower user id first, so each pair can only exist once
+UPDATE friends SET first_user_id = second_user_id, second_user_id = first_user_id WHERE first_user_id > second_user_id;
+DELETE FROM friends WHERE id NOT IN (SELECT min(id) FROM friends GROUP BY first_user_id, second_user_id);
+CREATE UNIQUE INDEX friends_users ON friends (first_user_id, second_user_id);
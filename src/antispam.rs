@@ -0,0 +1,487 @@
+use log::{error, info};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use crate::chanserv::parse_duration;
+use crate::sayhooks::read_lines;
+
+pub const ANTISPAM_CONFIG: &str = "antispam.txt";
+
+// messages longer than this don't score more
+const LONG_MESSAGE_CAP: usize = 200;
+
+// scoring and punishment in one channel, the default applies to channels without their own
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpamPolicy {
+    // messages older than this are not scored
+    pub window: Duration,
+    pub threshold: f32,
+    // added for every earlier identical message in the window
+    pub repeat_bonus: f32,
+    // messages longer than long_message score up to long_bonus extra
+    pub long_message: usize,
+    pub long_bonus: f32,
+    // messages following each other faster than fast_interval score up to fast_bonus extra
+    pub fast_interval: Duration,
+    pub fast_bonus: f32,
+    // added for every identical message said in another channel in the window
+    pub cross_channel_bonus: f32,
+    // mute lengths for the 1st, 2nd, ... offense, the last one repeats
+    pub mutes: Vec<chrono::Duration>,
+}
+
+impl Default for SpamPolicy {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(5),
+            threshold: 7.0,
+            repeat_bonus: 2.0,
+            long_message: 50,
+            long_bonus: 2.0,
+            fast_interval: Duration::from_secs(1),
+            fast_bonus: 1.5,
+            cross_channel_bonus: 3.0,
+            mutes: vec![chrono::Duration::minutes(5), chrono::Duration::minutes(30), chrono::Duration::hours(2), chrono::Duration::days(1)],
+        }
+    }
+}
+
+impl SpamPolicy {
+    fn set(&mut self, setting: &str, values: &[&str]) -> Result<(), String> {
+        let value = values.first().ok_or(format!("Missing value for {}", setting))?;
+        match setting {
+            "window" => self.window = parse_seconds(value)?,
+            "threshold" => self.threshold = parse_score(value)?,
+            "repeat_bonus" => self.repeat_bonus = parse_score(value)?,
+            "long_message" => self.long_message = value.parse().map_err(|_| format!("Invalid length {}", value))?,
+            "long_bonus" => self.long_bonus = parse_score(value)?,
+            "fast_interval" => self.fast_interval = parse_seconds(value)?,
+            "fast_bonus" => self.fast_bonus = parse_score(value)?,
+            "cross_channel_bonus" => self.cross_channel_bonus = parse_score(value)?,
+            "mutes" => {
+                self.mutes = values.iter()
+                    .map(|duration| parse_duration(duration).ok_or(format!("Invalid duration {}", duration)))
+                    .collect::<Result<_, _>>()?;
+            }
+            _ => return Err(format!("Unknown setting {}", setting)),
+        }
+        Ok(())
+    }
+}
+
+fn parse_seconds(value: &str) -> Result<Duration, String> {
+    value.parse::<f64>().ok()
+        .filter(|seconds| *seconds > 0.0 && seconds.is_finite())
+        .map(Duration::from_secs_f64)
+        .ok_or(format!("Invalid number of seconds {}", value))
+}
+
+fn parse_score(value: &str) -> Result<f32, String> {
+    value.parse::<f32>().ok()
+        .filter(|score| *score >= 0.0 && score.is_finite())
+        .ok_or(format!("Invalid score {}", value))
+}
+
+/**Settings of the anti-spam engine, read from antispam.txt.
+
+Each line is "<channel> <setting> <value...>", where the channel "*" sets the
+default policy all channels start from and "#" starts a comment, e.g.:
+
+    * threshold 7
+    * mutes 5m 30m 2h 1d
+    main window 10
+    * max_users 10000
+
+The engine wide settings max_users, history_limit and offense_decay are only
+accepted for "*".
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct AntiSpamConfig {
+    pub default: SpamPolicy,
+    pub channels: HashMap<String, SpamPolicy>,
+    // tracked users, the least recently active are evicted beyond it
+    pub max_users: usize,
+    // messages remembered per user
+    pub history_limit: usize,
+    // offenses are forgiven after this long without a new one
+    pub offense_decay: Duration,
+}
+
+impl Default for AntiSpamConfig {
+    fn default() -> Self {
+        Self {
+            default: Default::default(),
+            channels: Default::default(),
+            max_users: 10000,
+            history_limit: 32,
+            offense_decay: Duration::from_secs(60 * 60 * 24),
+        }
+    }
+}
+
+impl AntiSpamConfig {
+    pub fn policy(&self, chan: &str) -> &SpamPolicy {
+        self.channels.get(chan).unwrap_or(&self.default)
+    }
+
+    // messages older than this can't influence any score
+    fn max_window(&self) -> Duration {
+        self.channels.values().map(|policy| policy.window).fold(self.default.window, Duration::max)
+    }
+
+    pub fn parse<I: Iterator<Item = String>>(lines: I) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut overrides = Vec::new();
+        for (num, line) in lines.enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim().to_string();
+            if line.is_empty() {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() < 3 {
+                return Err(format!("line {}: expected <channel> <setting> <value>", num + 1));
+            }
+            let (chan, setting, values) = (parts[0], parts[1], &parts[2..]);
+            let result = match (chan, setting) {
+                ("*", "max_users") => values[0].parse().map(|max_users| config.max_users = max_users).map_err(|_| format!("Invalid number {}", values[0])),
+                ("*", "history_limit") => values[0].parse().map(|limit| config.history_limit = limit).map_err(|_| format!("Invalid number {}", values[0])),
+                ("*", "offense_decay") => parse_duration(values[0])
+                    .and_then(|decay| decay.to_std().ok())
+                    .map(|decay| config.offense_decay = decay)
+                    .ok_or(format!("Invalid duration {}", values[0])),
+                ("*", _) => config.default.set(setting, values),
+                _ => {
+                    overrides.push((num, chan.to_string(), setting.to_string(), values.iter().map(|value| value.to_string()).collect::<Vec<_>>()));
+                    Ok(())
+                }
+            };
+            result.map_err(|e| format!("line {}: {}", num + 1, e))?;
+        }
+
+        // channels inherit every setting they don't override from the default
+        let default = config.default.clone();
+        for (num, chan, setting, values) in overrides {
+            let values: Vec<&str> = values.iter().map(String::as_str).collect();
+            config.channels.entry(chan).or_insert_with(|| default.clone())
+                .set(&setting, &values)
+                .map_err(|e| format!("line {}: {}", num + 1, e))?;
+        }
+        if config.history_limit == 0 || config.max_users == 0 {
+            return Err("max_users and history_limit must be at least 1".into());
+        }
+        Ok(config)
+    }
+
+    pub fn load(file_name: &str) -> Result<Self, String> {
+        let lines = read_lines(file_name).map_err(|e| format!("File {}: {}", file_name, e))?;
+        Self::parse(lines.map_while(Result::ok))
+    }
+}
+
+struct SaidMessage {
+    time: Instant,
+    channel: u64,
+    message: u64,
+    length: usize,
+}
+
+fn hash(text: &str) -> u64 {
+    let mut s = DefaultHasher::new();
+    text.hash(&mut s);
+    s.finish()
+}
+
+// what a single user said recently, in all channels
+struct Speaker {
+    messages: VecDeque<SaidMessage>,
+    offenses: usize,
+    last_offense: Option<Instant>,
+    last_seen: Instant,
+}
+
+impl Speaker {
+    fn new(now: Instant) -> Self {
+        Self { messages: Default::default(), offenses: 0, last_offense: None, last_seen: now }
+    }
+
+    fn is_idle(&self, now: Instant, window: Duration, offense_decay: Duration) -> bool {
+        now.saturating_duration_since(self.last_seen) > window
+            && self.last_offense.is_none_or(|last| now.saturating_duration_since(last) > offense_decay)
+    }
+
+    fn score(&self, channel: u64, policy: &SpamPolicy, now: Instant) -> f32 {
+        let recent: Vec<&SaidMessage> = self.messages.iter()
+            .filter(|msg| now.saturating_duration_since(msg.time) <= policy.window)
+            .collect();
+        let latest = match recent.last() {
+            None => return 0.0,
+            Some(latest) => latest,
+        };
+
+        let mut score = 0.0;
+        let mut already: HashMap<u64, usize> = HashMap::new();
+        let mut last_time: Option<Instant> = None;
+        for msg in recent.iter().filter(|msg| msg.channel == channel) {
+            let counter = already.entry(msg.message).or_insert(0);
+            score += policy.repeat_bonus * *counter as f32;
+            *counter += 1;
+            if msg.length > policy.long_message {
+                score += policy.long_bonus * msg.length.min(LONG_MESSAGE_CAP) as f32 / LONG_MESSAGE_CAP as f32;
+            }
+            score += 1.0; // something was said
+            if let Some(last_time) = last_time {
+                let diff = msg.time.saturating_duration_since(last_time);
+                if diff < policy.fast_interval {
+                    score += (1.0 - diff.as_secs_f32() / policy.fast_interval.as_secs_f32()) * policy.fast_bonus;
+                }
+            }
+            last_time = Some(msg.time);
+        }
+
+        // the same text pasted into several channels
+        let elsewhere = recent.iter().filter(|msg| msg.channel != channel && msg.message == latest.message).count();
+        score + policy.cross_channel_bonus * elsewhere as f32
+    }
+}
+
+// scores what users say per channel and escalates the mutes of repeat offenders
+pub struct AntiSpam {
+    config: AntiSpamConfig,
+    speakers: HashMap<i32, Speaker>,
+}
+
+impl AntiSpam {
+    pub fn new(config: AntiSpamConfig) -> Self {
+        Self { config, speakers: Default::default() }
+    }
+
+    // falls back to the default policy if the file is missing or invalid
+    pub fn load() -> Self {
+        let mut antispam = Self::new(Default::default());
+        if let Err(e) = antispam.reload() {
+            info!("Using the default anti-spam policy: {}", e);
+        }
+        antispam
+    }
+
+    // keeps the current config if the new one is invalid
+    pub fn reload(&mut self) -> Result<(), String> {
+        match AntiSpamConfig::load(ANTISPAM_CONFIG) {
+            Ok(config) => {
+                info!("Anti-spam config loaded with {} channel policies", config.channels.len());
+                self.config = config;
+                Ok(())
+            }
+            Err(e) => {
+                error!("Error parsing anti-spam config: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    // records msg and returns how long to mute user_id if it is spam in chan
+    pub fn check(&mut self, user_id: i32, chan: &str, msg: &str, now: Instant) -> Option<chrono::Duration> {
+        if !self.speakers.contains_key(&user_id) && self.speakers.len() >= self.config.max_users {
+            self.evict(now);
+        }
+        let config = &self.config;
+        let policy = config.policy(chan);
+        let max_window = config.max_window();
+        let channel = hash(chan);
+
+        let speaker = self.speakers.entry(user_id).or_insert_with(|| Speaker::new(now));
+        speaker.last_seen = now;
+        while speaker.messages.front().is_some_and(|msg| now.saturating_duration_since(msg.time) > max_window) {
+            speaker.messages.pop_front();
+        }
+        speaker.messages.push_back(SaidMessage { time: now, channel, message: hash(msg), length: msg.len() });
+        while speaker.messages.len() > config.history_limit {
+            speaker.messages.pop_front();
+        }
+
+        if speaker.score(channel, policy, now) <= policy.threshold {
+            return None;
+        }
+        if speaker.last_offense.is_some_and(|last| now.saturating_duration_since(last) > config.offense_decay) {
+            speaker.offenses = 0;
+        }
+        speaker.offenses += 1;
+        speaker.last_offense = Some(now);
+        // start over once the mute is done
+        speaker.messages.retain(|msg| msg.channel != channel);
+        policy.mutes.get(speaker.offenses - 1).or(policy.mutes.last()).copied()
+    }
+
+    // forgets users which neither said anything recently nor have offenses to remember
+    pub fn cleanup(&mut self, now: Instant) {
+        let (window, offense_decay) = (self.config.max_window(), self.config.offense_decay);
+        self.speakers.retain(|_, speaker| !speaker.is_idle(now, window, offense_decay));
+    }
+
+    fn evict(&mut self, now: Instant) {
+        self.cleanup(now);
+        while self.speakers.len() >= self.config.max_users {
+            // offenders go last so evicting can't reset their escalation
+            let oldest = self.speakers.iter()
+                .min_by_key(|(_, speaker)| (speaker.last_offense.is_some(), speaker.last_seen))
+                .map(|(user_id, _)| *user_id);
+            match oldest {
+                Some(user_id) => self.speakers.remove(&user_id),
+                None => break,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Clock(Instant);
+
+    impl Clock {
+        fn advance(&mut self, millis: u64) -> Instant {
+            self.0 += Duration::from_millis(millis);
+            self.0
+        }
+    }
+
+    // says msg every millis until muted, returns the mute and how many messages got through
+    fn spam(antispam: &mut AntiSpam, clock: &mut Clock, user_id: i32, chan: &str, msg: &str, millis: u64) -> (Option<chrono::Duration>, usize) {
+        for said in 0..100 {
+            let now = clock.advance(millis);
+            if let Some(mute) = antispam.check(user_id, chan, msg, now) {
+                return (Some(mute), said);
+            }
+        }
+        (None, 100)
+    }
+
+    #[test]
+    fn test_normal_chat() {
+        let mut antispam = AntiSpam::new(Default::default());
+        let mut clock = Clock(Instant::now());
+        assert_eq!(antispam.check(1, "main", "hello", clock.0), None);
+        for i in 0..50 {
+            assert_eq!(antispam.check(1, "main", &format!("message {}", i), clock.advance(2000)), None);
+        }
+    }
+
+    #[test]
+    fn test_flood() {
+        let mut antispam = AntiSpam::new(Default::default());
+        let mut clock = Clock(Instant::now());
+        let (mute, said) = spam(&mut antispam, &mut clock, 1, "main", "hi", 200);
+        assert_eq!(mute, Some(chrono::Duration::minutes(5)));
+        assert!(said < 5);
+
+        // different text is scored too, just later
+        let said = (0..100).position(|i| antispam.check(2, "main", &format!("message {}", i), clock.advance(400)).is_some());
+        assert!(said.is_some_and(|said| said >= 3));
+    }
+
+    #[test]
+    fn test_escalation() {
+        let config = AntiSpamConfig { offense_decay: Duration::from_secs(3600), ..Default::default() };
+        let mut antispam = AntiSpam::new(config);
+        let mut clock = Clock(Instant::now());
+        let mutes: Vec<_> = (0..5).map(|_| {
+            clock.advance(60_000);
+            spam(&mut antispam, &mut clock, 1, "main", "buy gold", 100).0.unwrap()
+        }).collect();
+        assert_eq!(mutes, vec![
+            chrono::Duration::minutes(5),
+            chrono::Duration::minutes(30),
+            chrono::Duration::hours(2),
+            chrono::Duration::days(1),
+            chrono::Duration::days(1),
+        ]);
+
+        clock.advance(3_600_001);
+        assert_eq!(spam(&mut antispam, &mut clock, 1, "main", "buy gold", 100).0, Some(chrono::Duration::minutes(5)));
+    }
+
+    #[test]
+    fn test_cross_channel() {
+        let mut antispam = AntiSpam::new(Default::default());
+        let mut clock = Clock(Instant::now());
+        let msg = "join my awesome game";
+        assert_eq!(antispam.check(1, "main", msg, clock.advance(1500)), None);
+        assert_eq!(antispam.check(1, "newbies", msg, clock.advance(1500)), None);
+        assert_eq!(antispam.check(1, "weekend", msg, clock.advance(1500)), None);
+        assert!(antispam.check(1, "moon", msg, clock.advance(1500)).is_some());
+
+        // the same pace with different messages is fine
+        for (i, chan) in ["main", "newbies", "weekend", "moon"].iter().enumerate() {
+            assert_eq!(antispam.check(2, chan, &format!("hello {}", i), clock.advance(1500)), None);
+        }
+    }
+
+    #[test]
+    fn test_channel_policy() {
+        let config = AntiSpamConfig::parse(vec![
+            "# comment".to_string(),
+            "* threshold 7".to_string(),
+            "* mutes 10m 1h".to_string(),
+            "chatty threshold 100000 # anything goes".to_string(),
+            "strict window 10".to_string(),
+            "* max_users 5".to_string(),
+        ].into_iter()).unwrap();
+        assert_eq!(config.max_users, 5);
+        assert_eq!(config.policy("chatty").threshold, 100000.0);
+        assert_eq!(config.policy("chatty").mutes, vec![chrono::Duration::minutes(10), chrono::Duration::hours(1)]);
+        assert_eq!(config.policy("strict").window, Duration::from_secs(10));
+        assert_eq!(config.policy("strict").threshold, 7.0);
+        assert_eq!(config.policy("other"), &config.default);
+
+        let mut antispam = AntiSpam::new(config);
+        let mut clock = Clock(Instant::now());
+        assert_eq!(spam(&mut antispam, &mut clock, 1, "chatty", "hi", 100), (None, 100));
+        assert_eq!(spam(&mut antispam, &mut clock, 1, "main", "hi", 100).0, Some(chrono::Duration::minutes(10)));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let parse = |line: &str| AntiSpamConfig::parse(std::iter::once(line.to_string()));
+        assert!(parse("* threshold").is_err());
+        assert!(parse("* threshold lots").is_err());
+        assert!(parse("* colour blue").is_err());
+        assert!(parse("main max_users 5").is_err());
+        assert!(parse("* window 0").is_err());
+        assert!(parse("main mutes 5m forever").is_err());
+        assert!(parse("* history_limit 0").is_err());
+    }
+
+    #[test]
+    fn test_eviction() {
+        let config = AntiSpamConfig { max_users: 3, ..Default::default() };
+        let mut antispam = AntiSpam::new(config);
+        let mut clock = Clock(Instant::now());
+        spam(&mut antispam, &mut clock, 1, "main", "spam", 100);
+        for user_id in 2..10 {
+            antispam.check(user_id, "main", "hello", clock.advance(100));
+            assert!(antispam.speakers.len() <= 3);
+        }
+        // the offender is remembered, idle users are dropped first
+        clock.advance(10_000);
+        antispam.check(10, "main", "hello", clock.0);
+        assert!(antispam.speakers.contains_key(&1));
+
+        clock.advance(60 * 60 * 24 * 1000);
+        antispam.cleanup(clock.0);
+        assert_eq!(antispam.speakers.len(), 0);
+    }
+
+    #[test]
+    fn test_history_limit() {
+        let config = AntiSpamConfig { history_limit: 4, ..Default::default() };
+        let mut antispam = AntiSpam::new(config);
+        let mut clock = Clock(Instant::now());
+        for i in 0..20 {
+            antispam.check(1, &format!("chan{}", i), &format!("msg {}", i), clock.advance(10));
+        }
+        assert_eq!(antispam.speakers[&1].messages.len(), 4);
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+/**Arguments of a protocol command, taken field by field in the order they are sent.
+
+Words are separated by spaces and sentences by tabs, the rest of the line is
+whatever is left. Required fields fail with the argument name, which is sent
+back to the client as FAILED:
+
+    let mut args = Args::new("main 3 hello world");
+    let chan = args.word("chan")?;
+    let count: u8 = args.parse("count")?;
+    let msg = args.rest("msg")?;
+*/
+pub struct Args<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(args: &'a str) -> Self {
+        Self { rest: args }
+    }
+
+    // the next field up to separator, empty fields count as missing
+    fn take(&mut self, separator: char) -> Option<&'a str> {
+        let (field, rest) = self.rest.split_once(separator).unwrap_or((self.rest, ""));
+        self.rest = rest;
+        Some(field).filter(|field| !field.is_empty())
+    }
+
+    pub fn word(&mut self, name: &str) -> Result<&'a str, String> {
+        self.take(' ').ok_or_else(|| missing(name))
+    }
+
+    pub fn opt_word(&mut self) -> Option<&'a str> {
+        self.take(' ')
+    }
+
+    pub fn opt_sentence(&mut self) -> Option<&'a str> {
+        self.take('\t')
+    }
+
+    // everything left, including separators
+    pub fn rest(&mut self, name: &str) -> Result<&'a str, String> {
+        Some(self.opt_rest()).filter(|rest| !rest.is_empty()).ok_or_else(|| missing(name))
+    }
+
+    pub fn opt_rest(&mut self) -> &'a str {
+        std::mem::take(&mut self.rest)
+    }
+
+    // a word parsed as number, enum or anything else implementing FromStr
+    pub fn parse<T: FromStr>(&mut self, name: &str) -> Result<T, String>
+    where
+        T::Err: Display,
+    {
+        let word = self.word(name)?;
+        parse_word(name, word)
+    }
+
+    pub fn opt_parse<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, String>
+    where
+        T::Err: Display,
+    {
+        self.opt_word().map(|word| parse_word(name, word)).transpose()
+    }
+}
+
+fn missing(name: &str) -> String {
+    format!("Missing {} argument", name)
+}
+
+fn parse_word<T: FromStr>(name: &str, word: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    word.parse().map_err(|e| format!("Invalid {} argument '{}': {}", name, word, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Role;
+
+    #[test]
+    fn test_words_and_rest() {
+        let mut args = Args::new("main hello  world");
+        assert_eq!(args.word("chan"), Ok("main"));
+        assert_eq!(args.rest("msg"), Ok("hello  world"));
+        assert_eq!(args.rest("msg"), Err("Missing msg argument".to_string()));
+
+        let mut args = Args::new("main");
+        assert_eq!(args.word("chan"), Ok("main"));
+        assert_eq!(args.rest("msg"), Err("Missing msg argument".to_string()));
+    }
+
+    #[test]
+    fn test_missing() {
+        let mut args = Args::new("");
+        assert_eq!(args.word("chan"), Err("Missing chan argument".to_string()));
+        assert_eq!(args.opt_word(), None);
+        assert_eq!(args.opt_rest(), "");
+        assert_eq!(args.parse::<u8>("teamno"), Err("Missing teamno argument".to_string()));
+    }
+
+    #[test]
+    fn test_optional() {
+        let mut args = Args::new("main key");
+        assert_eq!(args.word("chan"), Ok("main"));
+        assert_eq!(args.opt_word(), Some("key"));
+        assert_eq!(args.opt_word(), None);
+        assert_eq!(args.opt_parse::<i32>("id"), Ok(None));
+
+        let mut args = Args::new("5");
+        assert_eq!(args.opt_parse::<i32>("id"), Ok(Some(5)));
+        assert!(Args::new("x").opt_parse::<i32>("id").is_err());
+    }
+
+    #[test]
+    fn test_sentences() {
+        // like LOGIN: words followed by tab separated sentences
+        let mut args = Args::new("user pass 0 * lobby 1.0\t1234\tb sp");
+        assert_eq!(args.word("username"), Ok("user"));
+        assert_eq!(args.word("password"), Ok("pass"));
+        assert_eq!(args.opt_word(), Some("0"));
+        assert_eq!(args.word("local_ip"), Ok("*"));
+        assert_eq!(args.opt_sentence(), Some("lobby 1.0"));
+        assert_eq!(args.opt_sentence(), Some("1234"));
+        assert_eq!(args.opt_sentence(), Some("b sp"));
+        assert_eq!(args.opt_sentence(), None);
+    }
+
+    #[test]
+    fn test_integers() {
+        let mut args = Args::new("7 -1 300 x");
+        assert_eq!(args.parse::<u8>("teamno"), Ok(7));
+        assert_eq!(args.parse::<i32>("id"), Ok(-1));
+        assert_eq!(args.parse::<u8>("allyno"), Err("Invalid allyno argument '300': number too large to fit in target type".to_string()));
+        assert_eq!(args.parse::<u16>("port"), Err("Invalid port argument 'x': invalid digit found in string".to_string()));
+    }
+
+    #[test]
+    fn test_enums() {
+        let mut args = Args::new("moderator god");
+        assert_eq!(args.parse::<Role>("access"), Ok(Role::Moderator));
+        let err = args.parse::<Role>("access").unwrap_err();
+        assert!(err.starts_with("Invalid access argument 'god': "), "{}", err);
+    }
+}
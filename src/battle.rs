@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+
+use crate::battlestatus::{BattleStatus, TeamColor};
+use crate::client::{Client, Tx};
+
+pub struct BattleBot {
+    pub owner: String,
+    pub battlestatus: BattleStatus,
+    pub teamcolor: TeamColor,
+    pub ai_dll: String,
+}
+
+pub struct StartRect {
+    pub left: u32,
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+}
+
+pub struct BattleUser {
+    pub username: String,
+    pub status: BattleStatus,
+    pub teamcolor: TeamColor,
+    tx: Tx,
+}
+
+#[derive(Default)]
+pub struct Battle {
+    pub battle_id: usize,
+    pub host: usize, // session id of the host
+    pub host_username: String,
+    pub ip: String,
+    pub battle_type: i32, // 0: battle, 1: hosted replay
+    pub nat_type: i32, // 0: none, 1: hole punching, 2: fixed source ports
+    pub key: Option<String>,
+    pub port: u16,
+    pub maxplayers: i32,
+    pub hashcode: i32,
+    pub rank: i32,
+    pub maphash: i32,
+    pub engine: String,
+    pub version: String,
+    pub map: String,
+    pub title: String,
+    pub modname: String,
+    pub spectators: usize,
+    pub locked: bool,
+    users: HashMap<usize, BattleUser>,
+    bots: HashMap<String, BattleBot>,
+    script_tags: HashMap<String, String>,
+    startrects: HashMap<i32, StartRect>,
+    disabled_units: Vec<String>,
+}
+
+#[allow(non_snake_case)]
+impl Battle {
+    pub fn new(battle_id: usize, host: &Client) -> Self {
+        Self {
+            battle_id,
+            host: host.session_id,
+            host_username: host.username.clone(),
+            ip: host.ip_address.clone(),
+            ..Default::default()
+        }
+    }
+
+    pub fn passworded(&self) -> bool {
+        !matches!(self.key.as_deref(), None | Some("*"))
+    }
+
+    pub fn canChangeSettings(&self, session: usize) -> bool {
+        session == self.host
+    }
+
+    pub fn has_user(&self, session: usize) -> bool {
+        self.users.contains_key(&session)
+    }
+
+    pub fn user_count(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn usernames(&self) -> impl Iterator<Item = &str> {
+        self.users.values().map(|user| user.username.as_str())
+    }
+
+    pub fn addUser(&mut self, session: usize, username: &str, tx: Tx) {
+        self.users.insert(session, BattleUser {
+            username: username.to_string(),
+            status: Default::default(),
+            teamcolor: Default::default(),
+            tx,
+        });
+    }
+
+    pub fn get_user(&mut self, session: usize) -> Option<&mut BattleUser> {
+        self.users.get_mut(&session)
+    }
+
+    pub fn session_of(&self, username: &str) -> Option<usize> {
+        self.users
+            .iter()
+            .find(|(_, user)| user.username == username)
+            .map(|(session, _)| *session)
+    }
+
+    pub fn player_count(&self) -> usize {
+        self.users.values().filter(|user| !user.status.spectator).count()
+    }
+
+    // returns true if the spectator count changed and UPDATEBATTLEINFO has to be sent
+    pub fn update_spectators(&mut self) -> bool {
+        let spectators = self.users.values().filter(|user| user.status.spectator).count();
+        let changed = spectators != self.spectators;
+        self.spectators = spectators;
+        changed
+    }
+
+    pub fn status_message(&self, session: usize) -> Option<String> {
+        self.users.get(&session).map(|user| {
+            format!("CLIENTBATTLESTATUS {} {} {}", user.username, user.status, user.teamcolor)
+        })
+    }
+
+    pub fn removeUser(&mut self, session: usize) -> Option<BattleUser> {
+        self.users.remove(&session)
+    }
+
+    pub fn send_to(&self, session: usize, message: &str) {
+        if let Some(user) = self.users.get(&session) {
+            let _ = user.tx.send(message.to_string());
+        }
+    }
+
+    pub fn broadcast(&self, message: &str) {
+        self.users.values().for_each(|user| {
+            let _ = user.tx.send(message.to_string());
+        });
+    }
+
+    // returns the tags as they should be relayed, keys are case insensitive
+    pub fn set_script_tags(&mut self, tags: HashMap<String, String>) -> Vec<String> {
+        tags.into_iter()
+            .map(|(tag, value)| {
+                let tag = tag.to_lowercase();
+                let pair = format!("{}={}", tag, value);
+                self.script_tags.insert(tag, value);
+                pair
+            })
+            .collect()
+    }
+
+    // only tags which existed are reported back as removed
+    pub fn remove_script_tags<'a>(&mut self, tags: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+        tags.filter(|tag| self.script_tags.remove(&tag.to_lowercase()).is_some())
+            .collect()
+    }
+
+    pub fn add_startrect(&mut self, allyno: i32, rect: StartRect) {
+        self.startrects.insert(allyno, rect);
+    }
+
+    pub fn remove_startrect(&mut self, allyno: i32) -> bool {
+        self.startrects.remove(&allyno).is_some()
+    }
+
+    // returns the units which weren't disabled before
+    pub fn disable_units<'a>(&mut self, units: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+        units
+            .filter(|unit| {
+                if unit.is_empty() || self.disabled_units.iter().any(|u| u == unit) {
+                    return false;
+                }
+                self.disabled_units.push(unit.to_string());
+                true
+            })
+            .collect()
+    }
+
+    // returns the units which were disabled before
+    pub fn enable_units<'a>(&mut self, units: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+        units
+            .filter(|unit| {
+                let before = self.disabled_units.len();
+                self.disabled_units.retain(|u| u != unit);
+                before != self.disabled_units.len()
+            })
+            .collect()
+    }
+
+    pub fn enable_all_units(&mut self) {
+        self.disabled_units.clear();
+    }
+
+    pub fn get_bot(&mut self, name: &str) -> Option<&mut BattleBot> {
+        self.bots.get_mut(name)
+    }
+
+    pub fn add_bot(&mut self, name: &str, bot: BattleBot) -> bool {
+        if self.bots.contains_key(name) {
+            return false;
+        }
+        self.bots.insert(name.to_string(), bot);
+        true
+    }
+
+    pub fn remove_bot(&mut self, name: &str) -> Option<BattleBot> {
+        self.bots.remove(name)
+    }
+
+    // returns the names of removed bots
+    pub fn remove_bots_of(&mut self, owner: &str) -> Vec<String> {
+        let names: Vec<String> = self.bots
+            .iter()
+            .filter(|(_, bot)| bot.owner == owner)
+            .map(|(name, _)| name.clone())
+            .collect();
+        names.iter().for_each(|name| {
+            self.bots.remove(name);
+        });
+        names
+    }
+
+    pub fn add_bot_message(&self, name: &str, bot: &BattleBot) -> String {
+        format!("ADDBOT {} {} {} {} {} {}",
+            self.battle_id, name, bot.owner, bot.battlestatus, bot.teamcolor, bot.ai_dll)
+    }
+
+    // full battle state for a client which joined the battle
+    pub fn state_messages(&self) -> Vec<String> {
+        let mut messages = Vec::new();
+        for session in self.users.keys() {
+            messages.extend(self.status_message(*session));
+        }
+        if !self.script_tags.is_empty() {
+            let scripttags: Vec<String> = self.script_tags
+                .iter()
+                .map(|(tag, value)| format!("{}={}", tag, value))
+                .collect();
+            messages.push(format!("SETSCRIPTTAGS {}", scripttags.join("\t")));
+        }
+        if !self.disabled_units.is_empty() {
+            messages.push(format!("DISABLEUNITS {}", self.disabled_units.join(" ")));
+        }
+        for (name, bot) in self.bots.iter() {
+            messages.push(self.add_bot_message(name, bot));
+        }
+        for (allyno, rect) in self.startrects.iter() {
+            messages.push(format!("ADDSTARTRECT {} {} {} {} {}",
+                allyno, rect.left, rect.top, rect.right, rect.bottom));
+        }
+        messages
+    }
+
+    pub fn opened_message(&self) -> String {
+        format!("BATTLEOPENED {} {} {} {} {} {} {} {} {} {} {}\t{}\t{}\t{}\t{}",
+            self.battle_id, self.battle_type, self.nat_type, self.host_username, self.ip,
+            self.port, self.maxplayers, self.passworded() as u8, self.rank, self.maphash,
+            self.engine, self.version, self.map, self.title, self.modname)
+    }
+
+    pub fn info_message(&self) -> String {
+        format!("UPDATEBATTLEINFO {} {} {} {} {}",
+            self.battle_id, self.spectators, self.locked as u8, self.maphash, self.map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bot(owner: &str) -> BattleBot {
+        BattleBot {
+            owner: owner.to_string(),
+            battlestatus: Default::default(),
+            teamcolor: TeamColor(255),
+            ai_dll: "KAIK".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_script_tags() {
+        let mut battle = Battle::default();
+        let tags = HashMap::from([("Game/StartMetal".to_string(), "1000".to_string())]);
+        assert_eq!(battle.set_script_tags(tags), vec!["game/startmetal=1000"]);
+        assert_eq!(battle.state_messages(), vec!["SETSCRIPTTAGS game/startmetal=1000"]);
+        let removed = battle.remove_script_tags("GAME/STARTMETAL game/unknown".split(' '));
+        assert_eq!(removed, vec!["GAME/STARTMETAL"]);
+        assert!(battle.state_messages().is_empty());
+    }
+
+    #[test]
+    fn test_startrects() {
+        let mut battle = Battle::default();
+        battle.add_startrect(1, StartRect { left: 0, top: 0, right: 100, bottom: 50 });
+        assert_eq!(battle.state_messages(), vec!["ADDSTARTRECT 1 0 0 100 50"]);
+        assert!(battle.remove_startrect(1));
+        assert!(!battle.remove_startrect(1));
+        assert!(battle.state_messages().is_empty());
+    }
+
+    #[test]
+    fn test_disabled_units() {
+        let mut battle = Battle::default();
+        assert_eq!(battle.disable_units("armcom  corcom armcom".split(' ')), vec!["armcom", "corcom"]);
+        assert_eq!(battle.disable_units("armcom".split(' ')), Vec::<&str>::new());
+        assert_eq!(battle.state_messages(), vec!["DISABLEUNITS armcom corcom"]);
+        assert_eq!(battle.enable_units("corcom armflash".split(' ')), vec!["corcom"]);
+        assert_eq!(battle.state_messages(), vec!["DISABLEUNITS armcom"]);
+        battle.enable_all_units();
+        assert!(battle.state_messages().is_empty());
+    }
+
+    #[test]
+    fn test_bots() {
+        let mut battle = Battle { battle_id: 3, ..Default::default() };
+        assert!(battle.add_bot("bot1", bot("Alice")));
+        assert!(!battle.add_bot("bot1", bot("Bob")));
+        assert!(battle.add_bot("bot2", bot("Bob")));
+        assert_eq!(battle.get_bot("bot1").unwrap().owner, "Alice");
+        assert_eq!(battle.add_bot_message("bot1", &bot("Alice")),
+            format!("ADDBOT 3 bot1 Alice {} 255 KAIK", BattleStatus::default()));
+
+        assert_eq!(battle.remove_bots_of("Bob"), vec!["bot2"]);
+        assert!(battle.get_bot("bot2").is_none());
+        assert!(battle.remove_bot("bot1").is_some());
+        assert!(battle.remove_bot("bot1").is_none());
+    }
+}
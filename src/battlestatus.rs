@@ -0,0 +1,181 @@
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+// see https://springrts.com/dl/LobbyProtocol/ProtocolDescription.html#MYBATTLESTATUS:client
+//   b0       undefined
+//   b1       ready
+//   b2..b5   team number
+//   b6..b9   ally team number
+//   b10      mode (0 = spectator, 1 = normal player)
+//   b11..b17 handicap (0-100)
+//   b18..b21 reserved
+//   b22..b23 sync status (0 = unknown, 1 = synced, 2 = unsynced)
+//   b24..b27 side
+//   b28..b31 undefined
+const READY_SHIFT: u32 = 1;
+const TEAM_SHIFT: u32 = 2;
+const ALLY_SHIFT: u32 = 6;
+const MODE_SHIFT: u32 = 10;
+const HANDICAP_SHIFT: u32 = 11;
+const SYNC_SHIFT: u32 = 22;
+const SIDE_SHIFT: u32 = 24;
+
+const TEAM_MASK: u32 = 0xf;
+const ALLY_MASK: u32 = 0xf;
+const HANDICAP_MASK: u32 = 0x7f;
+const SYNC_MASK: u32 = 0x3;
+const SIDE_MASK: u32 = 0xf;
+
+pub const MAX_TEAM: u8 = TEAM_MASK as u8;
+pub const MAX_ALLY: u8 = ALLY_MASK as u8;
+pub const MAX_HANDICAP: u8 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BattleStatus {
+    pub ready: bool,
+    pub team: u8,
+    pub ally: u8,
+    pub spectator: bool,
+    pub handicap: u8,
+    pub sync: u8,
+    pub side: u8,
+}
+
+// clients join a battle as spectators until they send MYBATTLESTATUS
+impl Default for BattleStatus {
+    fn default() -> Self {
+        Self {
+            ready: false,
+            team: 0,
+            ally: 0,
+            spectator: true,
+            handicap: 0,
+            sync: 0,
+            side: 0,
+        }
+    }
+}
+
+impl BattleStatus {
+    pub fn from_bits(bits: u32) -> Self {
+        Self {
+            ready: (bits >> READY_SHIFT) & 1 == 1,
+            team: ((bits >> TEAM_SHIFT) & TEAM_MASK) as u8,
+            ally: ((bits >> ALLY_SHIFT) & ALLY_MASK) as u8,
+            spectator: (bits >> MODE_SHIFT) & 1 == 0,
+            handicap: ((bits >> HANDICAP_SHIFT) & HANDICAP_MASK) as u8,
+            sync: ((bits >> SYNC_SHIFT) & SYNC_MASK) as u8,
+            side: ((bits >> SIDE_SHIFT) & SIDE_MASK) as u8,
+        }
+    }
+
+    // undefined and reserved bits are always sent as zero
+    pub fn to_bits(self) -> u32 {
+        (self.ready as u32) << READY_SHIFT
+            | (self.team as u32 & TEAM_MASK) << TEAM_SHIFT
+            | (self.ally as u32 & ALLY_MASK) << ALLY_SHIFT
+            | (!self.spectator as u32) << MODE_SHIFT
+            | (self.handicap as u32 & HANDICAP_MASK) << HANDICAP_SHIFT
+            | (self.sync as u32 & SYNC_MASK) << SYNC_SHIFT
+            | (self.side as u32 & SIDE_MASK) << SIDE_SHIFT
+    }
+}
+
+// old lobbies send the status as a signed 32 bit value, b31 is undefined anyway
+impl FromStr for BattleStatus {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from_bits(s.parse::<i32>()? as u32))
+    }
+}
+
+impl fmt::Display for BattleStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_bits())
+    }
+}
+
+// 0xBBGGRR sent as a signed 32 bit decimal
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TeamColor(pub i32);
+
+impl FromStr for TeamColor {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(TeamColor(s.parse::<i32>()?))
+    }
+}
+
+impl fmt::Display for TeamColor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_battlestatus_fields() {
+        let status = BattleStatus::from_bits(1 << 1 | 5 << 2 | 3 << 6 | 1 << 10 | 100 << 11 | 3 << 22 | 5 << 24);
+        assert!(status.ready);
+        assert_eq!(status.team, 5);
+        assert_eq!(status.ally, 3);
+        assert!(!status.spectator);
+        assert_eq!(status.handicap, 100);
+        assert_eq!(status.sync, 3);
+        assert_eq!(status.side, 5);
+    }
+
+    #[test]
+    fn test_battlestatus_roundtrip() {
+        let status = BattleStatus {
+            ready: true,
+            team: 15,
+            ally: 1,
+            spectator: false,
+            handicap: 42,
+            sync: 1,
+            side: 2,
+        };
+        assert_eq!(BattleStatus::from_bits(status.to_bits()), status);
+        assert_eq!(status.to_string().parse::<BattleStatus>().unwrap(), status);
+    }
+
+    #[test]
+    fn test_battlestatus_ignores_undefined_bits() {
+        let bits = 1 | 0xf << 18 | 0xf << 28;
+        assert_eq!(BattleStatus::from_bits(bits), BattleStatus::default());
+        assert_eq!(BattleStatus::from_bits(bits).to_bits(), 0);
+    }
+
+    #[test]
+    fn test_battlestatus_default_is_spectator() {
+        let status = BattleStatus::default();
+        assert!(status.spectator);
+        assert_eq!(status.to_bits(), 0);
+    }
+
+    #[test]
+    fn test_battlestatus_parse() {
+        assert!(!"1024".parse::<BattleStatus>().unwrap().spectator);
+        // negative values from old lobbies keep the lower bits
+        let status = "-2147482622".parse::<BattleStatus>().unwrap();
+        assert!(status.ready);
+        assert!(!status.spectator);
+        assert!("abc".parse::<BattleStatus>().is_err());
+        assert!("4294967296".parse::<BattleStatus>().is_err());
+    }
+
+    #[test]
+    fn test_teamcolor() {
+        let color = "16711935".parse::<TeamColor>().unwrap();
+        assert_eq!(color, TeamColor(0xFF00FF));
+        assert_eq!(color.to_string(), "16711935");
+        assert_eq!("-1".parse::<TeamColor>().unwrap(), TeamColor(-1));
+    }
+}
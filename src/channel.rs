@@ -1,35 +1,279 @@
+use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::time::{Duration, Instant};
 
-use crate::client::Tx;
+use crate::client::{IgnoreSet, Tx};
+use crate::util::pretty_time_delta;
+
+pub struct ChannelUser {
+    pub username: String,
+    tx: Tx,
+    ignored: IgnoreSet,
+}
+
+pub struct Mute {
+    pub username: String,
+    pub issuer: String,
+    pub expires: NaiveDateTime,
+    pub reason: String,
+}
+
+// bans apply to the user and to an ip address, by default the one the user last logged in from
+pub struct Ban {
+    pub username: String,
+    pub issuer: String,
+    pub ip_address: String,
+    pub expires: NaiveDateTime,
+    pub reason: String,
+}
+
+// expiry of mutes and bans without time limit
+pub fn never_expires() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(9999, 12, 31).unwrap().and_hms_opt(0, 0, 0).unwrap()
+}
+
+// expiry of a mute or ban lasting duration from now
+pub fn expires_after(duration: Duration) -> NaiveDateTime {
+    Utc::now().naive_utc().checked_add_signed(duration).unwrap_or_else(never_expires)
+}
 
 pub struct Channel {
+    pub id: i32, // database id, 0 for unregistered channels
     pub name: String,
-    users: HashMap<usize, Tx>,
-    mutelist: HashMap<usize, Instant>,
-    operators: HashSet<usize>,
-    owner_user_id: usize,
+    pub topic: String,
+    pub topic_author: String,
+    key: Option<String>,
+    forwards: HashSet<String>, // channels joined along with this one
+    users: HashMap<usize, ChannelUser>,
+    mutelist: HashMap<i32, Mute>, // by user id
+    ban: HashMap<i32, Ban>, // by user id
+    operators: HashSet<i32>, // user ids
+    owner_user_id: Option<i32>,
     pub antispam: bool,
+    pub censor: bool,
     pub store_history: bool,
 }
 
+#[allow(non_snake_case)]
 impl Channel {
+    pub fn new(name: &str) -> Self {
+        Self {
+            id: 0,
+            name: name.to_string(),
+            topic: Default::default(),
+            topic_author: "ChanServ".into(),
+            key: None,
+            forwards: Default::default(),
+            users: Default::default(),
+            mutelist: Default::default(),
+            ban: Default::default(),
+            operators: Default::default(),
+            owner_user_id: None,
+            antispam: false,
+            censor: false,
+            store_history: false,
+        }
+    }
+
     pub fn has_user(&self, session: usize) -> bool {
         self.users.contains_key(&session)
         //self.users.get(&session).is_some()
     }
 
-    pub fn mute(&mut self, client: usize, until: Instant) {
-        self.mutelist.insert(client, until);
+    pub fn user_count(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn usernames(&self) -> Vec<&str> {
+        self.users.values().map(|user| user.username.as_str()).collect()
+    }
+
+    pub fn addUser(&mut self, session: usize, username: &str, tx: Tx, ignored: IgnoreSet) {
+        self.broadcast(&format!("JOINED {} {}", self.name, username));
+        self.users.insert(session, ChannelUser { username: username.to_string(), tx, ignored });
+    }
+
+    pub fn removeUser(&mut self, session: usize, reason: Option<&str>) {
+        let username = match self.users.get(&session) {
+            None => return,
+            Some(user) => user.username.clone(),
+        };
+        match reason {
+            Some(reason) => self.broadcast(&format!("LEFT {} {} {}", self.name, username, reason)),
+            None => self.broadcast(&format!("LEFT {} {}", self.name, username)),
+        }
+        self.users.remove(&session);
+    }
+
+    // '*' clears the topic, returns false if nothing changed
+    pub fn setTopic(&mut self, username: &str, topic: &str) -> bool {
+        let topic = if topic == "*" { "" } else { topic };
+        if topic == self.topic {
+            return false;
+        }
+        self.topic = topic.to_string();
+        self.topic_author = username.to_string();
+
+        self.broadcast(&format!("CHANNELTOPIC {} {} {}", self.name, username, topic));
+        if topic.is_empty() {
+            self.channelMessage("Topic removed.");
+        } else {
+            self.channelMessage("Topic changed.");
+        }
+        true
+    }
+
+    pub fn setAntispam(&mut self, issuer: &str, enable: bool) {
+        self.antispam = enable;
+        self.channelMessage(&format!("Anti-spam protection was set to {} by <{}>", enable, issuer));
+    }
+
+    pub fn setCensor(&mut self, issuer: &str, enable: bool) {
+        self.censor = enable;
+        self.channelMessage(&format!("Censoring was set to {} by <{}>", enable, issuer));
+    }
+
+    pub fn setHistory(&mut self, issuer: &str, enable: bool) {
+        self.store_history = enable;
+        self.channelMessage(&format!("History retention was set to {} by <{}>", enable, issuer));
+    }
+
+    // '*' removes the key
+    pub fn setKey(&mut self, issuer: &str, key: &str) {
+        if key == "*" {
+            if self.key.take().is_some() {
+                self.channelMessage(&format!("<{}> removed the password of this channel", issuer));
+            }
+        } else {
+            self.key = Some(key.to_string());
+            self.channelMessage(&format!("<{}> set a new password for this channel", issuer));
+        }
+    }
+
+    pub fn hasKey(&self) -> bool {
+        self.key.is_some()
+    }
+
+    pub fn checkKey(&self, key: Option<&str>) -> bool {
+        self.key.is_none() || self.key.as_deref() == key
+    }
+
+    // returns false if the forward already exists
+    pub fn addForward(&mut self, issuer: &str, chan: &str) -> bool {
+        if !self.forwards.insert(chan.to_string()) {
+            return false;
+        }
+        self.channelMessage(&format!("<{}> added forwarding to #{}", issuer, chan));
+        true
+    }
+
+    pub fn removeForward(&mut self, issuer: &str, chan: &str) -> bool {
+        if !self.forwards.remove(chan) {
+            return false;
+        }
+        self.channelMessage(&format!("<{}> removed forwarding to #{}", issuer, chan));
+        true
+    }
+
+    pub fn forwards(&self) -> impl Iterator<Item = &String> {
+        self.forwards.iter()
+    }
+
+    pub fn channelMessage(&self, message: &str) {
+        self.broadcast(&format!("CHANNELMESSAGE {} {}", self.name, message));
+    }
+
+    // returns false if the user already is muted
+    // an expired mute which wasn't swept yet is replaced
+    pub fn muteUser(&mut self, user_id: i32, mute: Mute) -> bool {
+        if self.isMuted(user_id) {
+            return false;
+        }
+        let duration = pretty_time_delta(mute.expires - Utc::now().naive_utc());
+        self.channelMessage(&format!("<{}> has been muted by <{}> for {} (reason: {})", mute.username, mute.issuer, duration, mute.reason));
+        self.mutelist.insert(user_id, mute);
+        true
+    }
+
+    pub fn unmuteUser(&mut self, issuer: &str, user_id: i32) -> Option<Mute> {
+        let mute = self.mutelist.remove(&user_id)?;
+        self.channelMessage(&format!("<{}> has been unmuted by <{}>", mute.username, issuer));
+        Some(mute)
+    }
+
+    pub fn isMuted(&self, user_id: i32) -> bool {
+        self.mutelist.get(&user_id).is_some_and(|mute| Utc::now().naive_utc() < mute.expires)
+    }
+
+    pub fn getMuteMessage(&self, user_id: i32) -> String {
+        match self.mutelist.get(&user_id) {
+            Some(mute) if self.isMuted(user_id) => {
+                format!("muted for {} (reason: {})", pretty_time_delta(mute.expires - Utc::now().naive_utc()), mute.reason)
+            }
+            _ => "not muted".into(),
+        }
+    }
+
+    pub fn mutes(&self) -> impl Iterator<Item = (&i32, &Mute)> {
+        self.mutelist.iter()
+    }
+
+    // returns false if the user already is banned, a present user is kicked
+    pub fn banUser(&mut self, user_id: i32, ban: Ban) -> bool {
+        if self.isBanned(user_id) {
+            return false;
+        }
+        self.ban.insert(user_id, ban);
+        true
+    }
+
+    pub fn isBanned(&self, user_id: i32) -> bool {
+        self.ban.get(&user_id).is_some_and(|ban| Utc::now().naive_utc() < ban.expires)
+    }
+
+    pub fn unbanUser(&mut self, user_id: i32) -> Option<Ban> {
+        self.ban.remove(&user_id)
+    }
+
+    pub fn getBanMessage(&self, user_id: i32, ip_address: &str) -> Option<String> {
+        let now = Utc::now().naive_utc();
+        // an expired ban not swept yet must not hide an active one
+        let active = |ban: &&Ban| now < ban.expires;
+        self.ban
+            .get(&user_id)
+            .filter(active)
+            .or_else(|| self.ban.values().filter(active).find(|ban| ban.ip_address == ip_address))
+            .map(|ban| format!("Cannot join channel '{}' (reason: {}, remaining: {})", self.name, ban.reason, pretty_time_delta(ban.expires - now)))
+    }
+
+    pub fn bans(&self) -> impl Iterator<Item = (&i32, &Ban)> {
+        self.ban.iter()
     }
-    pub fn isMuted(&self, client: usize) -> bool {
-        match self.mutelist.get(&client) {
-            None => false,
-            Some(v) => Instant::now() < *v,
+
+    // removes expired mutes and bans and tells the channel
+    pub fn expire(&mut self, now: NaiveDateTime) {
+        let mutes: Vec<i32> = self.mutelist.iter().filter(|(_, mute)| mute.expires < now).map(|(user_id, _)| *user_id).collect();
+        for user_id in mutes {
+            if let Some(mute) = self.mutelist.remove(&user_id) {
+                self.channelMessage(&format!("<{}> is no longer muted (mute expired)", mute.username));
+            }
+        }
+        let bans: Vec<i32> = self.ban.iter().filter(|(_, ban)| ban.expires < now).map(|(user_id, _)| *user_id).collect();
+        for user_id in bans {
+            if let Some(ban) = self.ban.remove(&user_id) {
+                self.channelMessage(&format!("<{}> is no longer banned (ban expired)", ban.username));
+            }
         }
     }
 
+    // removes the user by name, returns its session if it was present
+    pub fn kickUser(&mut self, issuer: &str, username: &str, reason: Option<&str>) -> Option<usize> {
+        let session = self.users.iter().find(|(_, user)| user.username == username).map(|(session, _)| *session)?;
+        self.channelMessage(&format!("<{}> has been removed from this channel by <{}>", username, issuer));
+        self.removeUser(session, reason);
+        Some(session)
+    }
+
     /*
     def isAdmin(self, client):
         return client and ('admin' in client.accesslevels)
@@ -38,17 +282,120 @@ impl Channel {
         return client and (('mod' in client.accesslevels) or self.isAdmin(client))
     */
 
-    fn isFounder(&self, session: usize) -> bool {
-        return session == self.owner_user_id;
+    pub fn isFounder(&self, user_id: i32) -> bool {
+        self.owner_user_id == Some(user_id)
+    }
+
+    pub fn isOp(&self, user_id: i32) -> bool {
+        self.operators.contains(&user_id) || self.isFounder(user_id)
     }
 
-    pub fn isOp(&self, session: usize) -> bool {
-        return self.operators.contains(&session) || self.isFounder(session);
+    pub fn founder(&self) -> Option<i32> {
+        self.owner_user_id
+    }
+
+    pub fn operators(&self) -> impl Iterator<Item = &i32> {
+        self.operators.iter()
+    }
+
+    pub fn setFounder(&mut self, issuer: &str, user_id: i32, username: &str) {
+        self.owner_user_id = Some(user_id);
+        self.channelMessage(&format!("<{}> has been set as this channel's founder by <{}>", username, issuer));
+    }
+
+    // returns false if the user already was an operator
+    pub fn opUser(&mut self, issuer: &str, user_id: i32, username: &str) -> bool {
+        if !self.operators.insert(user_id) {
+            return false;
+        }
+        self.channelMessage(&format!("<{}> has been added to this channel's operator list by <{}>", username, issuer));
+        true
+    }
+
+    // returns false if the user was no operator
+    pub fn deopUser(&mut self, issuer: &str, user_id: i32, username: &str) -> bool {
+        if !self.operators.remove(&user_id) {
+            return false;
+        }
+        self.channelMessage(&format!("<{}> has been removed from this channel's operator list by <{}>", username, issuer));
+        true
+    }
+
+    pub fn unregister(&mut self, issuer: &str) {
+        self.id = 0;
+        self.owner_user_id = None;
+        self.operators.clear();
+        self.mutelist.clear();
+        self.ban.clear();
+        self.forwards.clear();
+        self.key = None;
+        self.topic.clear();
+        self.channelMessage(&format!("This channel has been unregistered by <{}>", issuer));
     }
 
     pub fn broadcast(&self, message: &str) {
-        self.users.iter().for_each(|(_, tx)| {
-            let _ = tx.send(message.to_string());
+        self.users.iter().for_each(|(_, user)| {
+            let _ = user.tx.send(message.to_string());
+        });
+    }
+
+    // skips users which ignore the sender
+    pub fn broadcast_from(&self, sender_user_id: i32, message: &str) {
+        self.users
+            .values()
+            .filter(|user| !user.ignored.lock().unwrap().contains(&sender_user_id))
+            .for_each(|user| {
+                let _ = user.tx.send(message.to_string());
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mute(reason: &str, expires: NaiveDateTime) -> Mute {
+        Mute { username: "spammer".into(), issuer: "op".into(), expires, reason: reason.into() }
+    }
+
+    #[test]
+    fn test_remute_after_expiry() {
+        let mut channel = Channel::new("main");
+        assert!(channel.muteUser(5, mute("spam", expires_after(Duration::minutes(10)))));
+        assert!(!channel.muteUser(5, mute("again", expires_after(Duration::minutes(10)))));
+        assert!(channel.isMuted(5));
+
+        // not swept yet, but no longer in effect
+        channel.mutelist.get_mut(&5).unwrap().expires = Utc::now().naive_utc() - Duration::minutes(1);
+        assert!(!channel.isMuted(5));
+        assert!(channel.muteUser(5, mute("again", expires_after(Duration::minutes(10)))));
+        assert_eq!(channel.mutes().next().unwrap().1.reason, "again");
+    }
+
+    #[test]
+    fn test_ban_by_ip() {
+        let mut channel = Channel::new("main");
+        assert!(channel.banUser(5, Ban {
+            username: "spammer".into(),
+            issuer: "op".into(),
+            ip_address: "10.0.0.1".into(),
+            expires: never_expires(),
+            reason: "spam".into(),
+        }));
+        assert!(channel.isBanned(5));
+        assert!(channel.getBanMessage(5, "192.168.1.1").is_some());
+        assert!(channel.getBanMessage(6, "10.0.0.1").is_some());
+        assert!(channel.getBanMessage(6, "192.168.1.1").is_none());
+
+        // an expired ban of the user does not hide the active ban of the address
+        channel.ban.insert(6, Ban {
+            username: "other".into(),
+            issuer: "op".into(),
+            ip_address: "192.168.1.1".into(),
+            expires: Utc::now().naive_utc() - Duration::minutes(1),
+            reason: "old".into(),
         });
+        assert!(channel.getBanMessage(6, "10.0.0.1").unwrap().contains("reason: spam"));
+        assert!(channel.getBanMessage(6, "192.168.1.1").is_none());
     }
 }
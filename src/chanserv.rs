@@ -0,0 +1,445 @@
+use chrono::Duration;
+use log::info;
+use tokio::sync::mpsc;
+
+use crate::channel::{expires_after, Ban, Mute};
+use crate::chatserver::ServerState;
+use crate::client::{SharedServerState, Tx};
+use crate::clientstatus::ClientStatus;
+use crate::util::pretty_time_delta;
+
+pub const CHANSERV: &str = "ChanServ";
+// real clients get session ids starting from 1
+pub const CHANSERV_SESSION: usize = 0;
+
+// parses minutes or a number with a unit like 10m, 2h, 3d or 1w, zero or less means forever
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    if let Ok(num) = duration.parse::<i64>() {
+        return if num <= 0 { Some(Duration::MAX) } else { Duration::try_minutes(num) };
+    }
+    let (index, unit) = duration.char_indices().last()?;
+    let num = duration[..index].parse::<i64>().ok()?;
+    if num <= 0 {
+        return Some(Duration::MAX);
+    }
+    // None for durations chrono can't represent
+    match unit {
+        'm' => Duration::try_minutes(num),
+        'h' => Duration::try_hours(num),
+        'd' => Duration::try_days(num),
+        'w' => Duration::try_weeks(num),
+        _ => None,
+    }
+}
+
+// manages interaction between database and channel mods/founders/ops
+pub struct ChanServ {
+    tx: Tx,
+}
+
+#[allow(non_snake_case)]
+impl ChanServ {
+    // logs in ChanServ, joins it to the registered channels and answers its messages
+    pub fn start(state: SharedServerState) {
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let chanserv = ChanServ { tx };
+        {
+            let mut state = state.lock().unwrap();
+            let status = ClientStatus { moderator: true, bot: true, ..Default::default() };
+            state.add_service_session(CHANSERV, CHANSERV_SESSION, status, chanserv.tx.clone());
+            let registered: Vec<String> = state.channels().filter(|channel| channel.id != 0).map(|channel| channel.name.clone()).collect();
+            for chan in registered {
+                chanserv.join(&mut state, &chan);
+            }
+        }
+        info!("[{}] <{}> logged in (access=ChanServ)", CHANSERV_SESSION, CHANSERV);
+
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                let mut state = state.lock().unwrap();
+                chanserv.Handle(&mut state, &msg);
+            }
+        });
+    }
+
+    fn join(&self, state: &mut ServerState, chan: &str) {
+        if let Some(channel) = state.get_channel(chan) {
+            if !channel.has_user(CHANSERV_SESSION) {
+                channel.addUser(CHANSERV_SESSION, CHANSERV, self.tx.clone(), Default::default());
+            }
+        }
+    }
+
+    fn leave(&self, state: &mut ServerState, chan: &str) {
+        if let Some(channel) = state.get_channel(chan) {
+            channel.removeUser(CHANSERV_SESSION, None);
+        }
+    }
+
+    // called with everything the server sends to ChanServ
+    fn Handle(&self, state: &mut ServerState, msg: &str) {
+        let (cmd, args) = match msg.split_once(' ') {
+            None => return,
+            Some(split) => split,
+        };
+        match cmd {
+            "SAID" => {
+                let mut parts = args.splitn(3, ' ');
+                if let (Some(chan), Some(user), Some(msg)) = (parts.next(), parts.next(), parts.next()) {
+                    self.HandleMessage(state, Some(chan), user, msg);
+                }
+            }
+            "SAIDPRIVATE" => {
+                if let Some((user, msg)) = args.split_once(' ') {
+                    self.HandleMessage(state, None, user, msg);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn Respond(&self, state: &ServerState, user: &str, msg: &str) {
+        if let Some(session) = state.clientFromUsername(user) {
+            session.Send(&format!("SAIDPRIVATE {} {}", CHANSERV, msg));
+        }
+    }
+
+    fn HandleMessage(&self, state: &mut ServerState, chan: Option<&str>, user: &str, msg: &str) {
+        if user == CHANSERV || msg.is_empty() {
+            return;
+        }
+        let msg = match msg.strip_prefix(':') {
+            Some(msg) => msg,
+            None => {
+                if chan.is_none() {
+                    self.Respond(state, user, "ChanServ commands must be prefixed by a colon e.g. ':help'");
+                }
+                return;
+            }
+        };
+
+        // in a channel the channel is implied, via pm it is the first argument
+        let (cmd, chan, args) = match chan {
+            Some(chan) => {
+                let (cmd, args) = msg.split_once(' ').map_or((msg, None), |(cmd, args)| (cmd, Some(args)));
+                (cmd, Some(chan), args)
+            }
+            None => {
+                let mut parts = msg.splitn(3, ' ');
+                (parts.next().unwrap_or(""), parts.next(), parts.next())
+            }
+        };
+
+        if let Some(response) = self.HandleCommand(state, user, &cmd.to_lowercase(), chan, args.unwrap_or("")) {
+            for line in response.split('\n') {
+                self.Respond(state, user, line);
+            }
+        }
+    }
+
+    fn HandleCommand(&self, state: &mut ServerState, user: &str, cmd: &str, chan: Option<&str>, args: &str) -> Option<String> {
+        let (user_id, is_mod) = state.clientFromUsername(user).map(|session| (session.user_id, session.status.moderator))?;
+
+        if cmd == "help" {
+            return Some(format!("Hello, {}!\nI am the server bot.\nFor the full list of my commands, see https://springrts.com/wiki/ChanServ\n\
+                If you want to go ahead and register a new channel, please contact one of the server moderators!", user));
+        }
+
+        let chan = match chan {
+            None => return Some("Channel not specified".into()),
+            Some(chan) if chan.starts_with('#') => {
+                return Some("ChanServ commands do not permit the # character to prefix channel names, please retry".into());
+            }
+            Some(chan) => chan,
+        };
+
+        if cmd == "register" {
+            if !is_mod {
+                return Some(format!("#{}: You must contact one of the server moderators to register a channel", chan));
+            }
+            let target = if args.is_empty() { user } else { args };
+            let founder = match state.users.clientFromUsername(target) {
+                None => return Some(format!("#{}: User <{}> not found", chan, target)),
+                Some(founder) => founder,
+            };
+            if let Err(reason) = state.register_channel(chan, user, founder.id.unwrap_or_default(), &founder.username) {
+                return Some(format!("#{}: {}", chan, reason));
+            }
+            self.join(state, chan);
+            return Some(format!("#{}: Successfully registered to <{}>", chan, founder.username));
+        }
+
+        let access = match state.get_channel(chan) {
+            None => return Some(format!("Channel '{}' does not exist", chan)),
+            Some(channel) if !channel.has_user(CHANSERV_SESSION) => {
+                return Some(format!("ChanServ is not present in channel '{}' (unregistered?)", chan));
+            }
+            Some(_) if is_mod => "mod",
+            Some(channel) if channel.isFounder(user_id) => "founder",
+            Some(channel) if channel.isOp(user_id) => "op",
+            Some(_) => "normal",
+        };
+        let founder_access = access == "mod" || access == "founder";
+        let op_access = founder_access || access == "op";
+
+        let response = match cmd {
+            "unregister" if !founder_access => {
+                format!("#{}: You must contact one of the server moderators or the owner of the channel to unregister a channel", chan)
+            }
+            "unregister" => {
+                self.leave(state, chan);
+                match state.unregister_channel(chan, user) {
+                    Ok(()) => format!("#{}: Successfully unregistered.", chan),
+                    Err(reason) => {
+                        self.join(state, chan);
+                        format!("#{}: {}", chan, reason)
+                    }
+                }
+            }
+            "forward" | "unforward" if !is_mod => {
+                format!("#{}: You must contact one of the server moderators to change the forwarding of a channel", chan)
+            }
+            "forward" | "unforward" if args.is_empty() => format!("#{}: You must specify a channel to forward to", chan),
+            "forward" => {
+                let chan_to = args.trim_start_matches('#');
+                match state.add_channel_forward(chan, user, chan_to) {
+                    Ok(()) => format!("#{}: Successfully added forwarding to #{}", chan, chan_to),
+                    Err(reason) => format!("#{}: {}", chan, reason),
+                }
+            }
+            "unforward" => {
+                let chan_to = args.trim_start_matches('#');
+                match state.remove_channel_forward(chan, user, chan_to) {
+                    Ok(()) => format!("#{}: Successfully removed forwarding to #{}", chan, chan_to),
+                    Err(reason) => format!("#{}: {}", chan, reason),
+                }
+            }
+            "listforwards" => {
+                let forwards: Vec<String> = state.get_channel(chan)?.forwards().map(|forward| format!("#{}", forward)).collect();
+                if forwards.is_empty() {
+                    format!("#{}: Not forwarding to anywhere", chan)
+                } else {
+                    format!("#{}: Forwarding to {}", chan, forwards.join(" "))
+                }
+            }
+            "history" | "antispam" | "censor" if !founder_access => {
+                format!("#{}: You must contact one of the server moderators or the owner of the channel to change the {} settings", chan, cmd)
+            }
+            "history" | "antispam" | "censor" => {
+                let enable = match args {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Some(format!("#{}: Unknown value for {} setting (expected: on, off).", chan, cmd)),
+                };
+                let (result, setting) = match cmd {
+                    "history" => (state.set_channel_history(chan, user, enable), "History"),
+                    "antispam" => (state.set_channel_antispam(chan, user, enable), "Anti-spam protection"),
+                    _ => (state.set_channel_censor(chan, user, enable), "Censoring"),
+                };
+                match result {
+                    Ok(()) => format!("#{}: {} is {}.", chan, setting, args),
+                    Err(reason) => format!("#{}: {}", chan, reason),
+                }
+            }
+            "setkey" if !founder_access => format!("#{}: You do not have permission to change the channel password", chan),
+            "setkey" if args.is_empty() || args.contains(' ') => {
+                format!("#{}: You must specify a password for the channel (use '*' for no password)", chan)
+            }
+            "setkey" => match state.set_channel_key(chan, user, args) {
+                Ok(()) => format!("#{}: Set key", chan),
+                Err(reason) => format!("#{}: {}", chan, reason),
+            },
+            "op" | "deop" | "changefounder" if !founder_access => {
+                format!("#{}: You do not have permission to change the operators or the founder of this channel", chan)
+            }
+            "op" | "deop" | "changefounder" if args.is_empty() => format!("#{}: You must specify a user", chan),
+            "op" | "deop" | "changefounder" => {
+                let target = match state.users.clientFromUsername(args) {
+                    None => return Some(format!("#{}: User <{}> not found", chan, args)),
+                    Some(target) => target,
+                };
+                let target_id = target.id.unwrap_or_default();
+                let result = match cmd {
+                    "op" => state.op_channel_user(chan, user, target_id, &target.username)
+                        .map(|_| format!("added <{}> to operator list", target.username)),
+                    "deop" => state.deop_channel_user(chan, user, target_id, &target.username)
+                        .map(|_| format!("removed <{}> from operator list", target.username)),
+                    _ => state.set_channel_founder(chan, user, target_id, &target.username)
+                        .map(|_| format!("changed founder to <{}>", target.username)),
+                };
+                format!("#{}: {}", chan, result.unwrap_or_else(|reason| reason))
+            }
+            "mute" | "unmute" | "listmutes" | "ban" | "unban" | "listbans" | "kick" | "topic" if !op_access => {
+                format!("#{}: You do not have permission to execute this command", chan)
+            }
+            "mute" | "ban" => self.restrict(state, user, user_id, cmd, chan, args),
+            "unmute" | "unban" => {
+                let target_id = state.users.clientFromUsername(args).and_then(|target| target.id).unwrap_or_default();
+                let result = if cmd == "unmute" {
+                    state.unmute_channel_user(chan, user, target_id, args)
+                } else {
+                    state.unban_channel_user(chan, target_id, args)
+                };
+                match result {
+                    Ok(()) => format!("#{}: {}d <{}>", chan, cmd, args),
+                    Err(reason) => format!("#{}: {}", chan, reason),
+                }
+            }
+            "listmutes" => {
+                let channel = state.get_channel(chan)?;
+                let mut mutes: Vec<String> = channel.mutes()
+                    .map(|(_, mute)| format!("{} :: {} :: ends {} ({})", mute.username, mute.reason, mute.expires.format("%Y-%m-%d %H:%M:%S"), mute.issuer))
+                    .collect();
+                if mutes.is_empty() {
+                    return Some("The mutelist is empty.".into());
+                }
+                mutes.sort();
+                format!(" -- Mutelist for {} -- \n{}\n -- End Mutelist -- ", chan, mutes.join("\n"))
+            }
+            "listbans" => {
+                let channel = state.get_channel(chan)?;
+                let mut bans: Vec<String> = channel.bans()
+                    .map(|(_, ban)| format!("{} :: {} :: {} :: ends {} ({})", ban.username, ban.ip_address, ban.reason, ban.expires.format("%Y-%m-%d %H:%M:%S"), ban.issuer))
+                    .collect();
+                if bans.is_empty() {
+                    return Some("The banlist is empty.".into());
+                }
+                bans.sort();
+                format!(" -- Banlist for {} -- \n{}\n -- End Banlist -- ", chan, bans.join("\n"))
+            }
+            "kick" if args.is_empty() => format!("#{}: You must specify a user to kick from the channel", chan),
+            "kick" => {
+                let target_id = state.clientFromUsername(args).map(|session| session.user_id);
+                if target_id.is_some_and(|target_id| state.get_channel(chan).is_some_and(|channel| channel.isOp(target_id))) {
+                    return Some(format!("#{}: Cannot kick <{}>, user has operator status", chan, args));
+                }
+                match state.kick_channel_user(chan, user, args, "") {
+                    Ok(()) => format!("#{}: kicked <{}>", chan, args),
+                    Err(reason) => format!("#{}: {}", chan, reason),
+                }
+            }
+            "topic" => match state.set_channel_topic(chan, user_id, user, args) {
+                Ok(()) => format!("#{}: Topic changed", chan),
+                Err(reason) => format!("#{}: {}", chan, reason),
+            },
+            "info" => self.info(state, chan)?,
+            // probably just a smiley or suchlike - not meant to invoke ChanServ
+            _ if cmd.len() < 3 || !cmd.chars().all(char::is_alphabetic) => return None,
+            _ => format!("command '{}' does not exist, try ':help' to get help", cmd),
+        };
+        Some(response)
+    }
+
+    // mute or ban, args are the target username, a duration and a reason
+    fn restrict(&self, state: &mut ServerState, user: &str, user_id: i32, cmd: &str, chan: &str, args: &str) -> String {
+        let mut parts = args.splitn(3, ' ');
+        let (target_username, duration, reason) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(target), Some(duration), Some(reason)) if !target.is_empty() && !duration.is_empty() && !reason.is_empty() => {
+                (target, duration, reason)
+            }
+            _ => return format!("#{}: Please specify a target username, a duration, and a reason (in that order)", chan),
+        };
+        let duration = match parse_duration(duration) {
+            None => {
+                return format!("#{}: Could not parse duration {}, please enter a number of minutes or specify a time unit e.g. '10m', '2h', or '3d'",
+                    chan, duration);
+            }
+            Some(duration) => duration,
+        };
+        let target = match state.users.clientFromUsername(target_username) {
+            None => return format!("#{}: User <{}> not found", chan, target_username),
+            Some(target) => target,
+        };
+        let target_id = target.id.unwrap_or_default();
+        if state.get_channel(chan).is_some_and(|channel| channel.isOp(target_id)) {
+            return format!("#{}: Cannot {} <{}>, user has operator status", chan, cmd, target.username);
+        }
+
+        let expires = expires_after(duration);
+        let result = if cmd == "mute" {
+            state.mute_channel_user(chan, user_id, target_id, Mute {
+                username: target.username.clone(),
+                issuer: user.to_string(),
+                expires,
+                reason: reason.to_string(),
+            })
+        } else {
+            state.ban_channel_user(chan, user_id, target_id, Ban {
+                username: target.username.clone(),
+                issuer: user.to_string(),
+                ip_address: target.last_ip.clone(),
+                expires,
+                reason: reason.to_string(),
+            })
+        };
+        match result {
+            Ok(()) => format!("#{}: {}d <{}> for {}", chan, if cmd == "mute" { "mute" } else { "banne" }, target.username, pretty_time_delta(duration)),
+            Err(reason) => format!("#{}: {}", chan, reason),
+        }
+    }
+
+    fn info(&self, state: &mut ServerState, chan: &str) -> Option<String> {
+        let channel = state.get_channel(chan)?;
+        let antispam = if channel.antispam { "Anti-spam protection is on" } else { "Anti-spam protection is off" };
+        let history = if channel.store_history { "Channel history is on" } else { "Channel history is off" };
+        let censor = if channel.censor { "Censoring is on" } else { "Censoring is off" };
+        let founder_id = channel.founder();
+        let operator_ids: Vec<i32> = channel.operators().copied().collect();
+        let user_count = channel.user_count();
+
+        let founder = founder_id
+            .and_then(|user_id| state.users.clientFromID(user_id))
+            .map_or("No founder is registered".to_string(), |founder| format!("Founder is <{}>", founder.username));
+        let mut operators: Vec<String> = operator_ids.into_iter()
+            .filter_map(|user_id| state.users.clientFromID(user_id))
+            .map(|op| op.username)
+            .collect();
+        operators.sort();
+        let operators = if operators.is_empty() { "empty".to_string() } else { format!("[{}]", operators.join(" ")) };
+        Some(format!("#{} info: {}. Operator list is {}. Currently contains {} users. {}. {}. {}.",
+            chan, founder, operators, user_count, antispam, history, censor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("10"), Some(Duration::minutes(10)));
+        assert_eq!(parse_duration("10m"), Some(Duration::minutes(10)));
+        assert_eq!(parse_duration("2h"), Some(Duration::hours(2)));
+        assert_eq!(parse_duration("3d"), Some(Duration::days(3)));
+        assert_eq!(parse_duration("1w"), Some(Duration::weeks(1)));
+        assert_eq!(parse_duration("0"), Some(Duration::MAX));
+        assert_eq!(parse_duration("-5h"), Some(Duration::MAX));
+    }
+
+    #[test]
+    fn test_parse_invalid_duration() {
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("2y"), None);
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration("1é"), None);
+        assert_eq!(parse_duration("é"), None);
+    }
+
+    #[test]
+    fn test_parse_huge_duration() {
+        assert_eq!(parse_duration("99999999999999999"), None);
+        assert_eq!(parse_duration("9999999999999w"), None);
+        assert_eq!(parse_duration("99999999999999999999"), None);
+        // representable, but past what a date can hold
+        assert_eq!(expires_after(parse_duration("100000000d").unwrap()), crate::channel::never_expires());
+        assert_eq!(parse_duration("52w"), Some(Duration::weeks(52)));
+    }
+
+    #[test]
+    fn test_expires_after() {
+        let now = chrono::Utc::now().naive_utc();
+        assert!(expires_after(Duration::minutes(10)) > now);
+        assert_eq!(expires_after(Duration::MAX), crate::channel::never_expires());
+    }
+}
@@ -1,54 +1,750 @@
 use futures::SinkExt;
 use log::{debug, error, info};
 use std::io;
-use std::net::SocketAddr;
-use tokio::net::{TcpListener, TcpStream};
+use std::net::{IpAddr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
 use std::sync::Arc;
-use std::sync::Mutex as StdMutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::sync::{mpsc, Mutex};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{Framed, LinesCodec};
 use tokio::time::{sleep, Duration, Instant};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
-use crate::client::Client;
+use crate::antispam::AntiSpam;
+use crate::client::{AccessLevel, Client, Role};
 use crate::client::SharedServerState;
-use crate::channel::Channel;
+use crate::client::{ChannelSet, IgnoreSet, Tx};
+use crate::channel::{expires_after, Ban, Channel, Mute};
+use crate::battle::Battle;
+use crate::clientstatus::ClientStatus;
+use crate::floodlimit::FloodLimits;
+use crate::jsonproto;
+use crate::sayhooks::SharedSayHooks;
+use crate::sqlusers::{ChannelBan, ChannelMute, UsersHandler};
+use crate::websocket;
 
 const SOCKET_LIMIT: usize = 1024; // TODO rlimit::getrlimit_nofile() / 2;
-const TCP_CHAR_LIMIT: usize = 1024;
+const IP_LIMIT: usize = 16;
 const TIMEOUT: u64 = 60;
+// session ids are unique across all listeners, 0 is ChanServ
+static NEXT_SESSION_ID: AtomicUsize = AtomicUsize::new(1);
+// open connections in total and per address, over all listeners like the session ids
+static CONNECTED_CLIENTS: AtomicUsize = AtomicUsize::new(0);
+static CONNECTIONS_PER_IP: std::sync::Mutex<BTreeMap<IpAddr, usize>> = std::sync::Mutex::new(BTreeMap::new());
+// a plain or TLS connection, STLS replaces one by the other
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+type Lines = Framed<Box<dyn Stream>, LinesCodec>;
+
 pub struct ChatServer {
-    tls: bool,
-    connected_clients: usize,
+    // for STLS, or all connections when implicit
+    tls: Option<TlsAcceptor>,
+    implicit_tls: bool,
     // root
 }
 
-#[derive(Default)]
+// one connection, counted in total and for its address until dropped
+struct ConnectionSlot(IpAddr);
+
+impl ConnectionSlot {
+    // counts a new connection, unless there are too many in total or from its address
+    fn acquire(addr: SocketAddr) -> Result<Self, &'static str> {
+        // the total only changes under this lock too
+        let mut connections = CONNECTIONS_PER_IP.lock().unwrap();
+        let connected_clients = CONNECTED_CLIENTS.load(Ordering::Relaxed);
+        if connected_clients >= SOCKET_LIMIT {
+            error!("too many connections: {} > {}", connected_clients, SOCKET_LIMIT);
+            return Err("too many connections, sorry!");
+        }
+        let count = connections.entry(addr.ip()).or_insert(0);
+        if *count >= IP_LIMIT {
+            error!("too many connections from {}: {}", addr.ip(), IP_LIMIT);
+            return Err("too many connections from your address, sorry!");
+        }
+        *count += 1;
+        CONNECTED_CLIENTS.fetch_add(1, Ordering::Relaxed);
+        Ok(Self(addr.ip()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = CONNECTIONS_PER_IP.lock().unwrap();
+        CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+        if let Some(count) = connections.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.0);
+            }
+        }
+    }
+}
+
+// a logged in client as seen by everyone else
+pub struct Session {
+    pub session_id: usize,
+    pub user_id: i32,
+    pub status: ClientStatus,
+    // changed by SETACCESS, the client picks it up with its next command
+    pub accesslevels: AccessLevel,
+    tx: Tx,
+    ignored: IgnoreSet,
+    channels: ChannelSet,
+}
+
+#[allow(non_snake_case)]
+impl Session {
+    pub fn is_ignoring(&self, user_id: i32) -> bool {
+        self.ignored.lock().unwrap().contains(&user_id)
+    }
+
+    pub fn Send(&self, message: &str) {
+        let _ = self.tx.send(message.to_string());
+    }
+}
+
 pub struct ServerState {
-    channels: HashMap<String, Channel>
+    channels: HashMap<String, Channel>,
+    battles: HashMap<usize, Battle>,
+    nextbattle: usize,
+    pub usernames: HashMap<String, Session>,
+    pub users: UsersHandler,
+    pub server_version: String,
+    pub natport: u32,
+    pub agreement: Vec<String>,
+    pub sayhooks: SharedSayHooks,
+    pub antispam: AntiSpam,
+    pub flood_limits: FloodLimits,
 }
 
+#[allow(non_snake_case)]
 impl ServerState {
+    pub fn new(users: UsersHandler, server_version: String, natport: u32, agreement: Vec<String>, sayhooks: SharedSayHooks) -> Self {
+        let mut state = Self {
+            channels: Default::default(),
+            battles: Default::default(),
+            nextbattle: 0,
+            usernames: Default::default(),
+            users,
+            server_version,
+            natport,
+            agreement,
+            sayhooks,
+            antispam: AntiSpam::load(),
+            flood_limits: Default::default(),
+        };
+        state.load_channels();
+        state
+    }
+
+    // sets up the registered channels from the database
+    fn load_channels(&mut self) {
+        let dbchannels = self.users.channels().all_channels().unwrap_or_else(|e| panic!("Could not load channels: {}", e));
+        for dbchannel in dbchannels {
+            let mut channel = Channel::new(&dbchannel.name);
+            channel.id = dbchannel.id.unwrap_or_default();
+            channel.antispam = dbchannel.antispam;
+            channel.censor = dbchannel.censor;
+            channel.store_history = dbchannel.store_history;
+            if let Some(key) = dbchannel.key.filter(|key| !key.is_empty() && key != "*") {
+                channel.setKey("ChanServ", &key);
+            }
+            if let Some(owner) = dbchannel.owner_user_id.and_then(|user_id| self.users.clientFromID(user_id)) {
+                channel.setFounder("ChanServ", owner.id.unwrap_or_default(), &owner.username);
+            }
+            if let Some(topic) = dbchannel.topic {
+                let author = dbchannel.topic_user_id
+                    .and_then(|user_id| self.users.clientFromID(user_id))
+                    .map_or("ChanServ".to_string(), |user| user.username);
+                channel.setTopic(&author, &topic);
+            }
+            self.channels.insert(dbchannel.name, channel);
+        }
+
+        let operators = self.users.channels().all_operators().unwrap_or_else(|e| panic!("Could not load channel operators: {}", e));
+        for op in operators {
+            let channel = self.channels.values_mut().find(|channel| channel.id == op.channel_id);
+            if let (Some(channel), Some(target)) = (channel, self.users.clientFromID(op.user_id)) {
+                channel.opUser("ChanServ", op.user_id, &target.username);
+            }
+        }
+
+        let forwards = self.users.channels().all_forwards().unwrap_or_else(|e| panic!("Could not load channel forwards: {}", e));
+        for forward in forwards {
+            let channel_to = self.channels.values().find(|channel| channel.id == forward.channel_to_id).map(|channel| channel.name.clone());
+            let channel_from = self.channels.values_mut().find(|channel| channel.id == forward.channel_from_id);
+            if let (Some(channel_from), Some(channel_to)) = (channel_from, channel_to) {
+                channel_from.addForward("ChanServ", &channel_to);
+            }
+        }
+
+        let users = &self.users;
+        let mutes = users.channels().all_mutes().unwrap_or_else(|e| panic!("Could not load channel mutes: {}", e));
+        for mute in mutes {
+            let channel = self.channels.values_mut().find(|channel| channel.id == mute.channel_id);
+            if let (Some(channel), Some(target)) = (channel, users.clientFromID(mute.user_id)) {
+                let issuer = mute.issuer_user_id.and_then(|user_id| users.clientFromID(user_id));
+                channel.muteUser(mute.user_id, Mute {
+                    username: target.username,
+                    issuer: issuer.map_or("ChanServ".into(), |issuer| issuer.username),
+                    expires: mute.expires,
+                    reason: mute.reason,
+                });
+            }
+        }
+
+        let bans = users.channels().all_bans().unwrap_or_else(|e| panic!("Could not load channel bans: {}", e));
+        for ban in bans {
+            let channel = self.channels.values_mut().find(|channel| channel.id == ban.channel_id);
+            if let (Some(channel), Some(target)) = (channel, users.clientFromID(ban.user_id)) {
+                let issuer = ban.issuer_user_id.and_then(|user_id| users.clientFromID(user_id));
+                channel.banUser(ban.user_id, Ban {
+                    username: target.username,
+                    issuer: issuer.map_or("ChanServ".into(), |issuer| issuer.username),
+                    ip_address: ban.ip_address,
+                    expires: ban.expires,
+                    reason: ban.reason,
+                });
+            }
+        }
+        info!("Loaded {} registered channels", self.channels.len());
+    }
+
+    fn registered_channel(&mut self, chan: &str) -> Result<&mut Channel, String> {
+        match self.channels.get_mut(chan) {
+            None => Err(format!("Channel {} does not exist", chan)),
+            Some(channel) if channel.id == 0 => Err("Not registered".into()),
+            Some(channel) => Ok(channel),
+        }
+    }
+
+    // registers an existing channel to founder_user_id, the caller is responsible for permission checks
+    pub fn register_channel(&mut self, chan: &str, issuer: &str, founder_user_id: i32, founder: &str) -> Result<(), String> {
+        let channel = match self.channels.get_mut(chan) {
+            None => return Err(format!("Channel {} does not exist", chan)),
+            Some(channel) if channel.founder().is_some() => return Err("Already registered".into()),
+            Some(channel) => channel,
+        };
+        if channel.id == 0 {
+            let topic = (!channel.topic.is_empty()).then_some(channel.topic.as_str());
+            channel.id = self.users.channels().register(chan, founder_user_id, topic).map_err(|e| e.to_string())?;
+        } else {
+            // stored for its history but without founder so far
+            self.users.channels().setFounder(channel.id, founder_user_id).map_err(|e| e.to_string())?;
+        }
+        channel.setFounder(issuer, founder_user_id, founder);
+        Ok(())
+    }
+
+    pub fn unregister_channel(&mut self, chan: &str, issuer: &str) -> Result<(), String> {
+        let channel_id = self.registered_channel(chan)?.id;
+        self.users.channels().unRegister(channel_id).map_err(|e| e.to_string())?;
+        for channel in self.channels.values_mut() {
+            channel.removeForward(issuer, chan);
+        }
+        let channel = self.channels.get_mut(chan).unwrap();
+        channel.unregister(issuer);
+        if channel.user_count() == 0 {
+            self.channels.remove(chan);
+        }
+        Ok(())
+    }
+
+    pub fn set_channel_founder(&mut self, chan: &str, issuer: &str, user_id: i32, username: &str) -> Result<(), String> {
+        let channel_id = self.registered_channel(chan)?.id;
+        self.users.channels().setFounder(channel_id, user_id).map_err(|e| e.to_string())?;
+        self.registered_channel(chan)?.setFounder(issuer, user_id, username);
+        Ok(())
+    }
+
+    pub fn op_channel_user(&mut self, chan: &str, issuer: &str, user_id: i32, username: &str) -> Result<(), String> {
+        let channel = self.registered_channel(chan)?;
+        if channel.isOp(user_id) {
+            return Err(format!("<{}> was already an op", username));
+        }
+        let channel_id = channel.id;
+        self.users.channels().opUser(channel_id, user_id).map_err(|e| e.to_string())?;
+        self.registered_channel(chan)?.opUser(issuer, user_id, username);
+        Ok(())
+    }
+
+    pub fn deop_channel_user(&mut self, chan: &str, issuer: &str, user_id: i32, username: &str) -> Result<(), String> {
+        let channel = self.registered_channel(chan)?;
+        if channel.isFounder(user_id) {
+            return Err("Cannot remove operator status from channel founder".into());
+        }
+        if !channel.isOp(user_id) {
+            return Err(format!("<{}> was not an op", username));
+        }
+        let channel_id = channel.id;
+        self.users.channels().deopUser(channel_id, user_id).map_err(|e| e.to_string())?;
+        self.registered_channel(chan)?.deopUser(issuer, user_id, username);
+        Ok(())
+    }
+
     pub fn get_channel(&mut self, channel : &str) -> Option<&mut Channel> {
         self.channels.get_mut(channel)
     }
+
+    pub fn channels(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.values()
+    }
+
+    // JOIN with ban and key checks, the forwards of the channel are joined as well
+    pub fn enter_channel(&mut self, client: &mut Client, chan: &str, key: Option<&str>) -> Result<(), String> {
+        if let Some(channel) = self.channels.get(chan) {
+            if !channel.isFounder(client.user_id) && !client.accesslevels.isMod() {
+                if let Some(message) = channel.getBanMessage(client.user_id, &client.ip_address) {
+                    return Err(message);
+                }
+                if !channel.checkKey(key) {
+                    return Err("Invalid key".into());
+                }
+            }
+        }
+        self.join_channel(client, chan);
+
+        let forwards: Vec<String> = self.channels[chan].forwards().cloned().collect();
+        for forward in forwards {
+            // forwards skip the key but not bans and are not followed any further
+            let allowed = match self.channels.get(&forward) {
+                None => false,
+                Some(channel) => client.accesslevels.isMod() || channel.isFounder(client.user_id)
+                    || channel.getBanMessage(client.user_id, &client.ip_address).is_none(),
+            };
+            if allowed {
+                self.join_channel(client, &forward);
+            }
+        }
+        Ok(())
+    }
+
+    // creates the channel on demand, the caller is responsible for permission checks
+    pub fn join_channel(&mut self, client: &mut Client, chan: &str) {
+        let channel = self.channels
+            .entry(chan.to_string())
+            .or_insert_with(|| Channel::new(chan));
+        if channel.has_user(client.session_id) {
+            return;
+        }
+        channel.addUser(client.session_id, &client.username, client.send_message_queue.clone(), client.ignored.clone());
+        client.channels.lock().unwrap().insert(chan.to_string());
+
+        client.Send(&format!("JOIN {}", chan));
+        if !channel.topic.is_empty() {
+            client.Send(&format!("CHANNELTOPIC {} {} {}", chan, channel.topic_author, channel.topic));
+        }
+        client.Send(&format!("CLIENTS {} {}", chan, channel.usernames().join(" ")));
+    }
+
+    // history is stored for channels of the channels table, enabling it adds the channel there
+    pub fn set_channel_history(&mut self, chan: &str, issuer: &str, enable: bool) -> Result<(), String> {
+        let channel = self.channels.get_mut(chan).ok_or(format!("Channel {} does not exist", chan))?;
+        channel.id = self.users.channels().setHistory(chan, enable).map_err(|e| e.to_string())?;
+        channel.setHistory(issuer, enable);
+        Ok(())
+    }
+
+    pub fn leave_channel(&mut self, client: &mut Client, chan: &str, reason: Option<&str>) {
+        client.channels.lock().unwrap().remove(chan);
+        if let Some(channel) = self.channels.get_mut(chan) {
+            channel.removeUser(client.session_id, reason);
+            // registered channels are kept with their settings
+            if channel.user_count() == 0 && channel.id == 0 {
+                self.channels.remove(chan);
+            }
+        }
+    }
+
+    // topics are stored for registered channels, the caller is responsible for permission checks
+    pub fn set_channel_topic(&mut self, chan: &str, user_id: i32, username: &str, topic: &str) -> Result<(), String> {
+        let channel = self.channels.get_mut(chan).ok_or(format!("Channel {} does not exist", chan))?;
+        if !channel.setTopic(username, topic) || channel.id == 0 {
+            return Ok(());
+        }
+        self.users.channels().setTopic(chan, &channel.topic, user_id).map_err(|e| e.to_string())
+    }
+
+    pub fn set_channel_antispam(&mut self, chan: &str, issuer: &str, enable: bool) -> Result<(), String> {
+        let channel = self.registered_channel(chan)?;
+        channel.setAntispam(issuer, enable);
+        self.users.channels().setAntispam(chan, enable).map_err(|e| e.to_string())
+    }
+
+    pub fn set_channel_censor(&mut self, chan: &str, issuer: &str, enable: bool) -> Result<(), String> {
+        let channel = self.registered_channel(chan)?;
+        channel.setCensor(issuer, enable);
+        self.users.channels().setCensor(chan, enable).map_err(|e| e.to_string())
+    }
+
+    pub fn set_channel_key(&mut self, chan: &str, issuer: &str, key: &str) -> Result<(), String> {
+        let channel = self.channels.get_mut(chan).ok_or(format!("Channel {} does not exist", chan))?;
+        if channel.id != 0 {
+            let key = (key != "*").then_some(key);
+            self.users.channels().setKey(channel.id, key).map_err(|e| e.to_string())?;
+        }
+        channel.setKey(issuer, key);
+        Ok(())
+    }
+
+    // users joining chan also join chan_to, both channels have to be registered
+    pub fn add_channel_forward(&mut self, chan: &str, issuer: &str, chan_to: &str) -> Result<(), String> {
+        if chan == chan_to {
+            return Err("Cannot forward a channel to itself".into());
+        }
+        let channel_to_id = self.registered_channel(chan_to)?.id;
+        let channel = self.registered_channel(chan)?;
+        if channel.forwards().any(|forward| forward == chan_to) {
+            return Err(format!("Forwarding already exists to #{}", chan_to));
+        }
+        let channel_id = channel.id;
+        self.users.channels().addForward(channel_id, channel_to_id).map_err(|e| e.to_string())?;
+        self.registered_channel(chan)?.addForward(issuer, chan_to);
+        Ok(())
+    }
+
+    pub fn remove_channel_forward(&mut self, chan: &str, issuer: &str, chan_to: &str) -> Result<(), String> {
+        let channel_to_id = self.registered_channel(chan_to)?.id;
+        let channel = self.registered_channel(chan)?;
+        if !channel.forwards().any(|forward| forward == chan_to) {
+            return Err(format!("No forwarding exists to #{}", chan_to));
+        }
+        let channel_id = channel.id;
+        self.users.channels().removeForward(channel_id, channel_to_id).map_err(|e| e.to_string())?;
+        self.registered_channel(chan)?.removeForward(issuer, chan_to);
+        Ok(())
+    }
+
+    // removes a present user from the channel and tells the user why, the caller is responsible for permission checks
+    pub fn kick_channel_user(&mut self, chan: &str, issuer: &str, username: &str, reason: &str) -> Result<(), String> {
+        let channel = self.channels.get_mut(chan).ok_or(format!("Channel {} does not exist", chan))?;
+        let session = self.usernames.get(username).filter(|session| channel.has_user(session.session_id));
+        match session {
+            None => return Err(format!("User <{}> not found", username)),
+            Some(session) => {
+                session.Send(format!("FORCELEAVECHANNEL {} {} {}", chan, issuer, reason).trim_end());
+                session.channels.lock().unwrap().remove(chan);
+            }
+        }
+        channel.kickUser(issuer, username, (!reason.is_empty()).then_some(reason));
+        if channel.user_count() == 0 && channel.id == 0 {
+            self.channels.remove(chan);
+        }
+        Ok(())
+    }
+
+    // mutes are stored for registered channels, the caller is responsible for permission checks
+    pub fn mute_channel_user(&mut self, chan: &str, issuer_user_id: i32, user_id: i32, mute: Mute) -> Result<(), String> {
+        let channel = self.channels.get_mut(chan).ok_or(format!("Channel {} does not exist", chan))?;
+        let now = chrono::Utc::now().naive_utc();
+        if let Some((_, mute)) = channel.mutes().find(|(muted, mute)| **muted == user_id && now < mute.expires) {
+            return Err(format!("User <{}> is already muted by <{}>", mute.username, mute.issuer));
+        }
+        if channel.id != 0 {
+            // drops an expired mute the sweep hasn't removed yet
+            self.users.channels().unmuteUser(channel.id, user_id).map_err(|e| e.to_string())?;
+            self.users.channels().muteUser(&ChannelMute {
+                id: None,
+                channel_id: channel.id,
+                issuer_user_id: (issuer_user_id != 0).then_some(issuer_user_id),
+                user_id,
+                expires: mute.expires,
+                reason: mute.reason.clone(),
+            }).map_err(|e| e.to_string())?;
+        }
+        channel.muteUser(user_id, mute);
+        Ok(())
+    }
+
+    // mutes user_id if msg is spam in a channel with anti-spam protection, ops are exempt
+    pub fn check_channel_spam(&mut self, chan: &str, user_id: i32, username: &str, msg: &str) {
+        let channel = match self.channels.get(chan) {
+            Some(channel) if channel.antispam && !channel.isOp(user_id) && !channel.isMuted(user_id) => channel,
+            _ => return,
+        };
+        let duration = match self.antispam.check(user_id, &channel.name, msg, std::time::Instant::now()) {
+            None => return,
+            Some(duration) => duration,
+        };
+        info!("<{}> was muted in {} for spamming", username, chan);
+        let mute = Mute {
+            username: username.to_string(),
+            issuer: "ChanServ".into(),
+            expires: expires_after(duration),
+            reason: "spamming".into(),
+        };
+        if let Err(e) = self.mute_channel_user(chan, 0, user_id, mute) {
+            error!("Could not mute <{}> in {} for spamming: {}", username, chan, e);
+        }
+    }
+
+    pub fn unmute_channel_user(&mut self, chan: &str, issuer: &str, user_id: i32, username: &str) -> Result<(), String> {
+        let channel = self.channels.get_mut(chan).ok_or(format!("Channel {} does not exist", chan))?;
+        if channel.unmuteUser(issuer, user_id).is_none() {
+            return Err(format!("User <{}> not found in mutelist", username));
+        }
+        if channel.id != 0 {
+            self.users.channels().unmuteUser(channel.id, user_id).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // bans are stored for registered channels, the caller is responsible for permission checks
+    pub fn ban_channel_user(&mut self, chan: &str, issuer_user_id: i32, user_id: i32, ban: Ban) -> Result<(), String> {
+        let channel = self.channels.get_mut(chan).ok_or(format!("Channel {} does not exist", chan))?;
+        let now = chrono::Utc::now().naive_utc();
+        if let Some((_, ban)) = channel.bans().find(|(banned, ban)| **banned == user_id && now < ban.expires) {
+            return Err(format!("User <{}> is already banned by <{}>", ban.username, ban.issuer));
+        }
+        if channel.id != 0 {
+            self.users.channels().unbanUser(channel.id, user_id).map_err(|e| e.to_string())?;
+            self.users.channels().banUser(&ChannelBan {
+                id: None,
+                channel_id: channel.id,
+                issuer_user_id: (issuer_user_id != 0).then_some(issuer_user_id),
+                user_id,
+                ip_address: ban.ip_address.clone(),
+                expires: ban.expires,
+                reason: ban.reason.clone(),
+            }).map_err(|e| e.to_string())?;
+        }
+        let (issuer, username) = (ban.issuer.clone(), ban.username.clone());
+        channel.banUser(user_id, ban);
+        if channel.usernames().contains(&username.as_str()) {
+            self.kick_channel_user(chan, &issuer, &username, "banned")?;
+        }
+        Ok(())
+    }
+
+    pub fn unban_channel_user(&mut self, chan: &str, user_id: i32, username: &str) -> Result<(), String> {
+        let channel = self.channels.get_mut(chan).ok_or(format!("Channel {} does not exist", chan))?;
+        if channel.unbanUser(user_id).is_none() {
+            return Err(format!("User <{}> not found in banlist", username));
+        }
+        if channel.id != 0 {
+            self.users.channels().unbanUser(channel.id, user_id).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    // removes expired channel mutes/bans and idle anti-spam records
+    pub fn channel_mute_ban_timeout(&mut self) {
+        self.antispam.cleanup(std::time::Instant::now());
+        let now = chrono::Utc::now().naive_utc();
+        for channel in self.channels.values_mut() {
+            channel.expire(now);
+        }
+        if let Err(e) = self.users.channels().clean() {
+            error!("Could not remove expired channel mutes/bans: {}", e);
+        }
+    }
+
+    // sends to every logged in client except the listed usernames
+    pub fn broadcast(&self, message: &str, ignore: &[&str]) {
+        self.usernames
+            .iter()
+            .filter(|(username, _)| !ignore.contains(&username.as_str()))
+            .for_each(|(_, session)| session.Send(message));
+    }
+
+    pub fn add_session(&mut self, client: &Client) {
+        self.usernames.insert(client.username.clone(), Session {
+            session_id: client.session_id,
+            user_id: client.user_id,
+            status: client.status,
+            accesslevels: client.accesslevels,
+            tx: client.send_message_queue.clone(),
+            ignored: client.ignored.clone(),
+            channels: client.channels.clone(),
+        });
+    }
+
+    // server side pseudo clients like ChanServ, which have no Client
+    pub fn add_service_session(&mut self, username: &str, session_id: usize, status: ClientStatus, tx: Tx) {
+        self.usernames.insert(username.to_string(), Session {
+            session_id,
+            user_id: 0,
+            status,
+            accesslevels: AccessLevel { role: Role::Moderator, bot: true },
+            tx,
+            ignored: Default::default(),
+            channels: Default::default(),
+        });
+    }
+
+    pub fn remove_session(&mut self, username: &str) -> Option<Session> {
+        self.usernames.remove(username)
+    }
+
+    pub fn clientFromUsername(&self, username: &str) -> Option<&Session> {
+        self.usernames.get(username)
+    }
+
+    // the caller is responsible for permission checks
+    pub fn set_access(&mut self, issuer_user_id: i32, issuer: &str, username: &str, role: Role) -> Result<(), String> {
+        let user = self.users.clientFromUsername(username).ok_or(format!("User <{}> does not exist", username))?;
+        let user_id = user.id.unwrap_or(-1);
+        if AccessLevel::from_access(&user.access, user.bot != 0).role == role {
+            return Err(format!("<{}> already has {} access", username, role));
+        }
+        self.users.set_access(user_id, role, (issuer_user_id != 0).then_some(issuer_user_id))?;
+        info!("<{}> changed the access of <{}> from {} to {}", issuer, username, user.access, role);
+
+        let session = match self.usernames.get_mut(username) {
+            None => return Ok(()),
+            Some(session) => session,
+        };
+        session.accesslevels.role = role;
+        session.Send(&format!("SERVERMSG Your access was changed to {} by <{}>", role, issuer));
+        let mut status = session.status;
+        status.moderator = session.accesslevels.isMod();
+        if status != session.status {
+            self.set_status(username, status);
+        }
+        Ok(())
+    }
+
+    pub fn statuses(&self) -> impl Iterator<Item = (&String, ClientStatus)> {
+        self.usernames.iter().map(|(username, session)| (username, session.status))
+    }
+
+    // stores the status and relays it to everyone
+    pub fn set_status(&mut self, username: &str, status: ClientStatus) {
+        if let Some(session) = self.usernames.get_mut(username) {
+            session.status = status;
+        }
+        self.broadcast(&format!("CLIENTSTATUS {} {}", username, status), &[]);
+    }
+
+    pub fn battles(&self) -> impl Iterator<Item = &Battle> {
+        self.battles.values()
+    }
+
+    pub fn get_battle(&mut self, battle_id: usize) -> Option<&mut Battle> {
+        self.battles.get_mut(&battle_id)
+    }
+
+    pub fn getCurrentBattle(&self, session: usize) -> Option<usize> {
+        self.battles
+            .values()
+            .find(|battle| battle.has_user(session))
+            .map(|battle| battle.battle_id)
+    }
+
+    pub fn next_battle_id(&mut self) -> usize {
+        self.nextbattle += 1;
+        self.nextbattle
+    }
+
+    pub fn open_battle(&mut self, client: &mut Client, battle: Battle) {
+        let battle_id = battle.battle_id;
+        // the host gets it queued in order, before OPENBATTLE
+        self.broadcast(&battle.opened_message(), &[&client.username]);
+        client.Send(&battle.opened_message());
+        self.battles.insert(battle_id, battle);
+
+        client.Send(&format!("OPENBATTLE {}", battle_id));
+        self.join_battle(client, battle_id, None);
+    }
+
+    // client joins battle + notifies others, the caller is responsible for permission checks
+    pub fn join_battle(&mut self, client: &mut Client, battle_id: usize, script_password: Option<&str>) {
+        let battle = match self.battles.get_mut(&battle_id) {
+            None => return,
+            Some(battle) => battle,
+        };
+        client.Send(&format!("JOINBATTLE {} {}", battle_id, battle.hashcode));
+        battle.addUser(client.session_id, &client.username, client.send_message_queue.clone());
+
+        if client.session_id != battle.host {
+            let joined = format!("JOINEDBATTLE {} {}", battle_id, client.username);
+            let host = battle.host;
+            let host_username = battle.host_username.clone();
+            match script_password {
+                Some(password) => {
+                    let joined_password = format!("{} {}", joined, password);
+                    battle.send_to(host, &joined_password);
+                    self.broadcast(&joined, &[&host_username, &client.username]);
+                    client.Send(&joined_password);
+                }
+                None => {
+                    self.broadcast(&joined, &[&client.username]);
+                    client.Send(&joined);
+                }
+            }
+        }
+
+        if let Some(battle) = self.battles.get(&battle_id) {
+            for message in battle.state_messages() {
+                client.Send(&message);
+            }
+        }
+        client.Send("REQUESTBATTLESTATUS");
+    }
+
+    // client leaves a battle + notifies others, the battle is closed if the host leaves
+    pub fn leave_battle(&mut self, session: usize, username: &str) {
+        let battle_id = match self.getCurrentBattle(session) {
+            None => return,
+            Some(battle_id) => battle_id,
+        };
+        let battle = self.battles.get_mut(&battle_id).unwrap();
+        if battle.host == session {
+            self.close_battle(battle_id);
+            return;
+        }
+        battle.removeUser(session);
+        for bot in battle.remove_bots_of(username) {
+            battle.broadcast(&format!("REMOVEBOT {} {}", battle_id, bot));
+        }
+        let info = battle.update_spectators().then(|| battle.info_message());
+        self.broadcast(&format!("LEFTBATTLE {} {}", battle_id, username), &[]);
+        if let Some(info) = info {
+            self.broadcast(&info, &[]);
+        }
+    }
+
+    pub fn close_battle(&mut self, battle_id: usize) {
+        if self.battles.remove(&battle_id).is_some() {
+            self.broadcast(&format!("BATTLECLOSED {}", battle_id), &[]);
+        }
+    }
+
+    pub fn login_string(&self) -> String {
+        format!("TASSERVER {} * {} 0", self.server_version, self.natport)
+    }
 }
 
-async fn process(stream: TcpStream, state: SharedServerState, uid: usize) {
-    let mut lines = Framed::new(stream, LinesCodec::new_with_max_length(TCP_CHAR_LIMIT));
+async fn process(stream: Box<dyn Stream>, addr: SocketAddr, state: SharedServerState, uid: usize, tls: Option<TlsAcceptor>, encrypted: bool) {
+    // longer lines than any access level may send end the connection, shorter ones are checked by the flood limits
+    let max_length = state.lock().unwrap().flood_limits.max_msg_length();
+    let mut lines: Lines = Framed::new(stream, LinesCodec::new_with_max_length(max_length));
     let timeout = sleep(Duration::from_secs(TIMEOUT));
     tokio::pin!(timeout); // Pinning the Sleep with tokio::pin! is necessary when the same Sleep is selected on multiple times.
     let (tx, mut rx) = mpsc::unbounded_channel();
-    let mut client = Client::new(state, tx.clone());
+    let mut client = Client::new(state, tx.clone(), uid, addr);
+    client.tls = encrypted;
+    client.tls_available = tls.is_some();
     let mut lastdata = Instant::now();
 
+    let login_string = client.server_state.lock().unwrap().login_string();
+    if lines.send(&login_string).await.is_err() {
+        return;
+    }
+    info!("[{}] Client connected from {}", uid, addr);
+
     loop {
         tokio::select! {
             // Message to pass from Channel
             Some(msg) = rx.recv() => {
                 //peer.lines.send(&msg).await?;
-                lines.send(&msg).await; 
+                let msg = if client.json { jsonproto::to_json(&msg, None) } else { msg };
+                if lines.send(&msg).await.is_err() {
+                    break;
+                }
             }
             result = lines.next() => match result {
                 // A message was received from the current user, we should
@@ -64,10 +760,16 @@ async fn process(stream: TcpStream, state: SharedServerState, uid: usize) {
                     client.Handle(&msg);
 
                     if !client.message_queue.is_empty() {
-                        // FIXME check if we don't duplicate newline here because we use
-                        // LinesCodec()
-                        lines.send(&client.message_queue).await; 
+                        // LinesCodec appends the final newline itself
+                        let queue = client.message_queue.trim_end_matches('\n');
+                        let sent = lines.send(queue).await;
                         client.message_queue.clear();
+                        if sent.is_err() {
+                            break;
+                        }
+                    }
+                    if client.is_removed() {
+                        break;
                     }
 
                     //self._root.session_manager.commit_guard()
@@ -82,57 +784,142 @@ async fn process(stream: TcpStream, state: SharedServerState, uid: usize) {
                 None => break,
             },
             _ = &mut timeout => {
-                if lastdata < Instant::now() + Duration::from_secs(TIMEOUT) {
+                if lastdata + Duration::from_secs(TIMEOUT) <= Instant::now() {
                     error!("client {} timed out", uid);
                     break
                 }
                 timeout.as_mut().reset(lastdata + Duration::from_secs(TIMEOUT));
             }
         }
+
+        if client.take_starttls() {
+            let acceptor = match &tls {
+                Some(acceptor) => acceptor.clone(),
+                None => break,
+            };
+            lines = match StartTLS(lines, acceptor).await {
+                Ok(lines) => lines,
+                Err(e) => {
+                    error!("[{}] TLS handshake with {} failed: {}", uid, addr, e);
+                    break;
+                }
+            };
+            client.tls = true;
+            info!("[{}] Connection upgraded to TLS", uid);
+            // the greeting is repeated encrypted, as it could have been tampered with
+            let login_string = client.server_state.lock().unwrap().login_string();
+            if lines.send(&login_string).await.is_err() {
+                break;
+            }
+        }
     }
+
+    client.Remove("Connection closed");
+}
+
+// replaces the plain connection by a TLS one, nothing may have been sent after STLS
+#[allow(non_snake_case)]
+async fn StartTLS(lines: Lines, acceptor: TlsAcceptor) -> io::Result<Lines> {
+    let parts = lines.into_parts();
+    if !parts.read_buf.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "data received before the TLS handshake"));
+    }
+    let stream = tokio::time::timeout(Duration::from_secs(TIMEOUT), acceptor.accept(parts.io)).await??;
+    Ok(Framed::new(Box::new(stream), parts.codec))
 }
 
 impl ChatServer {
-    pub async fn start(port: u32) -> io::Result<()> {
+    // with implicit_tls every connection starts with the TLS handshake, otherwise clients may use STLS
+    pub async fn start(port: u32, sstate: SharedServerState, tls: Option<TlsAcceptor>, implicit_tls: bool) -> io::Result<()> {
         let chat = Arc::new(Mutex::new(ChatServer {
-            tls: false,
-            connected_clients: 0,
+            tls,
+            implicit_tls,
         }));
 
         let addr = format!("0.0.0.0:{}", port).parse::<SocketAddr>().unwrap();
-        info!("Awaiting TCP messages on port {}", port);
+        if implicit_tls {
+            info!("Awaiting TLS connections on port {}", port);
+        } else {
+            info!("Awaiting TCP messages on port {}", port);
+        }
         let listener = TcpListener::bind(addr).await?;
 
-        let sstate = SharedServerState::new(StdMutex::new(ServerState::default()));
-
-        let mut uid: usize = 0;
         loop {
             let (stream, addr) = listener.accept().await?;
-
-            {
-                let mut s = chat.lock().await;
+            // the guard is dropped before DENIED is sent, a slow client must not hold the server
+            let (admitted, deny) = {
+                let s = chat.lock().await;
 
                 /* TODO refactor to use methods
                 if !s.connectionMade {
                 } */
 
-                if s.connected_clients >= SOCKET_LIMIT {
-                    error!("too many connections: {} > {}", s.connected_clients, SOCKET_LIMIT);
-                    let mut lines = Framed::new(stream, LinesCodec::new());
-                    lines.send("DENIED too many connections, sorry!").await;
+                (ConnectionSlot::acquire(addr).map(|slot| (slot, s.tls.clone())), !s.implicit_tls)
+            };
+            let (slot, tls) = match admitted {
+                Ok(admitted) => admitted,
+                Err(reason) => {
+                    if deny {
+                        let mut lines = Framed::new(stream, LinesCodec::new());
+                        let _ = lines.send(format!("DENIED {}", reason)).await;
+                    }
                     continue;
                 }
-                s.connected_clients += 1;
-                uid += 1;
-            }
+            };
+            let uid = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
             debug!("accepted connection {}", uid);
 
-            let cloned_state = Arc::clone(&chat);
             let sstate2 = sstate.clone();
             tokio::spawn(async move {
-                process(stream, sstate2, uid,).await;
-                let mut srv = cloned_state.lock().await;
-                srv.connected_clients -= 1;
+                let _slot = slot;
+                let stream: Option<Box<dyn Stream>> = match &tls {
+                    Some(acceptor) if implicit_tls => {
+                        match tokio::time::timeout(Duration::from_secs(TIMEOUT), acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => Some(Box::new(stream)),
+                            Ok(Err(e)) => {
+                                debug!("TLS handshake with {} failed: {}", addr, e);
+                                None
+                            }
+                            Err(_) => None,
+                        }
+                    }
+                    _ => Some(Box::new(stream)),
+                };
+                if let Some(stream) = stream {
+                    process(stream, addr, sstate2, uid, tls, implicit_tls).await;
+                }
+                debug!("closed connection {}", uid);
+            });
+        }
+    }
+
+    // the line protocol over WebSocket for browsers, one command per text frame
+    pub async fn start_websocket(port: u32, sstate: SharedServerState, allowed_origins: Vec<String>) -> io::Result<()> {
+        let allowed_origins = Arc::new(allowed_origins);
+
+        let addr = format!("0.0.0.0:{}", port).parse::<SocketAddr>().unwrap();
+        info!("Awaiting WebSocket connections on port {}", port);
+        let listener = TcpListener::bind(addr).await?;
+
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            // refused before the handshake, there is no way to send DENIED yet
+            let slot = match ConnectionSlot::acquire(addr) {
+                Ok(slot) => slot,
+                Err(_) => continue,
+            };
+            let uid = NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed);
+            debug!("accepted WebSocket connection {}", uid);
+
+            let sstate2 = sstate.clone();
+            let allowed_origins = Arc::clone(&allowed_origins);
+            tokio::spawn(async move {
+                let _slot = slot;
+                match tokio::time::timeout(Duration::from_secs(TIMEOUT), websocket::accept(stream, &allowed_origins)).await {
+                    Ok(Ok(lines)) => process(Box::new(lines), addr, sstate2, uid, None, false).await,
+                    Ok(Err(e)) => debug!("WebSocket handshake with {} failed: {}", addr, e),
+                    Err(_) => {}
+                }
                 debug!("closed connection {}", uid);
             });
         }
@@ -153,7 +940,5 @@ impl ChatServer {
     fn timeoutConnection() {}
 
     fn Remove(self, reason='Quit') {}
-
-    fn StartTLS(self) {}
     */
 }
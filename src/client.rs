@@ -1,151 +1,386 @@
 use log::{debug, error, info};
 use std::time::SystemTime;
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::Mutex;
 use tokio::sync::mpsc;
-use std::time::{Duration, Instant};
+use std::time::Instant;
+use chrono::{NaiveDateTime, Utc};
 
 use crate::protocol::Protocol;
 use crate::chatserver::ServerState;
-use crate::sayhooks::SpamHandler;
-use crate::channel::Channel;
+use crate::clientstatus::ClientStatus;
+use crate::jsonproto;
+use crate::floodlimit::{Flood, FloodControl, FloodLimits};
+use crate::sqlusers::UsersHandler;
 
 pub type SharedServerState = Arc<Mutex<ServerState>>;
 pub type Tx = mpsc::UnboundedSender<String>;
+// user ids ignored by a client, shared with its channels and session so they can filter messages
+pub type IgnoreSet = Arc<Mutex<HashSet<i32>>>;
+// channels joined by a client, shared with its session so kicks and bans can remove them
+pub type ChannelSet = Arc<Mutex<HashSet<String>>>;
 
 //#[derive(Default)]
 pub struct Client {
     lastdata: SystemTime,
     protocol: Protocol,
-    msg_id: String,
+    // sent back with every reply to the current command, #id for text, "id" for JSON
+    pub request_id: Option<u64>,
+    // replies and broadcasts are sent as JSON, after the first JSON command
+    pub json: bool,
     pub message_queue: String,
     pub session_id: usize,
     pub username: String,
-    //channels: HashMap<String, Channel>,
+    pub user_id: i32,
+    pub ip_address: String,
+    pub logged_in: bool,
+    removed: bool,
+    // the connection is encrypted
+    pub tls: bool,
+    // STLS is possible
+    pub tls_available: bool,
+    starttls: bool,
+    pub register_date: NaiveDateTime,
+    pub ingame_time: i32, // minutes
+    pub status: ClientStatus,
+    pub went_ingame: Option<Instant>,
+    pub agent: String,
+    pub channels: ChannelSet,
+    pub ignored: IgnoreSet,
     pub accesslevels : AccessLevel,
     pub server_state : SharedServerState,
-    spam_handler : SpamHandler,
-    send_message_queue : Tx
+    flood_limits : FloodLimits,
+    flood : FloodControl,
+    pub send_message_queue : Tx
 }
 
-    pub const User: u8 = 0x01;
-    pub const Moderator: u8 = 0x02;
-    pub const Admin: u8 = 0x04;
-    pub const Bot: u8 = 0x08;
-    pub const Agreement: u8 = 0x30;
-    pub const Fresh: u8 = 0x20;
+/**Role of an account as stored in the access column of the users table.
 
-#[derive(Default)]
-pub struct AccessLevel(u8);
-    // user, moderator, admin, bot, agreement, fresh
-    //flags : u32
+Roles are ordered, every role may do what the lower ones may. Bot status is
+not a role, it is kept in the bot column and applies to any role.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Role {
+    // not logged in, or an account that has never been activated
+    #[default]
+    Fresh,
+    // registered, the terms of service have to be confirmed on the next login
+    Agreement,
+    User,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 5] = [Role::Fresh, Role::Agreement, Role::User, Role::Moderator, Role::Admin];
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Role::Fresh => "fresh",
+            Role::Agreement => "agreement",
+            Role::User => "user",
+            Role::Moderator => "mod",
+            Role::Admin => "admin",
+        })
+    }
+}
 
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(access: &str) -> Result<Self, Self::Err> {
+        match access.to_lowercase().as_str() {
+            "fresh" => Ok(Role::Fresh),
+            "agreement" => Ok(Role::Agreement),
+            "user" => Ok(Role::User),
+            "mod" | "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("expected one of {}",
+                Role::ALL.iter().map(|role| role.to_string()).collect::<Vec<_>>().join(", "))),
+        }
+    }
+}
+
+// what a session may do: the role of its account and whether it is a bot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AccessLevel {
+    pub role: Role,
+    pub bot: bool,
+}
+
+#[allow(non_snake_case)]
 impl AccessLevel {
+    // access and bot columns of the users table, unknown access strings are treated as fresh
+    pub fn from_access(access: &str, bot: bool) -> Self {
+        match access.parse() {
+            Ok(role) => AccessLevel { role, bot },
+            // old databases stored bots as an access level
+            Err(_) if access == "bot" => AccessLevel { role: Role::User, bot: true },
+            Err(_) => AccessLevel { role: Role::Fresh, bot },
+        }
+    }
+    // value for the access column
+    pub fn access(&self) -> String {
+        self.role.to_string()
+    }
+    pub fn isBot(&self) -> bool {
+        self.bot
+    }
+    pub fn isAgreement(&self) -> bool {
+        self.role == Role::Agreement
+    }
     pub fn isUser(&self) -> bool {
-        (self.0 & User) > 0
+        self.role >= Role::User
     }
     pub fn isAdmin(&self) -> bool {
-        (self.0 & Admin) > 0
+        self.role >= Role::Admin
     }
     pub fn isMod(&self) -> bool {
-        (self.0 & Moderator) > 0 || self.isAdmin()
+        self.role >= Role::Moderator
     }
 }
 
-impl<'a> Client {
-    pub fn new(state: SharedServerState, tx : Tx) -> Self {
+#[allow(non_snake_case)]
+impl Client {
+    pub fn new(state: SharedServerState, tx : Tx, session_id : usize, addr : SocketAddr) -> Self {
+        let flood_limits = state.lock().unwrap().flood_limits;
+        let accesslevels = AccessLevel::default();
         Self {
             lastdata: SystemTime::now(),
             protocol: Default::default(),
-            msg_id: Default::default(),
+            request_id: None,
+            json: false,
             message_queue: Default::default(),
-            session_id: Default::default(),
+            session_id,
             username: Default::default(),
-            //channels: Default::default(),
-            accesslevels: Default::default(),
+            user_id: -1,
+            ip_address: addr.ip().to_string(),
+            logged_in: false,
+            removed: false,
+            tls: false,
+            tls_available: false,
+            starttls: false,
+            register_date: Utc::now().naive_utc(),
+            ingame_time: 0,
+            status: Default::default(),
+            went_ingame: None,
+            agent: Default::default(),
+            channels: Default::default(),
+            ignored: Default::default(),
+            flood: FloodControl::new(flood_limits.for_access(&accesslevels), Instant::now()),
+            flood_limits,
+            accesslevels,
             server_state: state,
-            spam_handler: Default::default(),
             send_message_queue : tx
         }
     }
 
     pub fn is_logged(&self) -> bool {
-        return true; // TODO implement
+        self.logged_in
+    }
+
+    // the connection is closed once the current command is done
+    pub fn is_removed(&self) -> bool {
+        self.removed
+    }
+
+    // the connection is upgraded once the reply to the current command is sent
+    pub fn StartTLS(&mut self) -> Result<(), &'static str> {
+        if self.tls {
+            return Err("Connection is already encrypted");
+        }
+        if !self.tls_available {
+            return Err("TLS is not enabled on this server");
+        }
+        self.starttls = true;
+        Ok(())
     }
 
-    //pub fn get_channel(&self, channel : &str) -> Option<&Channel> {
-    //    self.channels.get(channel)
-    //}
+    pub fn take_starttls(&mut self) -> bool {
+        std::mem::take(&mut self.starttls)
+    }
+
+    pub fn is_ignoring(&self, user_id: i32) -> bool {
+        self.ignored.lock().unwrap().contains(&user_id)
+    }
+
+    // adds the minutes spent in game since went_ingame to the users ingame_time
+    pub fn end_ingame(&mut self, users: &UsersHandler) {
+        let went_ingame = match self.went_ingame.take() {
+            None => return,
+            Some(went_ingame) => went_ingame,
+        };
+        let minutes = (went_ingame.elapsed().as_secs() / 60) as i32;
+        if minutes < 1 {
+            return;
+        }
+        match users.add_ingame_time(self.user_id, minutes) {
+            Ok(total) => self.ingame_time = total,
+            Err(e) => error!("[{}] Could not save ingame time of <{}>: {}", self.session_id, self.username, e),
+        }
+    }
 
     pub fn Handle(&mut self, msg: &str) {
-        // TODO here implement flood limit
+        if self.is_logged() {
+            self.sync_session();
+        }
+        let now = Instant::now();
+        self.flood.set_limit(self.flood_limits.for_access(&self.accesslevels), now);
+        match self.flood.check(msg.len(), now) {
+            Flood::Ok => {}
+            Flood::TooLong => {
+                let start: String = msg.chars().take(16).collect();
+                self.Send(&format!("SERVERMSG message length limit of {} chars was exceeded: command \"{}...\" dropped.", self.flood.limit().msg_length, start));
+                self.ReportFloodBreach(&format!("max message length (cmd=\"{}...\")", start), msg.len());
+                return;
+            }
+            Flood::Warning => {
+                let limit = *self.flood.limit();
+                self.Send(&format!("SERVERMSG No flooding (over {} bytes or {} commands per second), slow down or you will be disconnected",
+                    limit.bytes_per_second, limit.commands_per_second));
+                self.ReportFloodBreach("flood limit warning", msg.len());
+            }
+            Flood::Exceeded => {
+                let limit = *self.flood.limit();
+                self.Send(&format!("SERVERMSG No flooding (over {} bytes or {} commands per second for {} seconds)",
+                    limit.bytes_per_second, limit.commands_per_second, limit.burst_seconds));
+                self.ReportFloodBreach("flood limit", msg.len());
+                self.Remove("Kicked for flooding");
+                return;
+            }
+        }
 
         self.lastdata = SystemTime::now();
-        let msg = self.set_msg_id(msg);
+        let (request_id, msg) = jsonproto::split_request_id(msg);
+        self.request_id = request_id;
 
         self.HandleProtocolCommand(msg);
+        self.request_id = None;
+    }
+
+    fn ReportFloodBreach(&self, kind: &str, bytes: usize) {
+        let limit = self.flood.limit();
+        info!("[{}] {} ({} bytes/s, {} commands/s) breached by <{}> from {}, had {} bytes",
+            self.session_id, kind, limit.bytes_per_second, limit.commands_per_second, self.username, self.ip_address, bytes);
+    }
+
+    // picks up what others changed in the session, e.g. the access by SETACCESS
+    fn sync_session(&mut self) {
+        let clone = self.server_state.clone();
+        let state = clone.lock().unwrap();
+        if let Some(session) = state.usernames.get(&self.username) {
+            self.accesslevels = session.accesslevels;
+            self.status = session.status;
+        }
     }
 
     pub fn HandleProtocolCommand(&mut self, msg: &str) {
         let cmd = msg.trim_start_matches(' ').trim_end_matches('\r');
-        let executor = self.protocol._handle(cmd);
+        let executor = self.protocol._handle(self, cmd);
         match executor {
             Ok(v) => v.execute(self),
-            Err(err) => error!("{}", err),
+            Err(rejected) => rejected.reply(self),
         }
     }
 
     // appends to buffer which will be later handled by server
     pub fn Send(&mut self, msg: &str) {
         debug!("Client sending message {}", msg);
-        if !self.msg_id.is_empty() {
-            self.message_queue.push_str(&self.msg_id);
-            self.msg_id.clear();
+        if self.json {
+            self.message_queue.push_str(&jsonproto::to_json(msg, self.request_id));
+        } else {
+            if let Some(request_id) = self.request_id {
+                self.message_queue.push_str(&format!("#{} ", request_id));
+            }
+            self.message_queue.push_str(msg);
         }
-
-        self.message_queue.push_str(msg);
-        self.message_queue.push_str(msg);
         self.message_queue.push('\n');
     }
 
-    pub fn set_msg_id(&mut self, msg: &'a str) -> &'a str {
-        if !self.msg_id.is_empty() {
-            debug!("self.msg_id is not empty!: {}", self.msg_id); // TODO how does it work? it
-                                                                  // should be cleared in
-                                                                  // self.Send()
+    // remove all references related to the client
+    pub fn Remove(&mut self, reason: &str) {
+        if self.removed {
+            return;
         }
-        self.msg_id.clear();
-
-        if !msg.starts_with('#') {
-            return msg;
+        self.removed = true;
+        let clone = self.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        state.leave_battle(self.session_id, &self.username);
+        let channels: Vec<String> = self.channels.lock().unwrap().iter().cloned().collect();
+        for chan in channels {
+            state.leave_channel(self, &chan, Some("disconnected"));
         }
-        let vec = msg.split(' ').collect::<Vec<&str>>();
-        if vec.len() < 1 {
-            return msg;
+
+        if !self.logged_in {
+            info!("[{}] disconnected from {}: {}", self.session_id, self.ip_address, reason);
+            return;
         }
+        info!("[{}] <{}> disconnected from {}: {}", self.session_id, self.username, self.ip_address, reason);
 
-        let num = vec[0][1..].parse::<usize>();
+        self.end_ingame(&state.users);
+        state.remove_session(&self.username);
+        state.users.end_session(self.user_id);
+        self.logged_in = false;
+    }
 
-        match num {
-            Ok(val) => {
-                self.msg_id = format!("#{} ", val);
-                let pos = msg.find(' ').unwrap();
-                return &msg[pos+1..];
-            }
-            Err(_) => msg,
+    pub fn hook_SAY(&self, state: &mut ServerState, chan: &str, msg: &str) {
+        if !self.accesslevels.isMod() {
+            state.check_channel_spam(chan, self.user_id, &self.username, msg);
         }
     }
 
-    pub fn hook_SAY(&mut self, chan : &mut Channel, msg : &str) {
-        if chan.antispam && !self.accesslevels.isMod() && !chan.isOp(self.session_id) {
-            self.spam_handler.spamrec(&chan.name, msg);
-            if self.spam_handler.spam_enum(msg) {
-                let ban_expiration = Instant::now() + Duration::from_secs(5*60);
-                chan.mute(self.session_id, ban_expiration);
+    // private conversations are checked like a channel named after the receiver,
+    // returns the message to deliver or None if it has to be dropped
+    pub fn hook_SAYPRIVATE(&self, state: &mut ServerState, username: &str, msg: &str) -> Option<String> {
+        if !self.accesslevels.isMod() {
+            let conversation = format!("@{}", username);
+            if state.antispam.check(self.user_id, &conversation, msg, Instant::now()).is_some() {
+                return None;
             }
         }
+        Some(msg.to_string())
     }
 }
 
-// TODO test for set_msg_id
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role_roundtrip() {
+        for role in Role::ALL.iter() {
+            assert_eq!(role.to_string().parse::<Role>(), Ok(*role));
+        }
+        assert_eq!("moderator".parse::<Role>(), Ok(Role::Moderator));
+        assert_eq!("Admin".parse::<Role>(), Ok(Role::Admin));
+        assert!("bot".parse::<Role>().is_err());
+        assert!("".parse::<Role>().is_err());
+    }
+
+    #[test]
+    fn test_access_levels() {
+        let fresh = AccessLevel::default();
+        assert!(!fresh.isUser() && !fresh.isAgreement() && !fresh.isBot());
+
+        // agreement used to share a bit with fresh
+        let agreement = AccessLevel::from_access("agreement", false);
+        assert!(agreement.isAgreement() && !agreement.isUser());
+        assert!(!AccessLevel::from_access("fresh", false).isAgreement());
+
+        let moderator = AccessLevel::from_access("moderator", true);
+        assert!(moderator.isUser() && moderator.isMod() && !moderator.isAdmin() && moderator.isBot());
+        assert_eq!(moderator.access(), "mod");
+
+        let admin = AccessLevel::from_access("admin", false);
+        assert!(admin.isUser() && admin.isMod() && admin.isAdmin() && !admin.isBot());
+
+        assert_eq!(AccessLevel::from_access("bot", false), AccessLevel { role: Role::User, bot: true });
+        assert_eq!(AccessLevel::from_access("nonsense", true), AccessLevel { role: Role::Fresh, bot: true });
+    }
+}
@@ -0,0 +1,122 @@
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+use crate::client::AccessLevel;
+
+// see https://springrts.com/dl/LobbyProtocol/ProtocolDescription.html#MYSTATUS:client
+//   b0       in game
+//   b1       away
+//   b2..b4   rank
+//   b5       access (moderator)
+//   b6       bot
+const INGAME_SHIFT: u32 = 0;
+const AWAY_SHIFT: u32 = 1;
+const RANK_SHIFT: u32 = 2;
+const ACCESS_SHIFT: u32 = 5;
+const BOT_SHIFT: u32 = 6;
+
+const RANK_MASK: u32 = 0x7;
+
+// max. 8 ranks are possible (rank 0 isn't listed)
+// rank, ingame time in hours
+const RANKS: [i32; 7] = [5, 15, 30, 100, 300, 1000, 3000];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStatus {
+    pub ingame: bool,
+    pub away: bool,
+    pub rank: u8,
+    pub moderator: bool,
+    pub bot: bool,
+}
+
+impl ClientStatus {
+    pub fn from_bits(bits: u32) -> Self {
+        Self {
+            ingame: (bits >> INGAME_SHIFT) & 1 == 1,
+            away: (bits >> AWAY_SHIFT) & 1 == 1,
+            rank: ((bits >> RANK_SHIFT) & RANK_MASK) as u8,
+            moderator: (bits >> ACCESS_SHIFT) & 1 == 1,
+            bot: (bits >> BOT_SHIFT) & 1 == 1,
+        }
+    }
+
+    pub fn to_bits(self) -> u32 {
+        (self.ingame as u32) << INGAME_SHIFT
+            | (self.away as u32) << AWAY_SHIFT
+            | (self.rank as u32 & RANK_MASK) << RANK_SHIFT
+            | (self.moderator as u32) << ACCESS_SHIFT
+            | (self.bot as u32) << BOT_SHIFT
+    }
+
+    // only in game and away are taken from the client, the rest is forced by the server
+    pub fn calc(requested: ClientStatus, ingame_time: i32, accesslevels: &AccessLevel) -> Self {
+        Self {
+            ingame: requested.ingame,
+            away: requested.away,
+            rank: rank(ingame_time),
+            moderator: accesslevels.isMod(),
+            bot: accesslevels.isBot(),
+        }
+    }
+}
+
+// ingame_time is in minutes
+pub fn rank(ingame_time: i32) -> u8 {
+    let hours = ingame_time / 60;
+    RANKS.iter().filter(|&&t| hours >= t).count() as u8
+}
+
+impl FromStr for ClientStatus {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::from_bits(s.parse::<i32>()? as u32))
+    }
+}
+
+impl fmt::Display for ClientStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_bits())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clientstatus_roundtrip() {
+        let status = ClientStatus {
+            ingame: true,
+            away: false,
+            rank: 5,
+            moderator: true,
+            bot: true,
+        };
+        assert_eq!(status.to_bits(), 1 | 5 << 2 | 1 << 5 | 1 << 6);
+        assert_eq!(ClientStatus::from_bits(status.to_bits()), status);
+        assert_eq!(status.to_string().parse::<ClientStatus>().unwrap(), status);
+    }
+
+    #[test]
+    fn test_rank() {
+        assert_eq!(rank(0), 0);
+        assert_eq!(rank(5 * 60 - 1), 0);
+        assert_eq!(rank(5 * 60), 1);
+        assert_eq!(rank(100 * 60), 4);
+        assert_eq!(rank(3000 * 60), 7);
+        assert_eq!(rank(i32::MAX), 7);
+    }
+
+    #[test]
+    fn test_calc_forces_server_bits() {
+        let requested = ClientStatus::from_bits(0x7f);
+        let status = ClientStatus::calc(requested, 20 * 60, &AccessLevel::from_access("user", false));
+        assert_eq!(status, ClientStatus { ingame: true, away: true, rank: 2, moderator: false, bot: false });
+
+        let status = ClientStatus::calc(ClientStatus::default(), 0, &AccessLevel::from_access("admin", true));
+        assert_eq!(status, ClientStatus { ingame: false, away: false, rank: 0, moderator: true, bot: true });
+    }
+}
@@ -0,0 +1,258 @@
+use std::time::Instant;
+
+use crate::client::AccessLevel;
+
+// what a session may send, the burst is how many seconds of the rates may be used at once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FloodLimit {
+    pub bytes_per_second: f64,
+    pub commands_per_second: f64,
+    pub burst_seconds: f64,
+    // longer commands are dropped
+    pub msg_length: usize,
+}
+
+// budgets per access level, bots relay whole battles and need far more
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FloodLimits {
+    pub fresh: FloodLimit,
+    pub user: FloodLimit,
+    pub bot: FloodLimit,
+    pub moderator: FloodLimit,
+}
+
+impl Default for FloodLimits {
+    fn default() -> Self {
+        Self {
+            fresh: FloodLimit { bytes_per_second: 1000.0, commands_per_second: 10.0, burst_seconds: 2.0, msg_length: 1000 },
+            user: FloodLimit { bytes_per_second: 2000.0, commands_per_second: 20.0, burst_seconds: 10.0, msg_length: 10000 },
+            bot: FloodLimit { bytes_per_second: 50000.0, commands_per_second: 100.0, burst_seconds: 10.0, msg_length: 10000 },
+            moderator: FloodLimit { bytes_per_second: 2000.0, commands_per_second: 40.0, burst_seconds: 10.0, msg_length: 10000 },
+        }
+    }
+}
+
+impl FloodLimits {
+    // longest line any session may send, longer ones can't be read
+    pub fn max_msg_length(&self) -> usize {
+        [self.fresh, self.user, self.bot, self.moderator].iter().map(|limit| limit.msg_length).max().unwrap_or_default()
+    }
+
+    pub fn for_access(&self, accesslevels: &AccessLevel) -> FloodLimit {
+        if accesslevels.isBot() {
+            self.bot
+        } else if accesslevels.isMod() {
+            self.moderator
+        } else if accesslevels.isUser() {
+            self.user
+        } else {
+            self.fresh
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    rate: f64,
+    capacity: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, capacity: f64, now: Instant) -> Self {
+        Self { tokens: capacity, rate, capacity, last: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    // keeps the share of the budget already used
+    fn resize(&mut self, rate: f64, capacity: f64) {
+        self.tokens = capacity - (self.capacity - self.tokens);
+        self.rate = rate;
+        self.capacity = capacity;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Flood {
+    Ok,
+    // the command is dropped
+    TooLong,
+    // the first time the budget runs out, the command is still handled
+    Warning,
+    // the budget ran out again by another burst, the session has to be closed
+    Exceeded,
+}
+
+/**Token buckets for the bytes and commands a session sends.
+
+A session may overdraw its buckets by one more burst: it is warned when a
+bucket runs empty and disconnected when it empties the overdraft as well.
+The warning is forgotten once both buckets are full again.
+*/
+pub struct FloodControl {
+    limit: FloodLimit,
+    bytes: TokenBucket,
+    commands: TokenBucket,
+    warned: bool,
+}
+
+impl FloodControl {
+    pub fn new(limit: FloodLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            bytes: TokenBucket::new(limit.bytes_per_second, limit.bytes_per_second * limit.burst_seconds, now),
+            commands: TokenBucket::new(limit.commands_per_second, limit.commands_per_second * limit.burst_seconds, now),
+            warned: false,
+        }
+    }
+
+    pub fn limit(&self) -> &FloodLimit {
+        &self.limit
+    }
+
+    // e.g. after LOGIN, when the access level is known
+    pub fn set_limit(&mut self, limit: FloodLimit, now: Instant) {
+        if self.limit == limit {
+            return;
+        }
+        self.bytes.refill(now);
+        self.commands.refill(now);
+        self.bytes.resize(limit.bytes_per_second, limit.bytes_per_second * limit.burst_seconds);
+        self.commands.resize(limit.commands_per_second, limit.commands_per_second * limit.burst_seconds);
+        self.limit = limit;
+    }
+
+    pub fn check(&mut self, msg_length: usize, now: Instant) -> Flood {
+        self.bytes.refill(now);
+        self.commands.refill(now);
+        if self.bytes.is_full() && self.commands.is_full() {
+            self.warned = false;
+        }
+        // the bytes of dropped commands still count, so sending them repeatedly is flooding too
+        self.bytes.tokens -= msg_length as f64;
+        let too_long = msg_length > self.limit.msg_length;
+        if !too_long {
+            self.commands.tokens -= 1.0;
+        }
+
+        let overdrawn = |bucket: &TokenBucket| bucket.tokens < -bucket.capacity;
+        if overdrawn(&self.bytes) || overdrawn(&self.commands) {
+            return Flood::Exceeded;
+        }
+        if too_long {
+            return Flood::TooLong;
+        }
+        if !self.warned && (self.bytes.tokens < 0.0 || self.commands.tokens < 0.0) {
+            self.warned = true;
+            return Flood::Warning;
+        }
+        Flood::Ok
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const LIMIT: FloodLimit = FloodLimit { bytes_per_second: 100.0, commands_per_second: 5.0, burst_seconds: 2.0, msg_length: 50 };
+
+    // sends commands of length bytes every millis until something happens
+    fn flood(control: &mut FloodControl, now: &mut Instant, length: usize, millis: u64, count: usize) -> Vec<(usize, Flood)> {
+        (0..count).filter_map(|i| {
+            *now += Duration::from_millis(millis);
+            match control.check(length, *now) {
+                Flood::Ok => None,
+                flood => Some((i, flood)),
+            }
+        }).collect()
+    }
+
+    #[test]
+    fn test_within_budget() {
+        let mut now = Instant::now();
+        let mut control = FloodControl::new(LIMIT, now);
+        // a full burst at once, then the refill rate
+        assert!(flood(&mut control, &mut now, 10, 0, 10).is_empty());
+        assert!(flood(&mut control, &mut now, 10, 200, 100).is_empty());
+    }
+
+    #[test]
+    fn test_commands_warning_then_exceeded() {
+        let mut now = Instant::now();
+        let mut control = FloodControl::new(LIMIT, now);
+        assert_eq!(flood(&mut control, &mut now, 1, 0, 21), vec![(10, Flood::Warning), (20, Flood::Exceeded)]);
+    }
+
+    #[test]
+    fn test_bytes_warning_then_exceeded() {
+        let mut now = Instant::now();
+        let mut control = FloodControl::new(LIMIT, now);
+        assert_eq!(flood(&mut control, &mut now, 50, 100, 5), vec![(4, Flood::Warning)]);
+        assert_eq!(flood(&mut control, &mut now, 50, 100, 5), vec![(4, Flood::Exceeded)]);
+    }
+
+    #[test]
+    fn test_warning_is_forgotten() {
+        let mut now = Instant::now();
+        let mut control = FloodControl::new(LIMIT, now);
+        assert_eq!(flood(&mut control, &mut now, 1, 0, 11), vec![(10, Flood::Warning)]);
+        now += Duration::from_secs(5);
+        assert_eq!(flood(&mut control, &mut now, 1, 0, 11), vec![(10, Flood::Warning)]);
+    }
+
+    #[test]
+    fn test_too_long() {
+        let now = Instant::now();
+        let mut control = FloodControl::new(LIMIT, now);
+        assert_eq!(control.check(51, now), Flood::TooLong);
+        assert_eq!(control.check(50, now), Flood::Ok);
+    }
+
+    #[test]
+    fn test_too_long_exceeded() {
+        // 200 bytes of budget and 200 more before exceeding it
+        let mut now = Instant::now();
+        let mut control = FloodControl::new(LIMIT, now);
+        assert_eq!(control.check(400, now), Flood::TooLong);
+        assert_eq!(control.check(51, now), Flood::Exceeded);
+        now += Duration::from_secs(10);
+        assert_eq!(control.check(51, now), Flood::TooLong);
+    }
+
+    #[test]
+    fn test_max_msg_length() {
+        let limits = FloodLimits::default();
+        assert_eq!(limits.max_msg_length(), 10000);
+        let limits = FloodLimits { fresh: LIMIT, user: LIMIT, bot: LIMIT, moderator: LIMIT };
+        assert_eq!(limits.max_msg_length(), 50);
+    }
+
+    #[test]
+    fn test_bot_budget() {
+        let limits = FloodLimits::default();
+        let mut bot = AccessLevel::from_access("user", true);
+        assert_eq!(limits.for_access(&bot), limits.bot);
+        assert_eq!(limits.for_access(&AccessLevel::from_access("user", false)), limits.user);
+        assert_eq!(limits.for_access(&AccessLevel::from_access("mod", false)), limits.moderator);
+        assert_eq!(limits.for_access(&AccessLevel::from_access("fresh", false)), limits.fresh);
+
+        // a bot relaying a battle would be kicked as a user
+        let mut now = Instant::now();
+        let mut control = FloodControl::new(limits.fresh, now);
+        control.set_limit(limits.for_access(&bot), now);
+        assert!(flood(&mut control, &mut now, 1000, 50, 200).is_empty());
+        bot = AccessLevel::from_access("user", false);
+        control.set_limit(limits.for_access(&bot), now);
+        assert_eq!(flood(&mut control, &mut now, 1000, 50, 44)[..], [(21, Flood::Warning), (43, Flood::Exceeded)]);
+    }
+}
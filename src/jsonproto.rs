@@ -0,0 +1,114 @@
+use serde_json::{json, Map, Value};
+
+/**A command sent as JSON {"cmd": "SAY", "args": "main hello", "id": 5}.
+
+The args are the text protocol arguments, either as one string or as a list
+joined by spaces. The id is optional and sent back with every reply.
+*/
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub id: Option<u64>,
+    pub line: String,
+}
+
+pub fn parse_request(envelope: &str) -> Result<Request, String> {
+    let value: Value = serde_json::from_str(envelope).map_err(|e| format!("Invalid JSON: {}", e))?;
+    let object = value.as_object().ok_or("Expected a JSON object")?;
+    let cmd = object.get("cmd").and_then(Value::as_str).filter(|cmd| !cmd.is_empty()).ok_or("Missing cmd")?;
+    if cmd.contains(char::is_whitespace) {
+        return Err("Invalid cmd".into());
+    }
+    let args = match object.get("args") {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(args)) => args.clone(),
+        Some(Value::Array(args)) => args.iter()
+            .map(|arg| arg.as_str().ok_or("args must be strings"))
+            .collect::<Result<Vec<_>, _>>()?
+            .join(" "),
+        Some(_) => return Err("args must be a string or a list of strings".into()),
+    };
+    let id = match object.get("id") {
+        None | Some(Value::Null) => None,
+        Some(id) => Some(id.as_u64().ok_or("id must be a positive integer")?),
+    };
+    let line = if args.is_empty() { cmd.to_string() } else { format!("{} {}", cmd, args) };
+    Ok(Request { id, line })
+}
+
+// splits the #id prefix of text commands, e.g. "#5 PING", in JSON mode the replies
+// carry it as "id" like the id of an envelope, which takes precedence over it
+pub fn split_request_id(msg: &str) -> (Option<u64>, &str) {
+    let (prefix, rest) = msg.split_once(' ').unwrap_or((msg, ""));
+    match prefix.strip_prefix('#').and_then(|id| id.parse().ok()) {
+        Some(id) => (Some(id), rest),
+        None => (None, msg),
+    }
+}
+
+/**The JSON form of text protocol lines, one object per line:
+
+    SAID main Alice hi    {"cmd": "SAID", "args": "main Alice hi"}
+    JSON {"SAID": {..}}   {"cmd": "JSON", "data": {"SAID": {..}}}
+
+The request id is added to each as "id".
+*/
+pub fn to_json(msg: &str, id: Option<u64>) -> String {
+    msg.lines().map(|line| {
+        let (cmd, args) = line.split_once(' ').unwrap_or((line, ""));
+        let mut object = Map::new();
+        object.insert("cmd".into(), json!(cmd));
+        match serde_json::from_str::<Value>(args) {
+            Ok(data) if cmd == "JSON" => object.insert("data".into(), data),
+            _ => object.insert("args".into(), json!(args)),
+        };
+        if let Some(id) = id {
+            object.insert("id".into(), json!(id));
+        }
+        Value::Object(object).to_string()
+    }).collect::<Vec<_>>().join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(parse_request(r#"{"cmd": "SAY", "args": "main hello world", "id": 5}"#),
+            Ok(Request { id: Some(5), line: "SAY main hello world".into() }));
+        assert_eq!(parse_request(r#"{"cmd": "SAY", "args": ["main", "hello world"]}"#),
+            Ok(Request { id: None, line: "SAY main hello world".into() }));
+        assert_eq!(parse_request(r#"{"cmd": "PING"}"#), Ok(Request { id: None, line: "PING".into() }));
+        assert_eq!(parse_request(r#"{"cmd": "LOGIN", "args": "user pass 0 * lobby\t0\tsp"}"#).unwrap().line,
+            "LOGIN user pass 0 * lobby\t0\tsp");
+    }
+
+    #[test]
+    fn test_invalid_request() {
+        assert!(parse_request("PING").unwrap_err().starts_with("Invalid JSON"));
+        assert_eq!(parse_request("[1]"), Err("Expected a JSON object".into()));
+        assert_eq!(parse_request(r#"{"args": "x"}"#), Err("Missing cmd".into()));
+        assert_eq!(parse_request(r#"{"cmd": "SAY main"}"#), Err("Invalid cmd".into()));
+        assert_eq!(parse_request(r#"{"cmd": "SAY", "args": [1]}"#), Err("args must be strings".into()));
+        assert_eq!(parse_request(r#"{"cmd": "SAY", "args": 1}"#), Err("args must be a string or a list of strings".into()));
+        assert_eq!(parse_request(r#"{"cmd": "PING", "id": -1}"#), Err("id must be a positive integer".into()));
+    }
+
+    #[test]
+    fn test_split_request_id() {
+        assert_eq!(split_request_id("#5 PING"), (Some(5), "PING"));
+        assert_eq!(split_request_id("#5 SAY main hi"), (Some(5), "SAY main hi"));
+        assert_eq!(split_request_id("#5"), (Some(5), ""));
+        assert_eq!(split_request_id("#x PING"), (None, "#x PING"));
+        assert_eq!(split_request_id("PING #5"), (None, "PING #5"));
+    }
+
+    #[test]
+    fn test_to_json() {
+        assert_eq!(to_json("SAID main Alice hi", None), r#"{"args":"main Alice hi","cmd":"SAID"}"#);
+        assert_eq!(to_json("PONG", Some(3)), r#"{"args":"","cmd":"PONG","id":3}"#);
+        assert_eq!(to_json("JOIN main\nCLIENTS main Alice", Some(1)),
+            "{\"args\":\"main\",\"cmd\":\"JOIN\",\"id\":1}\n{\"args\":\"main Alice\",\"cmd\":\"CLIENTS\",\"id\":1}");
+        assert_eq!(to_json(r#"JSON {"SAID":{"msg":"hi"}}"#, None), r#"{"cmd":"JSON","data":{"SAID":{"msg":"hi"}}}"#);
+    }
+}
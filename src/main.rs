@@ -1,20 +1,38 @@
 use clap::Parser;
-use log::{info,error, set_max_level};
-use signal_hook::{consts::SIGHUP, consts::SIGINT, iterator::Signals};
+use log::{info,error, warn, set_max_level};
+use signal_hook::{consts::{SIGHUP, SIGINT}, iterator::Signals};
+use std::fs;
 use std::process::Command;
+use std::sync::{Arc, Mutex, RwLock};
 
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 extern crate chrono;
 
+mod antispam;
+mod args;
+mod chanserv;
 mod chatserver;
 mod client;
 mod natserver;
 mod protocol;
+// diesel 1.4 derives and table! put their impls inside functions
+#[allow(non_local_definitions)]
 mod sqlusers;
+#[allow(non_local_definitions)]
 mod schema;
 mod channel;
+mod battle;
+mod battlestatus;
+mod clientstatus;
+mod floodlimit;
+mod jsonproto;
 mod sayhooks;
+mod tls;
+mod util;
+mod websocket;
 
 /**Starts uberserver.
 
@@ -71,8 +89,40 @@ struct DataHandler {
     /// redirects connecting clients to the given ip and port
     #[clap(short, long, default_value = "")]
     redirect: String,
+    /// Bytes per second bot accounts may send before they are kicked for flooding
+    #[clap(long, default_value = "50000")]
+    bot_bytes_per_second: f64,
+    /// Commands per second bot accounts may send before they are kicked for flooding
+    #[clap(long, default_value = "100")]
+    bot_commands_per_second: f64,
+    /// Port for connections that start with the TLS handshake, 0 disables it (STLS works on the plain port anyway)
+    #[clap(long, default_value = "0")]
+    tls_port: u32,
+    /// PEM certificate for TLS, a self-signed server.crt is generated when neither it nor the key is given
+    #[clap(long)]
+    tls_cert: Option<String>,
+    /// PEM private key for TLS
+    #[clap(long)]
+    tls_key: Option<String>,
+    /// Port for WebSocket connections from browser lobbies, 0 disables it
+    #[clap(long, default_value = "0")]
+    ws_port: u32,
+    /// Origin of a web page allowed to connect to the WebSocket port, may be repeated, "*" allows any
+    #[clap(long)]
+    ws_origin: Vec<String>,
+    /// Days channel history is kept before the scheduled clean removes it
+    #[clap(long, default_value = "14")]
+    history_days: i64,
+    /// Messages kept per channel, older ones are removed by the scheduled clean
+    #[clap(long, default_value = "1000")]
+    history_messages: i64,
+    #[clap(skip)]
+    server_version: String,
+    #[clap(skip)]
+    agreement: Vec<String>,
 }
 
+#[allow(non_snake_case)]
 impl DataHandler {
     fn parse() -> Self {
         let mut obj: Self = Parser::parse();
@@ -89,12 +139,30 @@ impl DataHandler {
         }
     }
 
-    fn parseFiles(&mut self) {}
+    fn parseFiles(&mut self) {
+        match fs::read_to_string("server_agreement.txt") {
+            Ok(content) => {
+                self.agreement = content.lines().map(|line| line.trim_end_matches('\r').to_string()).collect();
+            }
+            Err(e) => {
+                error!("Could not load user agreement {}", e);
+                self.agreement.push("No user agreement detected. If this server is in production, please report this issue immediately!".into());
+            }
+        }
+    }
+
+    fn sqlite_path(&self) -> &str {
+        match self.sqlurl.strip_prefix("sqlite:///") {
+            Some("") => ":memory:",
+            Some(path) => path,
+            None => panic!("only sqlite databases are supported, got {}", self.sqlurl),
+        }
+    }
+
     fn init(&mut self) {
-        // TODO implement
-        // self.parseFiles()
+        self.parseFiles();
 
-        let ver = get_server_version();
+        self.server_version = get_server_version();
         /*
         let mut signals = Signals::new(&[SIGHUP]).unwrap();
 
@@ -118,7 +186,11 @@ impl DataHandler {
 
 fn get_server_version() -> String {
     let result = match Command::new("git").args(["describe"]).output() {
-        Ok(res) => String::from_utf8(res.stdout).unwrap().trim().to_string(),
+        Ok(res) if res.status.success() => String::from_utf8_lossy(&res.stdout).trim().to_string(),
+        Ok(res) => {
+            error!("Cannot get server version: {}", String::from_utf8_lossy(&res.stderr).trim());
+            "unknown".to_string()
+        }
         Err(err) => {
             error!("Cannot get server version: {}", err);
             "unknown".to_string()
@@ -142,29 +214,109 @@ async fn main() {
     // 2. start NATserver
     let natport = datahandler.natport;
     tokio::spawn(async move {
-        serv.start(natport).await;
+        if let Err(e) = serv.start(natport).await {
+            error!("NAT server failed: {}", e);
+        }
         // TODO graceful shutdown and panic! if error?
     });
 
     // 3.
     datahandler.init();
 
+    let users = sqlusers::UsersHandler::new(sqlusers::establish_connection(datahandler.sqlite_path()));
+    let sayhooks = Arc::new(RwLock::new(sayhooks::SayHooks::new(!datahandler.no_censor)));
+    let mut server_state = chatserver::ServerState::new(
+        users,
+        datahandler.server_version.clone(),
+        natport,
+        datahandler.agreement.clone(),
+        sayhooks.clone(),
+    );
+    server_state.flood_limits.bot.bytes_per_second = datahandler.bot_bytes_per_second;
+    server_state.flood_limits.bot.commands_per_second = datahandler.bot_commands_per_second;
+    let state = Arc::new(Mutex::new(server_state));
+
+    // ChanServ is logged in before any client can connect
+    chanserv::ChanServ::start(state.clone());
+
+    let tls = match tls::acceptor(datahandler.tls_cert.as_deref(), datahandler.tls_key.as_deref()) {
+        Ok(acceptor) => Some(acceptor),
+        Err(e) => {
+            error!("TLS is disabled, could not load the certificate: {}", e);
+            None
+        }
+    };
+
     // 4. start chatfactory TCP connection
     let port = datahandler.port;
+    let chat_state = state.clone();
+    let chat_tls = tls.clone();
     tokio::spawn(async move {
-        chatserver::ChatServer::start(port).await;
+        if let Err(e) = chatserver::ChatServer::start(port, chat_state, chat_tls, false).await {
+            error!("Chat server failed: {}", e);
+        }
     });
+    let tls_port = datahandler.tls_port;
+    if let Some(acceptor) = tls.filter(|_| tls_port != 0) {
+        let tls_state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = chatserver::ChatServer::start(tls_port, tls_state, Some(acceptor), true).await {
+                error!("TLS chat server failed: {}", e);
+            }
+        });
+    }
+    let ws_port = datahandler.ws_port;
+    if ws_port != 0 {
+        if datahandler.ws_origin.is_empty() {
+            warn!("No --ws-origin given, browsers can't connect to the WebSocket port");
+        }
+        let ws_state = state.clone();
+        let ws_origins = datahandler.ws_origin.clone();
+        tokio::spawn(async move {
+            if let Err(e) = chatserver::ChatServer::start_websocket(ws_port, ws_state, ws_origins).await {
+                error!("WebSocket chat server failed: {}", e);
+            }
+        });
+    }
 
     // 5. start scheduled clean 60*60*24
+    let history_age = chrono::Duration::days(datahandler.history_days);
+    let history_messages = datahandler.history_messages;
+    let clean_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60 * 24));
+        loop {
+            interval.tick().await;
+            let state = clean_state.lock().unwrap();
+            match state.users.channels().clean_history(history_age, history_messages) {
+                Ok(deleted) => info!("Scheduled clean removed {} channel messages", deleted),
+                Err(e) => error!("Scheduled clean failed: {}", e),
+            }
+        }
+    });
     // 6. start channel_mute_ban_timeout
+    let timeout_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            timeout_state.lock().unwrap().channel_mute_ban_timeout();
+        }
+    });
     // 7. start decrement_recent_registrations
     // 8. start decrement_recent_renames
 
     // 9. listen to keyboard interrupts
 
     // TODO add signals to tokio::select!
-    let mut signals = Signals::new(&[SIGINT]).unwrap();
-    for sig in signals.forever() {
+    let mut signals = Signals::new(if datahandler.sighup { vec![SIGINT, SIGHUP] } else { vec![SIGINT] }).unwrap();
+    for signal in signals.forever() {
+        if signal == SIGHUP {
+            info!("Reloading word lists and anti-spam config on SIGHUP");
+            sayhooks.write().unwrap().reload();
+            let _ = state.lock().unwrap().antispam.reload();
+            continue;
+        }
         info!("Server killed by keyboard interrupt.");
         break;
     }
@@ -22,8 +22,8 @@ impl NATServer {
             match str::from_utf8(&buf) {
                 Ok(msg) => {
                      // TODO write test for parsing message
-                    let content = NATServer::trim_message(msg);
-                    sock.send(&response).await?;
+                    let _content = NATServer::trim_message(msg);
+                    sock.send(response).await?;
 
                     // TODO callback with msg
                     // callback(msg, addr);
@@ -35,6 +35,6 @@ impl NATServer {
         }
     }
     fn trim_message(data : &str) -> &str {
-        return data.trim_end_matches('\n').trim_end_matches(' ');
+        data.trim_end_matches('\n').trim_end_matches(' ')
     }
 }
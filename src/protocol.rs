@@ -1,13 +1,82 @@
 use log::{debug, error, info};
-use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::net::UdpSocket;
+use std::time::Instant;
+use chrono::Utc;
 
-use crate::client::Client;
+use crate::args::Args;
+use crate::battle::{Battle, BattleBot, BattleUser, StartRect};
+use crate::channel::{expires_after, Ban, Mute};
+use crate::chanserv::parse_duration;
+use crate::battlestatus::{BattleStatus, TeamColor, MAX_ALLY, MAX_HANDICAP, MAX_TEAM};
+use crate::chatserver::ServerState;
+use crate::client::{AccessLevel, Client, Role};
+use crate::clientstatus::ClientStatus;
+use crate::jsonproto;
 
 #[derive(Default)]
 pub struct Protocol {}
 
+/**Commands each access level may use, a session may use those of all its levels.
+
+Sessions that are not logged in are fresh, or agreement while the terms of
+service wait to be confirmed. Logged in sessions are users, and bots,
+moderators or admins as their account says. Commands missing here are unknown.
+*/
+pub const RESTRICTED: &[(&str, &[&str])] = &[
+    ("everyone", &["PING", "LISTCOMMANDS", "JSON"]),
+    ("fresh", &["LOGIN", "REGISTER", "STLS"]),
+    ("agreement", &["CONFIRMAGREEMENT"]),
+    ("user", &[
+        "PORTTEST", "RENAMEACCOUNT",
+        // channels
+        "JOIN", "LEAVE", "CHANNELS", "SAY", "SAYEX", "SAYPRIVATE", "SAYPRIVATEEX", "CHANNELTOPIC",
+        "GETCHANNELMESSAGES", "MUTE", "UNMUTE", "MUTELIST", "CHANNELBAN", "CHANNELUNBAN", "CHANNELBANLIST",
+        "SETCHANNELKEY", "FORCELEAVECHANNEL", "CHANNELUNREGISTER", "SETCHANNELFOUNDER", "CHANNELOP", "CHANNELDEOP",
+        // battles
+        "OPENBATTLE", "JOINBATTLE", "LEAVEBATTLE", "UPDATEBATTLEINFO", "SETSCRIPTTAGS", "REMOVESCRIPTTAGS",
+        "ADDSTARTRECT", "REMOVESTARTRECT", "DISABLEUNITS", "ENABLEUNITS", "ENABLEALLUNITS",
+        "ADDBOT", "UPDATEBOT", "REMOVEBOT", "MYSTATUS", "MYBATTLESTATUS", "FORCETEAMNO", "FORCEALLYNO",
+        "FORCETEAMCOLOR", "FORCESPECTATORMODE", "HANDICAP", "KICKFROMBATTLE",
+        // friends and ignores
+        "FRIENDREQUEST", "ACCEPTFRIENDREQUEST", "DECLINEFRIENDREQUEST", "UNFRIEND", "FRIENDLIST",
+        "FRIENDREQUESTLIST", "IGNORE", "UNIGNORE", "IGNORELIST",
+    ]),
+    ("bot", &[]),
+    ("mod", &["LISTMODS", "SETCHANNELHISTORY", "CHANNELREGISTER", "CHANNELFORWARD", "CHANNELUNFORWARD"]),
+    ("admin", &["RELOAD", "SETACCESS"]),
+];
+
+// why a command was not executed, answered with FAILED
+pub struct Rejected {
+    pub command: String,
+    pub reason: String,
+}
+
+impl Rejected {
+    fn new(command: &str, reason: &str) -> Self {
+        Self { command: command.into(), reason: reason.into() }
+    }
+
+    pub fn reply(&self, client: &mut Client) {
+        let reason = &self.reason;
+        match self.command.as_str() {
+            // clients wait for the answers to these commands
+            "LOGIN" => {
+                let username = client.username.clone();
+                out_DENIED(client, &username, reason);
+            }
+            "REGISTER" => client.Send(&format!("REGISTRATIONDENIED {}", reason)),
+            "OPENBATTLE" => out_OPENBATTLEFAILED(client, reason),
+            "JOINBATTLE" => client.Send(&format!("JOINBATTLEFAILED {}", reason)),
+            command => out_FAILED(client, command, reason),
+        }
+    }
+}
+
 pub trait Command {
+    // parses the arguments with args::Args, the error is sent back as FAILED
     fn get_function_args(&mut self, args: &str) -> Result<(), String>;
     fn execute(&self, client: &mut Client);
 }
@@ -17,21 +86,183 @@ struct PingCommand {
     response: Option<String>,
 }
 
+#[allow(non_snake_case)]
 fn out_FAILED(client : &mut Client, cmd : &str, message : &str) {
     info!("[{}] <{}>: {} {}", client.session_id, client.username, cmd, message);
     client.Send(&format!("FAILED msg={}\tcmd={}", message, cmd));
 }
 
-impl Command for PingCommand {
-    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
-        let arg_num = 1;
+// response to LOGIN
+#[allow(non_snake_case)]
+fn out_DENIED(client : &mut Client, username : &str, reason : &str) {
+    client.Send(&format!("DENIED {}", reason));
+    info!("[{}] Failed to log in user <{}>: {}", client.session_id, username, reason);
+}
 
-        let mut parts = args.splitn(arg_num, ' ').fuse();
+#[allow(non_snake_case)]
+fn out_OPENBATTLEFAILED(client : &mut Client, reason : &str) {
+    client.Send(&format!("OPENBATTLEFAILED {}", reason));
+    info!("[{}] <{}> OPENBATTLEFAILED: {}", client.session_id, client.username, reason);
+}
 
-        self.response = match parts.next() {
-            Some("") | None => None,
-            Some(v) => Some(v.to_string()),
-        };
+#[allow(non_snake_case)]
+fn out_SERVERMSG(client : &mut Client, message : &str) {
+    client.Send(&format!("SERVERMSG {}", message));
+}
+
+// checks if usernames syntax is correct / doesn't contain invalid chars
+#[allow(non_snake_case)]
+fn validUsernameSyntax(username : &str) -> Result<(), String> {
+    if username.is_empty() {
+        return Err("Username is blank.".into());
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '[' || c == ']' || c == '_') {
+        return Err("Only ASCII chars, [], _, 0-9 are allowed in usernames.".into());
+    }
+    if username.len() < 3 {
+        return Err("Username is too short, must be at least 3 characters.".into());
+    }
+    if username.len() > 20 {
+        return Err("Username is too long, max 20 characters.".into());
+    }
+    Ok(())
+}
+
+// username checks shared by REGISTER and RENAMEACCOUNT, user_id is the account being renamed or 0
+#[allow(non_snake_case)]
+fn validUsername(state: &ServerState, username: &str, user_id: i32) -> Result<(), String> {
+    validUsernameSyntax(username)?;
+    let sayhooks = state.sayhooks.read().unwrap();
+    if sayhooks.isNasty(username) {
+        return Err(format!("Invalid username: '{}'", username));
+    }
+    if sayhooks.censor && !sayhooks.nasty_word_censor(username) {
+        return Err("Name failed to pass profanity filter.".into());
+    }
+    match state.users.username_taken(username, user_id) {
+        Ok(false) => Ok(()),
+        Ok(true) => Err("Username is already in use.".into()),
+        Err(e) => {
+            error!("Could not check username <{}>: {}", username, e);
+            Err("Database error, please try again later.".into())
+        }
+    }
+}
+
+// checks if an old-style password BASE64(MD5(PWRD)) is correctly encoded
+#[allow(non_snake_case)]
+fn validPasswordSyntax(password : &str) -> Result<(), String> {
+    if password.is_empty() {
+        return Err("Empty passwords are not allowed.".into());
+    }
+    let md5hash = base64::decode(password)
+        .map_err(|e| format!("Invalid base64-encoding: {}", e))?;
+    if md5hash.len() != 16 {
+        return Err("Invalid MD5-checksum.".into());
+    }
+    Ok(())
+}
+
+// checks if channel syntax is correct / doesn't contain invalid chars
+#[allow(non_snake_case)]
+fn validChannelSyntax(channel : &str) -> Result<(), String> {
+    if !channel.chars().all(|c| c.is_ascii_alphanumeric() || c == '[' || c == ']' || c == '_') {
+        return Err("Only ASCII chars, [], _, 0-9 are allowed in channel names.".into());
+    }
+    if channel.len() > 20 {
+        return Err(format!("Channel name '{}' is too long, max is 20 chars.", channel));
+    }
+    Ok(())
+}
+
+// parses tags to a map, for example user=bla\tcolor=123
+#[allow(non_snake_case)]
+fn parseTags(tagstring : &str) -> HashMap<String, String> {
+    tagstring
+        .split('\t')
+        .filter_map(|tagpair| tagpair.split_once('='))
+        .map(|(tag, value)| (tag.to_string(), value.to_string()))
+        .collect()
+}
+
+// the current battle of the client, if the client is allowed to change its settings
+fn hosted_battle<'a>(state : &'a mut ServerState, client : &Client) -> Option<&'a mut Battle> {
+    let battle_id = state.getCurrentBattle(client.session_id)?;
+    state.get_battle(battle_id).filter(|battle| battle.canChangeSettings(client.session_id))
+}
+
+// the user named by the userName tag of the friend and ignore commands as (username, user id)
+fn friend_user(client : &mut Client, state : &ServerState, tags : &HashMap<String, String>) -> Option<(String, i32)> {
+    let username = match tags.get("userName") {
+        None => {
+            out_SERVERMSG(client, "Missing userName argument.");
+            return None;
+        }
+        Some(username) => username,
+    };
+    match state.users.clientFromUsername(username).and_then(|user| user.id) {
+        None => {
+            out_SERVERMSG(client, "No such user.");
+            None
+        }
+        Some(user_id) => Some((username.clone(), user_id)),
+    }
+}
+
+// login sentence is "agent\tlast_id\tcompat_flags"
+#[allow(non_snake_case)]
+fn validLoginSentence(sentence : &str) -> bool {
+    let parts : Vec<&str> = sentence.split('\t').collect();
+    if parts.len() != 3 {
+        return false;
+    }
+    let (lobby, last_id, flags) = (parts[0], parts[1], parts[2]);
+    if lobby.len() > 64 || last_id.len() > 40 {
+        return false;
+    }
+    let (mac_id, sys_id) = last_id.split_once(' ').unwrap_or((last_id, "0"));
+    if sys_id.len() > 16 || u64::from_str_radix(sys_id, 16).is_err() {
+        return false;
+    }
+    if mac_id.parse::<u32>().is_err() {
+        return false;
+    }
+    flags.chars().all(|c| c.is_ascii_lowercase() || c == ' ')
+}
+
+fn send_login_info(client : &mut Client, state : &mut ServerState) {
+    client.logged_in = true;
+    client.status = ClientStatus::calc(Default::default(), client.ingame_time, &client.accesslevels);
+    match state.users.get_ignored_user_ids(client.user_id) {
+        Ok(ignored) => *client.ignored.lock().unwrap() = ignored.into_iter().collect(),
+        Err(e) => error!("[{}] Could not load ignore list of <{}>: {}", client.session_id, client.username, e),
+    }
+    state.add_session(client);
+
+    info!("[{}] <{}> logged in.", client.session_id, client.username);
+    client.Send(&format!("ACCEPTED {}", client.username));
+
+    for battle in state.battles() {
+        client.Send(&battle.opened_message());
+        client.Send(&battle.info_message());
+        for username in battle.usernames().filter(|username| *username != battle.host_username) {
+            client.Send(&format!("JOINEDBATTLE {} {}", battle.battle_id, username));
+        }
+    }
+
+    // client status is sent last, so battle status is calculated correctly updated at clients
+    for (username, status) in state.statuses().filter(|(_, status)| status.to_bits() != 0) {
+        client.Send(&format!("CLIENTSTATUS {} {}", username, status));
+    }
+    client.Send("LOGININFOEND");
+    if client.status.to_bits() != 0 {
+        state.broadcast(&format!("CLIENTSTATUS {} {}", client.username, client.status), &[&client.username]);
+    }
+}
+
+impl Command for PingCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.response = Some(Args::new(args).opt_rest()).filter(|response| !response.is_empty()).map(Into::into);
         Ok(())
     }
 
@@ -43,9 +274,217 @@ impl Command for PingCommand {
     }
 }
 
+#[derive(Default)]
+struct RegisterCommand {
+    username : String,
+    password : String,
+    email : String,
+}
+
+impl Command for RegisterCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.username = args.word("username")?.into();
+        self.password = args.word("password")?.into();
+        self.email = args.opt_word().unwrap_or("").to_lowercase();
+        Ok(())
+    }
+
+    fn execute(&self, client: &mut Client) {
+        if client.is_logged() {
+            client.Send("REGISTRATIONDENIED You are already logged in.");
+            return;
+        }
+        let clone = client.server_state.clone();
+        let state = clone.lock().unwrap();
+        // well formed-ness tests
+        if let Err(reason) = validUsername(&state, &self.username, 0).and(validPasswordSyntax(&self.password)) {
+            info!("[{}] Registration denied for user <{}>: {}", client.session_id, self.username, reason);
+            client.Send(&format!("REGISTRATIONDENIED {}", reason));
+            return;
+        }
+
+        // test if user would be OK on db side (e.g. duplication)
+        let email = if self.email.is_empty() { None } else { Some(self.email.as_str()) };
+        if let Err(reason) = state.users.check_register_user(&self.username, email) {
+            info!("[{}] Registration failed for user <{}>: {}", client.session_id, self.username, reason);
+            client.Send(&format!("REGISTRATIONDENIED {}", reason));
+            return;
+        }
+
+        if let Err(reason) = state.users.register_user(&self.username, &self.password, &client.ip_address, &self.email) {
+            error!("[{}] Could not save user <{}>: {}", client.session_id, self.username, reason);
+            client.Send("REGISTRATIONDENIED Database error, please try again later.");
+            return;
+        }
+
+        client.Send("REGISTRATIONACCEPTED");
+        info!("[{}] Successfully registered user <{}>.", client.session_id, self.username);
+    }
+}
+
+#[derive(Default)]
+struct RenameAccountCommand {
+    newname : String,
+}
+
+impl Command for RenameAccountCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.newname = Args::new(args).word("newname")?.into();
+        Ok(())
+    }
+
+    // Change the name of the current user, who is disconnected afterwards.
+    fn execute(&self, client: &mut Client) {
+        if !client.is_logged() {
+            out_FAILED(client, "RENAMEACCOUNT", "You must be logged in to rename your account.");
+            return;
+        }
+        if self.newname == client.username {
+            out_FAILED(client, "RENAMEACCOUNT", "You already have that username.");
+            return;
+        }
+
+        {
+            let clone = client.server_state.clone();
+            let state = clone.lock().unwrap();
+            if let Err(reason) = validUsername(&state, &self.newname, client.user_id) {
+                out_FAILED(client, "RENAMEACCOUNT", &reason);
+                return;
+            }
+            if let Err(reason) = state.users.rename_user(client.user_id, &self.newname) {
+                error!("[{}] Could not rename <{}> to <{}>: {}", client.session_id, client.username, self.newname, reason);
+                out_FAILED(client, "RENAMEACCOUNT", &format!("Failed to rename to <{}>: {}", self.newname, reason));
+                return;
+            }
+        }
+
+        info!("[{}] <{}> renamed to <{}>", client.session_id, client.username, self.newname);
+        out_SERVERMSG(client, &format!("Your account has been renamed to <{}>. Reconnect with the new username (you will now be automatically disconnected).", self.newname));
+        client.Remove("renaming");
+    }
+}
+
+#[derive(Default)]
+struct LoginCommand {
+    username : String,
+    password : String,
+    local_ip : String,
+    sentence_args : String,
+}
+
+impl Command for LoginCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.username = args.word("username")?.into();
+        self.password = args.word("password")?.into();
+        let _cpu = args.opt_word(); // deprecated
+        self.local_ip = args.opt_word().unwrap_or("").into();
+        self.sentence_args = args.opt_rest().into();
+        Ok(())
+    }
+
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+
+        if client.is_logged() || state.usernames.contains_key(&self.username) {
+            out_DENIED(client, &self.username, "Already logged in.");
+            return;
+        }
+
+        if let Err(reason) = state.users.check_login_user(&self.username, &self.password) {
+            out_DENIED(client, &self.username, &reason);
+            return;
+        }
+
+        let (agent, last_sys_id, last_mac_id) = if !self.sentence_args.contains('\t') {
+            // backwards compat for Melbot / Statserv
+            (self.sentence_args.as_str(), "0", "0")
+        } else if !validLoginSentence(&self.sentence_args) {
+            out_DENIED(client, &self.username, "Invalid sentence format, please update your lobby client.");
+            return;
+        } else {
+            let mut sentences = Args::new(&self.sentence_args);
+            let agent = sentences.opt_sentence().unwrap_or("");
+            let last_id = sentences.opt_sentence().unwrap_or("");
+            // backwards compat for SL<0.269
+            let (last_mac_id, last_sys_id) = last_id.split_once(' ').unwrap_or((last_id, "0"));
+            (agent, last_sys_id, last_mac_id)
+        };
+
+        // login checks complete
+        let dbuser = match state.users.login_user(&self.username, &client.ip_address, agent, last_sys_id, last_mac_id) {
+            Ok(user) => user,
+            Err(e) => {
+                error!("[{}] Could not log in user <{}>: {}", client.session_id, self.username, e);
+                out_DENIED(client, &self.username, "Database error, please try again later.");
+                return;
+            }
+        };
+
+        // update local client fields from DB User values
+        client.username = dbuser.username;
+        client.user_id = dbuser.id.unwrap_or(-1);
+        client.accesslevels = AccessLevel::from_access(&dbuser.access, dbuser.bot != 0);
+        client.register_date = dbuser.register_date;
+        client.ingame_time = dbuser.ingame_time;
+        client.agent = agent.into();
+
+        if client.accesslevels.isAgreement() {
+            info!("[{}] Sent user <{}> the terms of service on session.", client.session_id, client.username);
+            for line in state.agreement.iter() {
+                client.Send(&format!("AGREEMENT {}", line));
+            }
+            client.Send("AGREEMENTEND");
+            return;
+        }
+
+        send_login_info(client, &mut state);
+    }
+}
+
+#[derive(Default)]
+struct ConfirmAgreementCommand {
+    verification_code : String,
+}
+
+impl Command for ConfirmAgreementCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.verification_code = args.into();
+        Ok(())
+    }
+
+    // Confirm the terms of service as shown with the AGREEMENT commands.
+    // (Users must accept the terms of service to use their account.)
+    fn execute(&self, client: &mut Client) {
+        if client.is_logged() || !client.accesslevels.isAgreement() {
+            return;
+        }
+
+        let time_waited = Utc::now().naive_utc() - client.register_date;
+        if time_waited.num_seconds() < 2 {
+            let username = client.username.clone();
+            out_DENIED(client, &username, "Please take at least a few seconds to read our terms of service!");
+            return;
+        }
+
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if state.usernames.contains_key(&client.username) {
+            let username = client.username.clone();
+            out_DENIED(client, &username, "Already logged in.");
+            return;
+        }
+
+        state.users.confirm_agreement(&client.username);
+        client.accesslevels.role = Role::User;
+        send_login_info(client, &mut state);
+    }
+}
+
 #[derive(Default)]
 struct SayCommand {
-    client : String,
     chan : String,
     msg : String,
     ex_postfix : String,
@@ -53,18 +492,9 @@ struct SayCommand {
 
 impl Command for SayCommand {
     fn get_function_args(&mut self, args: &str) -> Result<(), String> {
-        let arg_num = 3;
-
-        let mut parts = args.splitn(arg_num, ' ').fuse();
-        self.client = parts.next()
-            .ok_or_else(|| "Missing client argument")?
-            .into();
-        self.chan = parts.next()
-            .ok_or_else(|| "Missing chan argument")?
-            .into();
-        self.msg = parts.next()
-            .ok_or_else(|| "Missing msg argument")?
-            .into();
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.msg = args.rest("msg")?.into();
         Ok(())
     }
 
@@ -75,123 +505,2447 @@ impl Command for SayCommand {
 
         let clone = client.server_state.clone(); // FIXME cheating borrow checker
         let mut state = clone.lock().unwrap();
+        let sayhooks = state.sayhooks.clone();
         match state.get_channel(&self.chan) {
             None => {
                 out_FAILED(client, &format!("SAY{}", self.ex_postfix), &format!("Channel {} does not exist", &self.chan));
-                return
+                return;
             }
-            Some(chan) => {
-                if !chan.has_user(client.session_id) {
-                    out_FAILED(client, &format!("SAY{}", self.ex_postfix), &format!("Not present in channel {}", &self.chan));
-                    return;
-                }
-                if chan.isMuted(client.session_id) {
-                    return;
-                }
-
-                client.hook_SAY(chan, &self.msg);
+            Some(chan) if !chan.has_user(client.session_id) => {
+                out_FAILED(client, &format!("SAY{}", self.ex_postfix), &format!("Not present in channel {}", &self.chan));
+                return;
+            }
+            Some(_) => client.hook_SAY(&mut state, &self.chan, &self.msg),
+        }
 
-                if chan.isMuted(client.session_id) {
-                    // TODO send channel.getMuteMessage(client)))
-                    // client.Send('CHANNELMESSAGE %s You are %s.' % (chan, channel.getMuteMessage(client)))
-                    client.Send(&format!("CHANNELMESSAGE {} You are muted.", &self.chan));
+        let history = match state.get_channel(&self.chan) {
+            None => return,
+            Some(chan) => {
+                if chan.isMuted(client.user_id) {
+                    client.Send(&format!("CHANNELMESSAGE {} You are {}.", &self.chan, chan.getMuteMessage(client.user_id)));
                     return
                 }
-                if chan.store_history {
-                    // TODO impl
-                    //self.userdb.add_channel_message(channel.id, client.user_id, None, msg, False)
-                }
-
-                chan.broadcast(&format!("SAID{} {} {} {}", self.ex_postfix, chan.name, client.session_id, self.msg));
+                let msg = match sayhooks.read().unwrap().hook_SAY(chan.censor, &self.msg) {
+                    None => {
+                        client.Send(&format!("CHANNELMESSAGE {} Your message was dropped as it links to a forbidden site.", &self.chan));
+                        return;
+                    }
+                    Some(msg) => msg,
+                };
 
+                chan.broadcast_from(client.user_id, &format!("SAID{} {} {} {}", self.ex_postfix, chan.name, client.username, msg));
 
                 // TODO ignored old compat code
+                (chan.store_history && chan.id != 0).then_some((chan.id, msg))
+            }
+        };
+
+        if let Some((channel_id, msg)) = history {
+            let ex_msg = !self.ex_postfix.is_empty();
+            if let Err(e) = state.users.channels().add_channel_message(channel_id, client.user_id, &msg, ex_msg) {
+                error!("[{}] Could not store message in channel {}: {}", client.session_id, self.chan, e);
             }
         }
     }
 }
 
 #[derive(Default)]
-struct PortTestCommand {
-    host : String,
-    port : usize,
+struct ChannelTopicCommand {
+    chan : String,
+    topic : String,
 }
 
-impl Command for PortTestCommand  {
+impl Command for ChannelTopicCommand {
     fn get_function_args(&mut self, args: &str) -> Result<(), String> {
-        let arg_num = 2;
-
-        let mut parts = args.splitn(arg_num, ' ').fuse();
-
-        self.host = parts.next()
-            .ok_or_else(|| "Missing host argument")?
-            .into();
-        self.port = parts.next()
-            .ok_or_else(|| "Missing port argument")?
-            .parse()
-            .map_err(|_| "Can't parse port argument")?; 
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.topic = args.opt_rest().into();
         Ok(())
     }
 
+    // Set the topic of a channel, an empty topic or '*' removes it. [operator]
     fn execute(&self, client: &mut Client) {
-        debug!("Executing PortTestCommand {}:{}", self.host, self.port);
-        let local = "0.0.0.0:0".parse::<SocketAddr>().unwrap();
-        match UdpSocket::bind(&local) {
-            Ok(socket) => {
-                let target = format!("{}:{}", self.host, self.port);
-                socket.send_to(b"Port testing...", target);
-            },
-            Err(_) => {
-                error!("Could not open udp socket on {}:{} in PortTestCommand", self.host, self.port);
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        match state.get_channel(&self.chan) {
+            None => {
+                out_FAILED(client, "CHANNELTOPIC", &format!("Channel {} does not exist", self.chan));
+                return;
             }
+            Some(chan) if !chan.isOp(client.user_id) && !client.accesslevels.isMod() => {
+                out_FAILED(client, "CHANNELTOPIC", "You are not allowed to change the topic of this channel");
+                return;
+            }
+            Some(_) => {}
+        }
+        if let Err(e) = state.set_channel_topic(&self.chan, client.user_id, &client.username, &self.topic) {
+            error!("[{}] Could not store topic of channel {}: {}", client.session_id, self.chan, e);
         }
     }
 }
 
-impl Protocol {
-    fn get_function(command: &str) -> Option<Box<dyn Command>> {
-        match command {
-            "PING" => Some(Box::new(PingCommand::default())),
-            "PORTTEST" => Some(Box::new(PortTestCommand::default())),
-            "SAY" => Some(Box::new(SayCommand::default())),
-            "SAYEX" =>  {
-                let mut cmd = SayCommand::default();
-                cmd.ex_postfix = "EX".to_string();
-                Some(Box::new(cmd))
-            },
-            _ => None
+// ops, the founder and mods may mute or ban users of a channel
+fn channel_moderation_allowed(client : &Client, state : &mut ServerState, chan : &str) -> Result<(), String> {
+    match state.get_channel(chan) {
+        None => Err(format!("Channel {} does not exist", chan)),
+        Some(channel) if !channel.isOp(client.user_id) && !client.accesslevels.isMod() => {
+            Err("You do not have permission to execute this command".into())
         }
+        Some(_) => Ok(()),
     }
+}
 
-    pub fn _handle(&self, msg: &str) -> Result<Box<dyn Command>, String> {
-        let mut fun = None;
+#[derive(Default)]
+struct MuteCommand {
+    chan : String,
+    username : String,
+    duration : String,
+    reason : String,
+}
 
-        // TODO implement restricted list handling
-        //if command not in self.restricted_list:
+impl Command for MuteCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.username = args.word("username")?.into();
+        self.duration = args.opt_word().unwrap_or("0").into();
+        self.reason = Some(args.opt_rest()).filter(|reason| !reason.is_empty()).unwrap_or("no reason given").into();
+        Ok(())
+    }
 
-        let command = {
-            if let Some((command, args)) = msg.split_once(' ') {
-                let command = &command.to_lowercase();
-                // TODO add error checking for max cargs size
-                fun = Protocol::get_function(command);
-                if let Some(ref mut v) = fun {
-                    v.get_function_args(args)?;
-                }
-                command.to_string()
-            } else {
-                let command = &msg.to_lowercase();
-                fun = Protocol::get_function(&command);
-                command.to_string()
+    // Mute a user in a channel for duration minutes (or 10m, 2h, 3d, 1w), 0 mutes forever. [operator]
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if let Err(reason) = channel_moderation_allowed(client, &mut state, &self.chan) {
+            out_FAILED(client, "MUTE", &reason);
+            return;
+        }
+        let duration = match parse_duration(&self.duration) {
+            Some(duration) => duration,
+            None => {
+                out_FAILED(client, "MUTE", &format!("Invalid duration {}, please enter a number of minutes or specify a time unit e.g. '10m', '2h', or '3d'", self.duration));
+                return;
+            }
+        };
+        let target = match state.users.clientFromUsername(&self.username) {
+            None => {
+                out_FAILED(client, "MUTE", &format!("User <{}> not found", self.username));
+                return;
             }
+            Some(target) => target,
+        };
+        let user_id = target.id.unwrap_or_default();
+        if state.get_channel(&self.chan).is_some_and(|channel| channel.isOp(user_id)) {
+            out_FAILED(client, "MUTE", &format!("Cannot mute <{}>, user has operator status", target.username));
+            return;
+        }
+
+        let mute = Mute {
+            username: target.username,
+            issuer: client.username.clone(),
+            expires: expires_after(duration),
+            reason: self.reason.clone(),
         };
+        if let Err(reason) = state.mute_channel_user(&self.chan, client.user_id, user_id, mute) {
+            out_FAILED(client, "MUTE", &reason);
+        }
+    }
+}
+
+#[derive(Default)]
+struct UnmuteCommand {
+    chan : String,
+    username : String,
+}
+
+impl Command for UnmuteCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.username = args.word("username")?.into();
+        Ok(())
+    }
+
+    // [operator]
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if let Err(reason) = channel_moderation_allowed(client, &mut state, &self.chan) {
+            out_FAILED(client, "UNMUTE", &reason);
+            return;
+        }
+        let user_id = state.users.clientFromUsername(&self.username).and_then(|target| target.id).unwrap_or_default();
+        if let Err(reason) = state.unmute_channel_user(&self.chan, &client.username, user_id, &self.username) {
+            out_FAILED(client, "UNMUTE", &reason);
+        }
+    }
+}
+
+#[derive(Default)]
+struct MuteListCommand {
+    chan : String,
+}
+
+impl Command for MuteListCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.chan = Args::new(args).word("chan")?.into();
+        Ok(())
+    }
+
+    // [operator]
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if let Err(reason) = channel_moderation_allowed(client, &mut state, &self.chan) {
+            out_FAILED(client, "MUTELIST", &reason);
+            return;
+        }
+        let channel = state.get_channel(&self.chan).unwrap();
+        client.Send(&format!("MUTELISTBEGIN {}", self.chan));
+        for (_, mute) in channel.mutes() {
+            client.Send(&format!("MUTELIST {} :: {} :: ends {} ({})",
+                mute.username, mute.reason, mute.expires.format("%Y-%m-%d %H:%M:%S"), mute.issuer));
+        }
+        client.Send("MUTELISTEND");
+    }
+}
+
+#[derive(Default)]
+struct ChannelBanCommand {
+    chan : String,
+    username : String,
+    duration : String,
+    ip_address : Option<String>,
+    reason : String,
+}
+
+impl Command for ChannelBanCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.username = args.word("username")?.into();
+        self.duration = args.opt_word().unwrap_or("0").into();
+        // the address is optional, anything else starts the reason
+        let rest = args.opt_rest();
+        let (ip_address, reason) = match rest.split_once(' ').unwrap_or((rest, "")) {
+            ("*", reason) => (None, reason),
+            (ip, reason) if ip.parse::<IpAddr>().is_ok() => (Some(ip.to_string()), reason),
+            _ => (None, rest),
+        };
+        self.ip_address = ip_address;
+        self.reason = Some(reason).filter(|reason| !reason.is_empty()).unwrap_or("no reason given").into();
+        Ok(())
+    }
+
+    // Ban a user from a channel like MUTE, also banning ip_address or else the last address the user logged in from. [operator]
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if let Err(reason) = channel_moderation_allowed(client, &mut state, &self.chan) {
+            out_FAILED(client, "CHANNELBAN", &reason);
+            return;
+        }
+        let duration = match parse_duration(&self.duration) {
+            Some(duration) => duration,
+            None => {
+                out_FAILED(client, "CHANNELBAN", &format!("Invalid duration {}, please enter a number of minutes or specify a time unit e.g. '10m', '2h', or '3d'", self.duration));
+                return;
+            }
+        };
+        let target = match state.users.clientFromUsername(&self.username) {
+            None => {
+                out_FAILED(client, "CHANNELBAN", &format!("User <{}> not found", self.username));
+                return;
+            }
+            Some(target) => target,
+        };
+        let user_id = target.id.unwrap_or_default();
+        if state.get_channel(&self.chan).is_some_and(|channel| channel.isOp(user_id)) {
+            out_FAILED(client, "CHANNELBAN", &format!("Cannot ban <{}>, user has operator status", target.username));
+            return;
+        }
+
+        let ban = Ban {
+            username: target.username,
+            issuer: client.username.clone(),
+            ip_address: self.ip_address.clone().unwrap_or(target.last_ip),
+            expires: expires_after(duration),
+            reason: self.reason.clone(),
+        };
+        if let Err(reason) = state.ban_channel_user(&self.chan, client.user_id, user_id, ban) {
+            out_FAILED(client, "CHANNELBAN", &reason);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChannelUnbanCommand {
+    chan : String,
+    username : String,
+}
+
+impl Command for ChannelUnbanCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.username = args.word("username")?.into();
+        Ok(())
+    }
+
+    // [operator]
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if let Err(reason) = channel_moderation_allowed(client, &mut state, &self.chan) {
+            out_FAILED(client, "CHANNELUNBAN", &reason);
+            return;
+        }
+        let user_id = state.users.clientFromUsername(&self.username).and_then(|target| target.id).unwrap_or_default();
+        if let Err(reason) = state.unban_channel_user(&self.chan, user_id, &self.username) {
+            out_FAILED(client, "CHANNELUNBAN", &reason);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChannelBanListCommand {
+    chan : String,
+}
+
+impl Command for ChannelBanListCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.chan = Args::new(args).word("chan")?.into();
+        Ok(())
+    }
+
+    // [operator]
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if let Err(reason) = channel_moderation_allowed(client, &mut state, &self.chan) {
+            out_FAILED(client, "CHANNELBANLIST", &reason);
+            return;
+        }
+        let channel = state.get_channel(&self.chan).unwrap();
+        client.Send(&format!("CHANNELBANLISTBEGIN {}", self.chan));
+        for (_, ban) in channel.bans() {
+            client.Send(&format!("CHANNELBANLIST {} :: {} :: {} :: ends {} ({})",
+                ban.username, ban.ip_address, ban.reason, ban.expires.format("%Y-%m-%d %H:%M:%S"), ban.issuer));
+        }
+        client.Send("CHANNELBANLISTEND");
+    }
+}
+
+#[derive(Default)]
+struct SetChannelKeyCommand {
+    chan : String,
+    key : String,
+}
+
+impl Command for SetChannelKeyCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.key = args.opt_word().unwrap_or("*").into();
+        Ok(())
+    }
+
+    // Set the password of a channel, '*' removes it. [founder]
+    fn execute(&self, client: &mut Client) {
+        if self.key.contains(' ') {
+            out_FAILED(client, "SETCHANNELKEY", "The channel password must not contain spaces");
+            return;
+        }
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let allowed = match state.get_channel(&self.chan) {
+            None => {
+                out_FAILED(client, "SETCHANNELKEY", &format!("Channel {} does not exist", self.chan));
+                return;
+            }
+            Some(channel) => channel.isFounder(client.user_id) || client.accesslevels.isMod(),
+        };
+        if !allowed {
+            out_FAILED(client, "SETCHANNELKEY", "You do not have permission to change the channel password");
+            return;
+        }
+        if let Err(reason) = state.set_channel_key(&self.chan, &client.username, &self.key) {
+            out_FAILED(client, "SETCHANNELKEY", &reason);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChannelForwardCommand {
+    chan : String,
+    chan_to : String,
+    remove : bool,
+}
+
+impl Command for ChannelForwardCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.chan_to = args.word("chan_to")?.into();
+        Ok(())
+    }
+
+    // Add or remove a forward, users joining chan also join chan_to. [moderator]
+    fn execute(&self, client: &mut Client) {
+        let cmd = if self.remove { "CHANNELUNFORWARD" } else { "CHANNELFORWARD" };
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let result = if self.remove {
+            state.remove_channel_forward(&self.chan, &client.username, &self.chan_to)
+        } else {
+            state.add_channel_forward(&self.chan, &client.username, &self.chan_to)
+        };
+        if let Err(reason) = result {
+            out_FAILED(client, cmd, &reason);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ForceLeaveChannelCommand {
+    chan : String,
+    username : String,
+    reason : String,
+}
+
+impl Command for ForceLeaveChannelCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.username = args.word("username")?.into();
+        self.reason = args.opt_rest().into();
+        Ok(())
+    }
+
+    // Kick a user from a channel. [operator]
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if let Err(reason) = channel_moderation_allowed(client, &mut state, &self.chan) {
+            out_FAILED(client, "FORCELEAVECHANNEL", &reason);
+            return;
+        }
+        let user_id = state.clientFromUsername(&self.username).map(|session| session.user_id);
+        if user_id.is_some_and(|user_id| state.get_channel(&self.chan).unwrap().isOp(user_id)) {
+            out_FAILED(client, "FORCELEAVECHANNEL", &format!("Cannot kick <{}>, user has operator status", self.username));
+            return;
+        }
+        if let Err(reason) = state.kick_channel_user(&self.chan, &client.username, &self.username, &self.reason) {
+            out_FAILED(client, "FORCELEAVECHANNEL", &reason);
+        }
+    }
+}
+
+// founders and moderators manage registered channels
+fn channel_founder_access(state: &mut ServerState, client: &Client, chan: &str) -> bool {
+    client.accesslevels.isMod() || state.get_channel(chan).is_some_and(|channel| channel.isFounder(client.user_id))
+}
+
+#[derive(Default)]
+struct ChannelRegisterCommand {
+    chan : String,
+    founder : Option<String>,
+}
+
+impl Command for ChannelRegisterCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.founder = args.opt_word().map(Into::into);
+        Ok(())
+    }
+
+    // Register an existing channel to a founder, the issuer by default. [moderator]
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let target = self.founder.clone().unwrap_or_else(|| client.username.clone());
+        let founder = match state.users.clientFromUsername(&target) {
+            None => {
+                out_FAILED(client, "CHANNELREGISTER", &format!("User <{}> not found", target));
+                return;
+            }
+            Some(founder) => founder,
+        };
+        if let Err(reason) = state.register_channel(&self.chan, &client.username, founder.id.unwrap_or_default(), &founder.username) {
+            out_FAILED(client, "CHANNELREGISTER", &reason);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChannelUnregisterCommand {
+    chan : String,
+}
+
+impl Command for ChannelUnregisterCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        Ok(())
+    }
+
+    // Unregister a channel, its operators and history are removed. [founder]
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if !channel_founder_access(&mut state, client, &self.chan) {
+            out_FAILED(client, "CHANNELUNREGISTER", "You are not allowed to unregister this channel");
+            return;
+        }
+        if let Err(reason) = state.unregister_channel(&self.chan, &client.username) {
+            out_FAILED(client, "CHANNELUNREGISTER", &reason);
+        }
+    }
+}
+
+#[derive(Default)]
+struct SetChannelFounderCommand {
+    chan : String,
+    username : String,
+}
+
+impl Command for SetChannelFounderCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.username = args.word("username")?.into();
+        Ok(())
+    }
+
+    // Hand a registered channel over to another founder. [founder]
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if !channel_founder_access(&mut state, client, &self.chan) {
+            out_FAILED(client, "SETCHANNELFOUNDER", "You are not allowed to change the founder of this channel");
+            return;
+        }
+        let founder = match state.users.clientFromUsername(&self.username) {
+            None => {
+                out_FAILED(client, "SETCHANNELFOUNDER", &format!("User <{}> not found", self.username));
+                return;
+            }
+            Some(founder) => founder,
+        };
+        if let Err(reason) = state.set_channel_founder(&self.chan, &client.username, founder.id.unwrap_or_default(), &founder.username) {
+            out_FAILED(client, "SETCHANNELFOUNDER", &reason);
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChannelOpCommand {
+    chan : String,
+    username : String,
+    deop : bool,
+}
+
+impl Command for ChannelOpCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.username = args.word("username")?.into();
+        Ok(())
+    }
+
+    // Add a user to or remove a user from the operators of a registered channel. [founder]
+    fn execute(&self, client: &mut Client) {
+        let cmd = if self.deop { "CHANNELDEOP" } else { "CHANNELOP" };
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if !channel_founder_access(&mut state, client, &self.chan) {
+            out_FAILED(client, cmd, "You are not allowed to change the operators of this channel");
+            return;
+        }
+        let target = match state.users.clientFromUsername(&self.username) {
+            None => {
+                out_FAILED(client, cmd, &format!("User <{}> not found", self.username));
+                return;
+            }
+            Some(target) => target,
+        };
+        let user_id = target.id.unwrap_or_default();
+        let result = if self.deop {
+            state.deop_channel_user(&self.chan, &client.username, user_id, &target.username)
+        } else {
+            state.op_channel_user(&self.chan, &client.username, user_id, &target.username)
+        };
+        if let Err(reason) = result {
+            out_FAILED(client, cmd, &reason);
+        }
+    }
+}
+
+// max. number of messages replayed by GETCHANNELMESSAGES
+const CHANNEL_HISTORY_REPLAY_LIMIT: i64 = 250;
+
+#[derive(Default)]
+struct GetChannelMessagesCommand {
+    chan : String,
+    last_msg_id : i32,
+}
+
+impl Command for GetChannelMessagesCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.last_msg_id = args.opt_parse("last_msg_id")?.unwrap_or(0);
+        Ok(())
+    }
+
+    // Get historical messages of a joined channel newer than last_msg_id, 0 or none returns the most recent ones.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let channel_id = match state.get_channel(&self.chan) {
+            None => return,
+            Some(chan) if chan.id == 0 => return, // unregistered channels have no history
+            Some(chan) if !chan.has_user(client.session_id) => {
+                out_FAILED(client, "GETCHANNELMESSAGES", "Can't get channel messages when not joined");
+                return;
+            }
+            Some(chan) => chan.id,
+        };
+        let messages = match state.users.channels().get_channel_messages(channel_id, self.last_msg_id, CHANNEL_HISTORY_REPLAY_LIMIT) {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!("[{}] Could not load messages of channel {}: {}", client.session_id, self.chan, e);
+                return;
+            }
+        };
+        // replayed like they were said, so clients parse them as usual,
+        // each one after a CHANNELHISTORY line with its id and unix time
+        for (id, time, username, msg, ex_msg) in messages {
+            let postfix = if ex_msg { "EX" } else { "" };
+            client.Send(&format!("CHANNELHISTORY {} {} {}", self.chan, id, time.and_utc().timestamp()));
+            client.Send(&format!("SAID{} {} {} {}", postfix, self.chan, username, msg));
+        }
+    }
+}
+
+#[derive(Default)]
+struct SetChannelHistoryCommand {
+    chan : String,
+    enable : String,
+}
+
+impl Command for SetChannelHistoryCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.into();
+        self.enable = args.word("enable")?.into();
+        Ok(())
+    }
+
+    // Moderators turn the history of a channel on (1) or off (0), the channel is stored in the database for it.
+    fn execute(&self, client: &mut Client) {
+        let enable = match self.enable.as_str() {
+            "1" => true,
+            "0" => false,
+            _ => {
+                out_FAILED(client, "SETCHANNELHISTORY", "Invalid value, expected 1 or 0");
+                return;
+            }
+        };
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if let Err(reason) = state.set_channel_history(&self.chan, &client.username, enable) {
+            out_FAILED(client, "SETCHANNELHISTORY", &reason);
+        }
+    }
+}
+
+#[derive(Default)]
+struct SayPrivateCommand {
+    user : String,
+    msg : String,
+    ex_postfix : String,
+}
+
+impl Command for SayPrivateCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.user = args.word("user")?.into();
+        self.msg = args.rest("msg")?.into();
+        Ok(())
+    }
+
+    // Send a message in private to another user.
+    fn execute(&self, client: &mut Client) {
+        if self.msg.trim().is_empty() {
+            return;
+        }
+
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if state.clientFromUsername(&self.user).is_none() {
+            info!("[{}] <{}>: user to pm is not online: {}", client.session_id, client.username, self.user);
+            return;
+        }
+        let msg = match client.hook_SAYPRIVATE(&mut state, &self.user, &self.msg) {
+            None => {
+                out_SERVERMSG(client, &format!("You are sending messages too fast, your message to {} was dropped.", self.user));
+                return;
+            }
+            Some(msg) => msg,
+        };
+        let msg = match state.sayhooks.read().unwrap().hook_SAYPRIVATE(&msg) {
+            None => {
+                out_SERVERMSG(client, &format!("Your message to {} was dropped as it links to a forbidden site.", self.user));
+                return;
+            }
+            Some(msg) => msg,
+        };
+
+        client.Send(&format!("SAYPRIVATE{} {} {}", self.ex_postfix, self.user, msg));
+        if let Some(receiver) = state.clientFromUsername(&self.user).filter(|receiver| !receiver.is_ignoring(client.user_id)) {
+            receiver.Send(&format!("SAIDPRIVATE{} {} {}", self.ex_postfix, client.username, msg));
+        }
+    }
+}
+
+#[derive(Default)]
+struct FriendRequestCommand {
+    tags : HashMap<String, String>,
+}
+
+impl Command for FriendRequestCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.tags = parseTags(args);
+        Ok(())
+    }
+
+    // Ask another user to become friends, userName=name and optional msg=text tags.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let state = clone.lock().unwrap();
+        let (username, user_id) = match friend_user(client, &state, &self.tags) {
+            None => return,
+            Some(user) => user,
+        };
+        if username == client.username {
+            out_SERVERMSG(client, "Can't send friend request to self. Sorry :(");
+            return;
+        }
+        if state.users.are_friends(client.user_id, user_id) {
+            out_SERVERMSG(client, "Already friends with user.");
+            return;
+        }
+        // don't inform the user about ignores or an existing request, so they can't tell if they are being ignored
+        if state.users.is_ignored(user_id, client.user_id) || state.users.has_friend_request(client.user_id, user_id) {
+            return;
+        }
+
+        let msg = self.tags.get("msg").map(|msg| msg.as_str());
+        if let Err(e) = state.users.add_friend_request(client.user_id, user_id, msg) {
+            error!("[{}] Could not add friend request to <{}>: {}", client.session_id, username, e);
+            return;
+        }
+        if let Some(receiver) = state.clientFromUsername(&username) {
+            match msg {
+                Some(msg) => receiver.Send(&format!("FRIENDREQUEST userName={}\tmsg={}", client.username, msg)),
+                None => receiver.Send(&format!("FRIENDREQUEST userName={}", client.username)),
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct AcceptFriendRequestCommand {
+    tags : HashMap<String, String>,
+}
+
+impl Command for AcceptFriendRequestCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.tags = parseTags(args);
+        Ok(())
+    }
+
+    // Accept a friend request sent by userName.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let state = clone.lock().unwrap();
+        let (username, user_id) = match friend_user(client, &state, &self.tags) {
+            None => return,
+            Some(user) => user,
+        };
+        if !state.users.has_friend_request(user_id, client.user_id) {
+            out_SERVERMSG(client, "No such friend request.");
+            return;
+        }
+        if state.users.are_friends(client.user_id, user_id) {
+            let _ = state.users.remove_friend_request(user_id, client.user_id);
+            out_SERVERMSG(client, "Already friends with user.");
+            return;
+        }
+
+        let result = state.users.friend_users(client.user_id, user_id)
+            .and_then(|_| state.users.remove_friend_request(user_id, client.user_id));
+        if let Err(e) = result {
+            error!("[{}] Could not accept friend request from <{}>: {}", client.session_id, username, e);
+            return;
+        }
+        client.Send(&format!("FRIEND userName={}", username));
+        if let Some(friend) = state.clientFromUsername(&username) {
+            friend.Send(&format!("FRIEND userName={}", client.username));
+        }
+    }
+}
+
+#[derive(Default)]
+struct DeclineFriendRequestCommand {
+    tags : HashMap<String, String>,
+}
+
+impl Command for DeclineFriendRequestCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.tags = parseTags(args);
+        Ok(())
+    }
+
+    // Decline a friend request sent by userName, the sender isn't notified.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let state = clone.lock().unwrap();
+        let (username, user_id) = match friend_user(client, &state, &self.tags) {
+            None => return,
+            Some(user) => user,
+        };
+        if !state.users.has_friend_request(user_id, client.user_id) {
+            out_SERVERMSG(client, "No such friend request.");
+            return;
+        }
+        if state.users.are_friends(client.user_id, user_id) {
+            let _ = state.users.remove_friend_request(user_id, client.user_id);
+            out_SERVERMSG(client, "Already friends with user.");
+            return;
+        }
+        if let Err(e) = state.users.remove_friend_request(user_id, client.user_id) {
+            error!("[{}] Could not decline friend request from <{}>: {}", client.session_id, username, e);
+        }
+    }
+}
+
+#[derive(Default)]
+struct UnfriendCommand {
+    tags : HashMap<String, String>,
+}
+
+impl Command for UnfriendCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.tags = parseTags(args);
+        Ok(())
+    }
+
+    // Remove userName from your friends.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let state = clone.lock().unwrap();
+        let (username, user_id) = match friend_user(client, &state, &self.tags) {
+            None => return,
+            Some(user) => user,
+        };
+        if !state.users.are_friends(client.user_id, user_id) {
+            out_FAILED(client, "UNFRIEND", "Not friends with user.");
+            return;
+        }
+        if let Err(e) = state.users.unfriend_users(client.user_id, user_id) {
+            error!("[{}] Could not unfriend <{}>: {}", client.session_id, username, e);
+            return;
+        }
+        client.Send(&format!("UNFRIEND userName={}", username));
+        if let Some(friend) = state.clientFromUsername(&username) {
+            friend.Send(&format!("UNFRIEND userName={}", client.username));
+        }
+    }
+}
+
+#[derive(Default)]
+struct FriendListCommand {}
+
+impl Command for FriendListCommand {
+    fn get_function_args(&mut self, _args: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let state = clone.lock().unwrap();
+        client.Send("FRIENDLISTBEGIN");
+        for username in state.users.get_friend_usernames(client.user_id).unwrap_or_default() {
+            client.Send(&format!("FRIENDLIST userName={}", username));
+        }
+        client.Send("FRIENDLISTEND");
+    }
+}
+
+#[derive(Default)]
+struct FriendRequestListCommand {}
+
+impl Command for FriendRequestListCommand {
+    fn get_function_args(&mut self, _args: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let state = clone.lock().unwrap();
+        client.Send("FRIENDREQUESTLISTBEGIN");
+        for (username, msg) in state.users.get_friend_request_list(client.user_id).unwrap_or_default() {
+            match msg {
+                Some(msg) => client.Send(&format!("FRIENDREQUESTLIST userName={}\tmsg={}", username, msg)),
+                None => client.Send(&format!("FRIENDREQUESTLIST userName={}", username)),
+            }
+        }
+        client.Send("FRIENDREQUESTLISTEND");
+    }
+}
+
+const MAX_IGNORES: usize = 50;
+
+#[derive(Default)]
+struct IgnoreCommand {
+    tags : HashMap<String, String>,
+}
+
+impl Command for IgnoreCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.tags = parseTags(args);
+        Ok(())
+    }
+
+    // Prevent SAID*, SAIDPRIVATE and friend requests from userName, optional reason=text tag.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let state = clone.lock().unwrap();
+        let username = match self.tags.get("userName") {
+            None => {
+                out_SERVERMSG(client, "Missing userName argument.");
+                return;
+            }
+            Some(username) => username,
+        };
+        let user = match state.users.clientFromUsername(username) {
+            None => {
+                out_SERVERMSG(client, "No such user.");
+                return;
+            }
+            Some(user) => user,
+        };
+        let user_id = user.id.unwrap_or(-1);
+        if AccessLevel::from_access(&user.access, user.bot != 0).isMod() {
+            out_SERVERMSG(client, "Can't ignore a moderator.");
+            return;
+        }
+        if *username == client.username {
+            out_SERVERMSG(client, "Can't ignore self.");
+            return;
+        }
+        if client.is_ignoring(user_id) {
+            out_SERVERMSG(client, "User is already ignored.");
+            return;
+        }
+        if client.ignored.lock().unwrap().len() >= MAX_IGNORES {
+            out_SERVERMSG(client, &format!("Ignore list full ({} users).", MAX_IGNORES));
+            return;
+        }
+
+        let reason = self.tags.get("reason").map(|reason| reason.trim()).filter(|reason| !reason.is_empty());
+        if let Err(e) = state.users.ignore_user(client.user_id, user_id, reason) {
+            error!("[{}] Could not ignore <{}>: {}", client.session_id, username, e);
+            return;
+        }
+        client.ignored.lock().unwrap().insert(user_id);
+        match reason {
+            Some(reason) => client.Send(&format!("IGNORE userName={}\treason={}", username, reason)),
+            None => client.Send(&format!("IGNORE userName={}", username)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct UnignoreCommand {
+    tags : HashMap<String, String>,
+}
+
+impl Command for UnignoreCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.tags = parseTags(args);
+        Ok(())
+    }
+
+    // Remove userName from your ignore list.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let state = clone.lock().unwrap();
+        let (username, user_id) = match friend_user(client, &state, &self.tags) {
+            None => return,
+            Some(user) => user,
+        };
+        if !client.is_ignoring(user_id) {
+            out_SERVERMSG(client, "User is not ignored.");
+            return;
+        }
+        if let Err(e) = state.users.unignore_user(client.user_id, user_id) {
+            error!("[{}] Could not unignore <{}>: {}", client.session_id, username, e);
+            return;
+        }
+        client.ignored.lock().unwrap().remove(&user_id);
+        client.Send(&format!("UNIGNORE userName={}", username));
+    }
+}
+
+#[derive(Default)]
+struct IgnoreListCommand {}
+
+impl Command for IgnoreListCommand {
+    fn get_function_args(&mut self, _args: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let state = clone.lock().unwrap();
+        client.Send("IGNORELISTBEGIN");
+        for (username, reason) in state.users.get_ignore_list(client.user_id).unwrap_or_default() {
+            match reason {
+                Some(reason) => client.Send(&format!("IGNORELIST userName={}\treason={}", username, reason)),
+                None => client.Send(&format!("IGNORELIST userName={}", username)),
+            }
+        }
+        client.Send("IGNORELISTEND");
+    }
+}
+
+#[derive(Default)]
+struct JoinCommand {
+    chan : String,
+    key : Option<String>,
+}
+
+impl Command for JoinCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.chan = args.word("chan")?.trim_start_matches('#').into();
+        self.key = args.opt_word().map(Into::into);
+        Ok(())
+    }
+
+    fn execute(&self, client: &mut Client) {
+        if let Err(reason) = validChannelSyntax(&self.chan) {
+            client.Send(&format!("JOINFAILED {}", reason));
+            return;
+        }
+        if self.chan == "moderator" && !client.accesslevels.isMod() {
+            out_FAILED(client, "JOIN", "Only moderators allowed in this channel!");
+            return;
+        }
+        if self.chan.is_empty() {
+            out_FAILED(client, "JOIN", "Invalid channel");
+            return;
+        }
+
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if state.get_channel(&self.chan).is_none() && self.chan.starts_with("__battle__") {
+            out_FAILED(client, "JOIN", &format!("cannot create channel {} with prefix __battle__, these names are reserved for battles", self.chan));
+            return;
+        }
+        if let Err(reason) = state.enter_channel(client, &self.chan, self.key.as_deref()) {
+            client.Send(&format!("JOINFAILED {} {}", self.chan, reason));
+        }
+    }
+}
+
+#[derive(Default)]
+struct LeaveCommand {
+    chan : String,
+}
+
+impl Command for LeaveCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.chan = Args::new(args).word("chan")?.into();
+        Ok(())
+    }
+
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        match state.get_channel(&self.chan) {
+            None => {}
+            Some(chan) if !chan.has_user(client.session_id) => {
+                out_FAILED(client, "LEAVE", &format!("not in channel {}", self.chan));
+            }
+            Some(_) => state.leave_channel(client, &self.chan, None),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ChannelsCommand {}
+
+impl Command for ChannelsCommand {
+    fn get_function_args(&mut self, _args: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Return a listing of all channels on the server.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let state = clone.lock().unwrap();
+        for chan in state.channels().filter(|chan| !chan.hasKey()) {
+            client.Send(&format!("CHANNEL {} {} {}", chan.name, chan.user_count(), chan.topic));
+        }
+        client.Send("ENDOFCHANNELS");
+    }
+}
+
+#[derive(Default)]
+struct OpenBattleCommand {
+    battle_type : String,
+    nat_type : String,
+    key : String,
+    port : String,
+    maxplayers : String,
+    hashcode : String,
+    rank : String,
+    maphash : String,
+    sentence_args : String,
+}
+
+impl Command for OpenBattleCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.battle_type = args.word("type")?.into();
+        self.nat_type = args.word("natType")?.into();
+        self.key = args.word("key")?.into();
+        self.port = args.word("port")?.into();
+        self.maxplayers = args.word("maxplayers")?.into();
+        self.hashcode = args.word("hashcode")?.into();
+        self.rank = args.word("rank")?.into();
+        self.maphash = args.word("maphash")?.into();
+        self.sentence_args = args.rest("sentence")?.into();
+        Ok(())
+    }
+
+    // Host a new battle with the arguments specified.
+    fn execute(&self, client: &mut Client) {
+        let sentence : Vec<&str> = self.sentence_args.split('\t').collect();
+        if sentence.len() != 5 {
+            out_OPENBATTLEFAILED(client, &format!("Invalid arguments ({}): {}", sentence.len() - 1, self.sentence_args));
+            return;
+        }
+        let (engine, version, map, title, modname) = (sentence[0], sentence[1], sentence[2], sentence[3].trim(), sentence[4]);
+
+        let checkvars = [
+            (engine, "No engine specified."),
+            (version, "No engine version specified."),
+            (map, "No map name specified"),
+            (title, "No title specified"),
+            (modname, "No game name specified"),
+        ];
+        if let Some((_, error)) = checkvars.iter().find(|(var, _)| var.is_empty()) {
+            out_OPENBATTLEFAILED(client, error);
+            return;
+        }
+
+        let parsed = (|| -> Option<(i32, i32, u32, i32, i32, i32, i32)> {
+            Some((
+                self.battle_type.parse().ok()?,
+                self.nat_type.parse().ok()?,
+                self.port.parse().ok()?,
+                self.maxplayers.parse().ok()?,
+                self.hashcode.parse().ok()?,
+                self.rank.parse().ok()?,
+                self.maphash.parse().ok()?,
+            ))
+        })();
+        let (battle_type, nat_type, port, mut maxplayers, hashcode, rank, maphash) = match parsed {
+            Some(v) => v,
+            None => {
+                out_OPENBATTLEFAILED(client, &format!(
+                    "Invalid argument type, send this to your lobby dev: type={} natType={} key={} port={} maphash={} gamehash={}",
+                    self.battle_type, self.nat_type, self.key, self.port, self.maphash, self.hashcode));
+                return;
+            }
+        };
+
+        if !(1..=65535).contains(&port) {
+            out_OPENBATTLEFAILED(client, &format!("Port is out of range: 1-65535: {}", port));
+            return;
+        }
+        if hashcode == 0 {
+            out_OPENBATTLEFAILED(client, "Invalid game hash 0");
+            return;
+        }
+
+        let noflag_limit = 8;
+        if !client.accesslevels.isBot() && maxplayers > noflag_limit {
+            maxplayers = noflag_limit;
+            out_SERVERMSG(client, &format!("A botflag is required to host battles with > {} players. Your battle was restricted to {} players", noflag_limit, noflag_limit));
+        }
+
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let title = match state.sayhooks.read().unwrap().hook_OPENBATTLE(title) {
+            None => {
+                out_OPENBATTLEFAILED(client, "Battle title links to a forbidden site");
+                return;
+            }
+            Some(title) => title,
+        };
+        state.leave_battle(client.session_id, &client.username);
+
+        let mut battle = Battle::new(state.next_battle_id(), client);
+        battle.battle_type = battle_type;
+        battle.nat_type = nat_type;
+        battle.key = Some(self.key.clone());
+        battle.port = port as u16;
+        battle.maxplayers = maxplayers;
+        battle.hashcode = hashcode;
+        battle.rank = rank;
+        battle.maphash = maphash;
+        battle.engine = engine.into();
+        battle.version = version.into();
+        battle.map = map.into();
+        battle.title = title;
+        battle.modname = modname.into();
+        state.open_battle(client, battle);
+    }
+}
+
+#[derive(Default)]
+struct JoinBattleCommand {
+    battle_id : String,
+    key : Option<String>,
+    script_password : Option<String>,
+}
+
+impl Command for JoinBattleCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.battle_id = args.word("battle_id")?.into();
+        self.key = args.opt_word().map(Into::into);
+        self.script_password = args.opt_word().map(Into::into);
+        Ok(())
+    }
+
+    // Attempt to join target battle.
+    fn execute(&self, client: &mut Client) {
+        let battle_id = match self.battle_id.parse::<usize>() {
+            Ok(v) => v,
+            Err(_) => {
+                client.Send(&format!("JOINBATTLEFAILED Invalid battle id: {}.", self.battle_id));
+                return;
+            }
+        };
+
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if state.getCurrentBattle(client.session_id).is_some() {
+            client.Send("JOINBATTLEFAILED You are already in a battle");
+            return;
+        }
+        let battle = match state.get_battle(battle_id) {
+            None => {
+                client.Send("JOINBATTLEFAILED Battle does not exist");
+                return;
+            }
+            Some(battle) => battle,
+        };
+        if !client.accesslevels.isMod() {
+            if battle.passworded() && battle.key != self.key {
+                client.Send("JOINBATTLEFAILED Incorrect password");
+                return;
+            }
+            if battle.locked {
+                client.Send("JOINBATTLEFAILED Battle is locked");
+                return;
+            }
+        }
+        state.join_battle(client, battle_id, self.script_password.as_deref());
+    }
+}
+
+#[derive(Default)]
+struct LeaveBattleCommand {}
+
+impl Command for LeaveBattleCommand {
+    fn get_function_args(&mut self, _args: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Leave current battle.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if state.getCurrentBattle(client.session_id).is_none() {
+            out_FAILED(client, "LEAVEBATTLE", "not in battle");
+            return;
+        }
+        state.leave_battle(client.session_id, &client.username);
+    }
+}
+
+#[derive(Default)]
+struct UpdateBattleInfoCommand {
+    locked : String,
+    maphash : String,
+    mapname : String,
+}
+
+impl Command for UpdateBattleInfoCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        let _spectators = args.word("SpectatorCount")?; // calculated by the server
+        self.locked = args.word("locked")?.into();
+        self.maphash = args.word("maphash")?.into();
+        self.mapname = args.rest("mapname")?.into();
+        Ok(())
+    }
+
+    // Update public properties of your battle.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let battle = match state.getCurrentBattle(client.session_id).and_then(|id| state.get_battle(id)) {
+            Some(battle) if battle.canChangeSettings(client.session_id) => battle,
+            _ => return,
+        };
+
+        let maphash = match self.maphash.parse::<i32>() {
+            Ok(v) => v,
+            Err(_) => {
+                out_SERVERMSG(client, &format!("UPDATEBATTLEINFO failed - Invalid map hash send: {} {} ", self.mapname, self.maphash));
+                return;
+            }
+        };
+        if self.mapname.is_empty() || self.mapname.contains('\t') {
+            out_SERVERMSG(client, &format!("UPDATEBATTLEINFO failed - invalid mapname send: {}", self.mapname));
+            return;
+        }
+
+        let oldstr = battle.info_message();
+        battle.locked = self.locked.parse::<i32>().unwrap_or(0) != 0;
+        battle.maphash = maphash;
+        battle.map = self.mapname.clone();
+        let newstr = battle.info_message();
+        if oldstr != newstr {
+            state.broadcast(&newstr, &[]);
+        }
+    }
+}
+
+#[derive(Default)]
+struct SetScriptTagsCommand {
+    scripttags : String,
+}
+
+impl Command for SetScriptTagsCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.scripttags = args.into();
+        Ok(())
+    }
+
+    // Set script tags and send them to all clients in your battle.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let battle = match hosted_battle(&mut state, client) {
+            None => {
+                out_FAILED(client, "SETSCRIPTTAGS", "You are not allowed to change settings in this battle");
+                return;
+            }
+            Some(battle) => battle,
+        };
+        let scripttags = battle.set_script_tags(parseTags(&self.scripttags));
+        if !scripttags.is_empty() {
+            battle.broadcast(&format!("SETSCRIPTTAGS {}", scripttags.join("\t")));
+        }
+    }
+}
+
+#[derive(Default)]
+struct RemoveScriptTagsCommand {
+    tags : String,
+}
+
+impl Command for RemoveScriptTagsCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.tags = args.into();
+        Ok(())
+    }
+
+    // Remove script tags and send an update to all clients in your battle.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let battle = match hosted_battle(&mut state, client) {
+            None => {
+                out_FAILED(client, "REMOVESCRIPTTAGS", "You are not allowed to change settings in this battle");
+                return;
+            }
+            Some(battle) => battle,
+        };
+        // this means we only broadcast removed tags if they existed
+        let removed = battle.remove_script_tags(self.tags.split(' '));
+        if !removed.is_empty() {
+            battle.broadcast(&format!("REMOVESCRIPTTAGS {}", removed.join(" ")));
+        }
+    }
+}
+
+#[derive(Default)]
+struct AddStartRectCommand {
+    allyno : String,
+    rect : Vec<String>,
+}
+
+impl Command for AddStartRectCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.allyno = args.word("allyno")?.into();
+        self.rect = ["left", "top", "right", "bottom"].iter()
+            .map(|name| args.word(name).map(Into::into))
+            .collect::<Result<_, _>>()?;
+        Ok(())
+    }
+
+    // Add a start rectangle for an ally team.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let battle = match hosted_battle(&mut state, client) {
+            None => return,
+            Some(battle) => battle,
+        };
+        let coords : Result<Vec<u32>, _> = self.rect.iter().map(|v| v.parse::<u32>()).collect();
+        let (allyno, coords) = match (self.allyno.parse::<i32>(), coords) {
+            (Ok(allyno), Ok(coords)) => (allyno, coords),
+            _ => {
+                out_SERVERMSG(client, "invalid ADDSTARTRECT received");
+                return;
+            }
+        };
+        let rect = StartRect { left: coords[0], top: coords[1], right: coords[2], bottom: coords[3] };
+        battle.broadcast(&format!("ADDSTARTRECT {} {} {} {} {}", allyno, rect.left, rect.top, rect.right, rect.bottom));
+        battle.add_startrect(allyno, rect);
+    }
+}
+
+#[derive(Default)]
+struct RemoveStartRectCommand {
+    allyno : String,
+}
+
+impl Command for RemoveStartRectCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.allyno = args.into();
+        Ok(())
+    }
+
+    // Remove a start rectangle for an ally team.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let battle = match hosted_battle(&mut state, client) {
+            None => return,
+            Some(battle) => battle,
+        };
+        match self.allyno.parse::<i32>() {
+            Ok(allyno) if battle.remove_startrect(allyno) => {
+                battle.broadcast(&format!("REMOVESTARTRECT {}", allyno));
+            }
+            _ => out_SERVERMSG(client, &format!("invalid rect removed: {}", self.allyno)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct DisableUnitsCommand {
+    units : String,
+}
+
+impl Command for DisableUnitsCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.units = args.into();
+        Ok(())
+    }
+
+    // Add a list of units to disable.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if let Some(battle) = hosted_battle(&mut state, client) {
+            let disabled = battle.disable_units(self.units.split(' '));
+            if !disabled.is_empty() {
+                battle.broadcast(&format!("DISABLEUNITS {}", disabled.join(" ")));
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct EnableUnitsCommand {
+    units : String,
+}
+
+impl Command for EnableUnitsCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.units = args.into();
+        Ok(())
+    }
+
+    // Remove units from the disabled unit list.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if let Some(battle) = hosted_battle(&mut state, client) {
+            let enabled = battle.enable_units(self.units.split(' '));
+            if !enabled.is_empty() {
+                battle.broadcast(&format!("ENABLEUNITS {}", enabled.join(" ")));
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct EnableAllUnitsCommand {}
+
+impl Command for EnableAllUnitsCommand {
+    fn get_function_args(&mut self, _args: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        if let Some(battle) = hosted_battle(&mut state, client) {
+            battle.enable_all_units();
+            battle.broadcast("ENABLEALLUNITS");
+        }
+    }
+}
+
+#[derive(Default)]
+struct AddBotCommand {
+    name : String,
+    battlestatus : String,
+    teamcolor : String,
+    ai_dll : String,
+}
+
+impl Command for AddBotCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.name = args.word("name")?.into();
+        self.battlestatus = args.word("battlestatus")?.into();
+        self.teamcolor = args.word("teamcolor")?.into();
+        self.ai_dll = args.rest("AIDLL")?.into();
+        Ok(())
+    }
+
+    // Add a bot to the current battle.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let battle = match state.getCurrentBattle(client.session_id).and_then(|id| state.get_battle(id)) {
+            None => {
+                out_FAILED(client, "ADDBOT", "Couldn't find battle");
+                return;
+            }
+            Some(battle) => battle,
+        };
+        let (battlestatus, teamcolor) = match (self.battlestatus.parse(), self.teamcolor.parse()) {
+            (Ok(battlestatus), Ok(teamcolor)) => (battlestatus, teamcolor),
+            _ => {
+                out_FAILED(client, "ADDBOT", "Invalid battlestatus or teamcolor");
+                return;
+            }
+        };
+        let bot = BattleBot {
+            owner: client.username.clone(),
+            battlestatus,
+            teamcolor,
+            ai_dll: self.ai_dll.clone(),
+        };
+        let message = battle.add_bot_message(&self.name, &bot);
+        if !battle.add_bot(&self.name, bot) {
+            out_FAILED(client, "ADDBOT", "Bot already exists!");
+            return;
+        }
+        battle.broadcast(&message);
+    }
+}
+
+#[derive(Default)]
+struct UpdateBotCommand {
+    name : String,
+    battlestatus : String,
+    teamcolor : String,
+}
+
+impl Command for UpdateBotCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.name = args.word("name")?.into();
+        self.battlestatus = args.word("battlestatus")?.into();
+        self.teamcolor = args.word("teamcolor")?.into();
+        Ok(())
+    }
+
+    // Update battle status and teamcolor for a bot.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let battle = match state.getCurrentBattle(client.session_id).and_then(|id| state.get_battle(id)) {
+            None => {
+                out_FAILED(client, "UPDATEBOT", "Couldn't find battle");
+                return;
+            }
+            Some(battle) => battle,
+        };
+        let (battlestatus, teamcolor) = match (self.battlestatus.parse(), self.teamcolor.parse()) {
+            (Ok(battlestatus), Ok(teamcolor)) => (battlestatus, teamcolor),
+            _ => {
+                out_FAILED(client, "UPDATEBOT", "Invalid battlestatus or teamcolor");
+                return;
+            }
+        };
+        let is_host = battle.canChangeSettings(client.session_id);
+        let battle_id = battle.battle_id;
+        if let Some(bot) = battle.get_bot(&self.name) {
+            if bot.owner == client.username || is_host {
+                bot.battlestatus = battlestatus;
+                bot.teamcolor = teamcolor;
+                battle.broadcast(&format!("UPDATEBOT {} {} {} {}", battle_id, self.name, battlestatus, teamcolor));
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct RemoveBotCommand {
+    name : String,
+}
+
+impl Command for RemoveBotCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.name = args.into();
+        Ok(())
+    }
+
+    // Remove a bot from the active battle.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let battle = match state.getCurrentBattle(client.session_id).and_then(|id| state.get_battle(id)) {
+            None => {
+                out_FAILED(client, "REMOVEBOT", "Couldn't find battle");
+                return;
+            }
+            Some(battle) => battle,
+        };
+        let is_host = battle.canChangeSettings(client.session_id);
+        let allowed = match battle.get_bot(&self.name) {
+            Some(bot) => bot.owner == client.username || is_host,
+            None => false,
+        };
+        if allowed {
+            battle.remove_bot(&self.name);
+            battle.broadcast(&format!("REMOVEBOT {} {}", battle.battle_id, self.name));
+        }
+    }
+}
+
+#[derive(Default)]
+struct MyBattleStatusCommand {
+    battlestatus : String,
+    teamcolor : String,
+}
+
+impl Command for MyBattleStatusCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.battlestatus = args.word("battlestatus")?.into();
+        self.teamcolor = args.word("teamcolor")?.into();
+        Ok(())
+    }
+
+    // Set your status in a battle.
+    fn execute(&self, client: &mut Client) {
+        if self.battlestatus.starts_with('-') {
+            out_FAILED(client, "MYBATTLESTATUS", &format!("invalid status is below 0: {}. Please update your lobby!", self.battlestatus));
+            return;
+        }
+        let status = match self.battlestatus.parse::<BattleStatus>() {
+            Ok(status) => status,
+            Err(_) => {
+                out_FAILED(client, "MYBATTLESTATUS", &format!("invalid status: {}.", self.battlestatus));
+                return;
+            }
+        };
+        let teamcolor = match self.teamcolor.parse::<TeamColor>() {
+            Ok(teamcolor) => teamcolor,
+            Err(_) => {
+                out_FAILED(client, "MYBATTLESTATUS", &format!("invalid teamcolor: {}.", self.teamcolor));
+                return;
+            }
+        };
+
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let battle = match state.getCurrentBattle(client.session_id).and_then(|id| state.get_battle(id)) {
+            None => {
+                out_FAILED(client, "MYBATTLESTATUS", "not inside a battle");
+                return;
+            }
+            Some(battle) => battle,
+        };
+        let battle_full = battle.player_count() as i32 >= battle.maxplayers;
+        let user = battle.get_user(client.session_id).unwrap();
+        let (oldstatus, oldcolor) = (user.status, user.teamcolor);
+        user.status = BattleStatus {
+            handicap: oldstatus.handicap, // only the host can change it
+            spectator: status.spectator || (oldstatus.spectator && battle_full),
+            ..status
+        };
+        user.teamcolor = teamcolor;
+        let changed = user.status != oldstatus || user.teamcolor != oldcolor;
+
+        let info = battle.update_spectators().then(|| battle.info_message());
+        let statuscmd = battle.status_message(client.session_id).unwrap();
+        if !changed {
+            client.Send(&statuscmd);
+        } else {
+            battle.broadcast(&statuscmd);
+        }
+        if let Some(info) = info {
+            state.broadcast(&info, &[]);
+        }
+    }
+}
+
+// applies a host command to a user of the hosted battle and relays the new status to the battle,
+// nothing is sent if update returns false
+fn force_battlestatus(client: &Client, username: &str, update: impl FnOnce(&mut BattleUser) -> bool) {
+    let clone = client.server_state.clone();
+    let mut state = clone.lock().unwrap();
+    let battle = match hosted_battle(&mut state, client) {
+        None => return,
+        Some(battle) => battle,
+    };
+    let session = match battle.session_of(username) {
+        None => return,
+        Some(session) => session,
+    };
+    if !update(battle.get_user(session).unwrap()) {
+        return;
+    }
+    if let Some(statuscmd) = battle.status_message(session) {
+        battle.broadcast(&statuscmd);
+    }
+    if battle.update_spectators() {
+        let info = battle.info_message();
+        state.broadcast(&info, &[]);
+    }
+}
+
+#[derive(Default)]
+struct ForceTeamNoCommand {
+    username : String,
+    teamno : u8,
+}
+
+impl Command for ForceTeamNoCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.username = args.word("username")?.into();
+        self.teamno = args.parse("teamno")?;
+        Ok(())
+    }
+
+    // Force target player's team number.
+    fn execute(&self, client: &mut Client) {
+        if self.teamno > MAX_TEAM {
+            out_FAILED(client, "FORCETEAMNO", &format!("Invalid team number: {}", self.teamno));
+            return;
+        }
+        let teamno = self.teamno;
+        force_battlestatus(client, &self.username, |user| {
+            user.status.team = teamno;
+            true
+        });
+    }
+}
+
+#[derive(Default)]
+struct ForceAllyNoCommand {
+    username : String,
+    allyno : u8,
+}
+
+impl Command for ForceAllyNoCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.username = args.word("username")?.into();
+        self.allyno = args.parse("allyno")?;
+        Ok(())
+    }
+
+    // Force target player's ally team number.
+    fn execute(&self, client: &mut Client) {
+        if self.allyno > MAX_ALLY {
+            out_FAILED(client, "FORCEALLYNO", &format!("Invalid ally team number: {}", self.allyno));
+            return;
+        }
+        let allyno = self.allyno;
+        force_battlestatus(client, &self.username, |user| {
+            user.status.ally = allyno;
+            true
+        });
+    }
+}
+
+#[derive(Default)]
+struct ForceTeamColorCommand {
+    username : String,
+    teamcolor : TeamColor,
+}
+
+impl Command for ForceTeamColorCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.username = args.word("username")?.into();
+        self.teamcolor = args.parse("teamcolor")?;
+        Ok(())
+    }
+
+    // Force target player's team color.
+    fn execute(&self, client: &mut Client) {
+        let teamcolor = self.teamcolor;
+        force_battlestatus(client, &self.username, |user| {
+            user.teamcolor = teamcolor;
+            true
+        });
+    }
+}
+
+#[derive(Default)]
+struct ForceSpectatorModeCommand {
+    username : String,
+}
+
+impl Command for ForceSpectatorModeCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.username = Args::new(args).word("username")?.into();
+        Ok(())
+    }
+
+    // Force target player to become a spectator.
+    fn execute(&self, client: &mut Client) {
+        force_battlestatus(client, &self.username, |user| {
+            if user.status.spectator {
+                return false;
+            }
+            user.status.spectator = true;
+            true
+        });
+    }
+}
+
+#[derive(Default)]
+struct HandicapCommand {
+    username : String,
+    value : String,
+}
+
+impl Command for HandicapCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.username = args.word("username")?.into();
+        self.value = args.word("value")?.into();
+        Ok(())
+    }
+
+    // Change the handicap value for a player.
+    fn execute(&self, client: &mut Client) {
+        let handicap = match self.value.parse::<u8>() {
+            Ok(handicap) if handicap <= MAX_HANDICAP => handicap,
+            _ => {
+                out_FAILED(client, "HANDICAP", &format!("Invalid handicap: {}, must be 0-100", self.value));
+                return;
+            }
+        };
+        force_battlestatus(client, &self.username, |user| {
+            user.status.handicap = handicap;
+            true
+        });
+    }
+}
+
+#[derive(Default)]
+struct KickFromBattleCommand {
+    username : String,
+}
+
+impl Command for KickFromBattleCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.username = Args::new(args).word("username")?.into();
+        Ok(())
+    }
+
+    // Kick a player from their battle.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let is_mod = state.users.clientFromUsername(&self.username)
+            .map(|user| AccessLevel::from_access(&user.access, user.bot != 0).isMod())
+            .unwrap_or(false);
+        let battle = match hosted_battle(&mut state, client) {
+            None => return,
+            Some(battle) => battle,
+        };
+        let session = match battle.session_of(&self.username) {
+            Some(session) if !is_mod => session,
+            _ => return,
+        };
+        battle.send_to(session, &format!("FORCEQUITBATTLE {}", client.username));
+        state.leave_battle(session, &self.username);
+    }
+}
+
+#[derive(Default)]
+struct MyStatusCommand {
+    status : ClientStatus,
+}
+
+impl Command for MyStatusCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.status = Args::new(args).parse("status")?;
+        Ok(())
+    }
+
+    // Set your client status, to be relayed to all other clients.
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        let was_ingame = client.status.ingame;
+        if self.status.ingame && !was_ingame {
+            let battle = match state.getCurrentBattle(client.session_id).and_then(|id| state.get_battle(id)) {
+                None => {
+                    out_FAILED(client, "MYSTATUS", "ingame but no battleid set");
+                    return;
+                }
+                Some(battle) => battle,
+            };
+            // playing alone doesn't count for the rank
+            client.went_ingame = (battle.user_count() > 1).then(Instant::now);
+        } else if was_ingame && !self.status.ingame {
+            client.end_ingame(&state.users);
+        }
+
+        client.status = ClientStatus::calc(self.status, client.ingame_time, &client.accesslevels);
+        state.set_status(&client.username, client.status);
+    }
+}
+
+#[derive(Default)]
+struct PortTestCommand {
+    host : String,
+    port : usize,
+}
+
+impl Command for PortTestCommand  {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.host = args.word("host")?.into();
+        self.port = args.parse("port")?;
+        Ok(())
+    }
+
+    fn execute(&self, _client: &mut Client) {
+        debug!("Executing PortTestCommand {}:{}", self.host, self.port);
+        let local = "0.0.0.0:0".parse::<SocketAddr>().unwrap();
+        match UdpSocket::bind(local) {
+            Ok(socket) => {
+                let target = format!("{}:{}", self.host, self.port);
+                let _ = socket.send_to(b"Port testing...", target);
+            },
+            Err(_) => {
+                error!("Could not open udp socket on {}:{} in PortTestCommand", self.host, self.port);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct ReloadCommand {}
+
+impl Command for ReloadCommand {
+    fn get_function_args(&mut self, _args: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Reload the profanity, shock site and bad nick lists and the anti-spam config. [admin]
+    fn execute(&self, client: &mut Client) {
+        info!("[{}] Reload initiated by <{}>", client.session_id, client.username);
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        state.sayhooks.write().unwrap().reload();
+        match state.antispam.reload() {
+            Ok(()) => out_SERVERMSG(client, "Reload successful"),
+            Err(e) => out_SERVERMSG(client, &format!("Reload failed, keeping the previous anti-spam config: {}", e)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct SetAccessCommand {
+    username : String,
+    access : Role,
+}
+
+impl Command for SetAccessCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        let mut args = Args::new(args);
+        self.username = args.word("username")?.into();
+        self.access = args.parse("access")?;
+        Ok(())
+    }
+
+    // Change the role of an account (fresh, agreement, user, mod or admin), bot status is kept. [admin]
+    fn execute(&self, client: &mut Client) {
+        let role = self.access;
+        if self.username == client.username {
+            out_FAILED(client, "SETACCESS", "You can't change your own access");
+            return;
+        }
+
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        match state.set_access(client.user_id, &client.username, &self.username, role) {
+            Ok(()) => out_SERVERMSG(client, &format!("Access of <{}> was changed to {}", self.username, role)),
+            Err(reason) => out_FAILED(client, "SETACCESS", &reason),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ListModsCommand {}
+
+impl Command for ListModsCommand {
+    fn get_function_args(&mut self, _args: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    // List the accounts with moderator or admin access. [moderator]
+    fn execute(&self, client: &mut Client) {
+        let clone = client.server_state.clone();
+        let state = clone.lock().unwrap();
+        let moderators = match state.users.moderators() {
+            Ok(moderators) => moderators,
+            Err(e) => {
+                error!("[{}] Could not list moderators: {}", client.session_id, e);
+                out_FAILED(client, "LISTMODS", "Database error, please try again later.");
+                return;
+            }
+        };
+        client.Send("MODLISTBEGIN");
+        for user in moderators {
+            let accesslevels = AccessLevel::from_access(&user.access, user.bot != 0);
+            let online = state.usernames.contains_key(&user.username);
+            client.Send(&format!("MODLIST {} {} {}", user.username, accesslevels.access(), online as u8));
+        }
+        client.Send("MODLISTEND");
+    }
+}
+
+#[derive(Default)]
+struct JsonCommand {
+    envelope : String,
+}
+
+impl Command for JsonCommand {
+    fn get_function_args(&mut self, args: &str) -> Result<(), String> {
+        self.envelope = Args::new(args).rest("envelope")?.into();
+        Ok(())
+    }
+
+    // Run the command in {"cmd": .., "args": .., "id": ..}, from now on everything is sent as JSON.
+    fn execute(&self, client: &mut Client) {
+        client.json = true;
+        let request = match jsonproto::parse_request(&self.envelope) {
+            Ok(request) => request,
+            Err(reason) => {
+                out_FAILED(client, "JSON", &reason);
+                return;
+            }
+        };
+        if request.line.split(' ').next().is_some_and(|cmd| cmd.eq_ignore_ascii_case("JSON")) {
+            out_FAILED(client, "JSON", "JSON commands can't be nested");
+            return;
+        }
+        // the id of the envelope wins over a #id prefix of the JSON line
+        client.request_id = request.id.or(client.request_id);
+        client.HandleProtocolCommand(&request.line);
+    }
+}
+
+#[derive(Default)]
+struct StlsCommand {}
+
+impl Command for StlsCommand {
+    fn get_function_args(&mut self, _args: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    // Encrypt the connection, the TLS handshake starts after OK and the greeting is sent again.
+    fn execute(&self, client: &mut Client) {
+        match client.StartTLS() {
+            Ok(()) => client.Send("OK cmd=STLS"),
+            Err(reason) => out_FAILED(client, "STLS", reason),
+        }
+    }
+}
+
+#[derive(Default)]
+struct ListCommandsCommand {}
+
+impl Command for ListCommandsCommand {
+    fn get_function_args(&mut self, _args: &str) -> Result<(), String> {
+        Ok(())
+    }
+
+    // List which commands each access level may use, a session may use those of all its levels.
+    fn execute(&self, client: &mut Client) {
+        client.Send(&format!("COMMANDLISTBEGIN {}", Protocol::access_levels(client).join(" ")));
+        for (level, commands) in RESTRICTED {
+            client.Send(format!("COMMANDLIST {} {}", level, commands.join(" ")).trim_end());
+        }
+        client.Send("COMMANDLISTEND");
+    }
+}
+
+impl Protocol {
+    fn get_function(command: &str) -> Option<Box<dyn Command>> {
+        match command {
+            "PING" => Some(Box::new(PingCommand::default())),
+            "PORTTEST" => Some(Box::new(PortTestCommand::default())),
+            "REGISTER" => Some(Box::new(RegisterCommand::default())),
+            "LOGIN" => Some(Box::new(LoginCommand::default())),
+            "RENAMEACCOUNT" => Some(Box::new(RenameAccountCommand::default())),
+            "CONFIRMAGREEMENT" => Some(Box::new(ConfirmAgreementCommand::default())),
+            "JOIN" => Some(Box::new(JoinCommand::default())),
+            "LEAVE" => Some(Box::new(LeaveCommand::default())),
+            "CHANNELS" => Some(Box::new(ChannelsCommand::default())),
+            "SAY" => Some(Box::new(SayCommand::default())),
+            "OPENBATTLE" => Some(Box::new(OpenBattleCommand::default())),
+            "JOINBATTLE" => Some(Box::new(JoinBattleCommand::default())),
+            "LEAVEBATTLE" => Some(Box::new(LeaveBattleCommand::default())),
+            "UPDATEBATTLEINFO" => Some(Box::new(UpdateBattleInfoCommand::default())),
+            "SETSCRIPTTAGS" => Some(Box::new(SetScriptTagsCommand::default())),
+            "REMOVESCRIPTTAGS" => Some(Box::new(RemoveScriptTagsCommand::default())),
+            "ADDSTARTRECT" => Some(Box::new(AddStartRectCommand::default())),
+            "REMOVESTARTRECT" => Some(Box::new(RemoveStartRectCommand::default())),
+            "DISABLEUNITS" => Some(Box::new(DisableUnitsCommand::default())),
+            "ENABLEUNITS" => Some(Box::new(EnableUnitsCommand::default())),
+            "ENABLEALLUNITS" => Some(Box::new(EnableAllUnitsCommand::default())),
+            "ADDBOT" => Some(Box::new(AddBotCommand::default())),
+            "UPDATEBOT" => Some(Box::new(UpdateBotCommand::default())),
+            "REMOVEBOT" => Some(Box::new(RemoveBotCommand::default())),
+            "MYSTATUS" => Some(Box::new(MyStatusCommand::default())),
+            "MYBATTLESTATUS" => Some(Box::new(MyBattleStatusCommand::default())),
+            "FORCETEAMNO" => Some(Box::new(ForceTeamNoCommand::default())),
+            "FORCEALLYNO" => Some(Box::new(ForceAllyNoCommand::default())),
+            "FORCETEAMCOLOR" => Some(Box::new(ForceTeamColorCommand::default())),
+            "FORCESPECTATORMODE" => Some(Box::new(ForceSpectatorModeCommand::default())),
+            "HANDICAP" => Some(Box::new(HandicapCommand::default())),
+            "KICKFROMBATTLE" => Some(Box::new(KickFromBattleCommand::default())),
+            "SAYEX" =>  {
+                let cmd = SayCommand { ex_postfix: "EX".to_string(), ..Default::default() };
+                Some(Box::new(cmd))
+            },
+            "SAYPRIVATE" => Some(Box::new(SayPrivateCommand::default())),
+            "SAYPRIVATEEX" =>  {
+                let cmd = SayPrivateCommand { ex_postfix: "EX".to_string(), ..Default::default() };
+                Some(Box::new(cmd))
+            },
+            "FRIENDREQUEST" => Some(Box::new(FriendRequestCommand::default())),
+            "ACCEPTFRIENDREQUEST" => Some(Box::new(AcceptFriendRequestCommand::default())),
+            "DECLINEFRIENDREQUEST" => Some(Box::new(DeclineFriendRequestCommand::default())),
+            "UNFRIEND" => Some(Box::new(UnfriendCommand::default())),
+            "FRIENDLIST" => Some(Box::new(FriendListCommand::default())),
+            "FRIENDREQUESTLIST" => Some(Box::new(FriendRequestListCommand::default())),
+            "IGNORE" => Some(Box::new(IgnoreCommand::default())),
+            "UNIGNORE" => Some(Box::new(UnignoreCommand::default())),
+            "IGNORELIST" => Some(Box::new(IgnoreListCommand::default())),
+            "MUTE" => Some(Box::new(MuteCommand::default())),
+            "UNMUTE" => Some(Box::new(UnmuteCommand::default())),
+            "MUTELIST" => Some(Box::new(MuteListCommand::default())),
+            "CHANNELBAN" => Some(Box::new(ChannelBanCommand::default())),
+            "CHANNELUNBAN" => Some(Box::new(ChannelUnbanCommand::default())),
+            "CHANNELBANLIST" => Some(Box::new(ChannelBanListCommand::default())),
+            "SETCHANNELKEY" => Some(Box::new(SetChannelKeyCommand::default())),
+            "CHANNELFORWARD" => Some(Box::new(ChannelForwardCommand::default())),
+            "CHANNELUNFORWARD" => Some(Box::new(ChannelForwardCommand { remove: true, ..Default::default() })),
+            "FORCELEAVECHANNEL" => Some(Box::new(ForceLeaveChannelCommand::default())),
+            "CHANNELTOPIC" => Some(Box::new(ChannelTopicCommand::default())),
+            "GETCHANNELMESSAGES" => Some(Box::new(GetChannelMessagesCommand::default())),
+            "SETCHANNELHISTORY" => Some(Box::new(SetChannelHistoryCommand::default())),
+            "CHANNELREGISTER" => Some(Box::new(ChannelRegisterCommand::default())),
+            "CHANNELUNREGISTER" => Some(Box::new(ChannelUnregisterCommand::default())),
+            "SETCHANNELFOUNDER" => Some(Box::new(SetChannelFounderCommand::default())),
+            "CHANNELOP" => Some(Box::new(ChannelOpCommand::default())),
+            "CHANNELDEOP" => Some(Box::new(ChannelOpCommand { deop: true, ..Default::default() })),
+            "RELOAD" => Some(Box::new(ReloadCommand::default())),
+            "LISTCOMMANDS" => Some(Box::new(ListCommandsCommand::default())),
+            "STLS" => Some(Box::new(StlsCommand::default())),
+            "JSON" => Some(Box::new(JsonCommand::default())),
+            "SETACCESS" => Some(Box::new(SetAccessCommand::default())),
+            "LISTMODS" => Some(Box::new(ListModsCommand::default())),
+            _ => None
+        }
+    }
+
+    // the levels of RESTRICTED a session has
+    pub fn access_levels(client: &Client) -> Vec<&'static str> {
+        let mut levels = vec!["everyone"];
+        if !client.is_logged() {
+            levels.push(if client.accesslevels.isAgreement() { "agreement" } else { "fresh" });
+            return levels;
+        }
+        if client.accesslevels.isUser() {
+            levels.push("user");
+        }
+        if client.accesslevels.isBot() {
+            levels.push("bot");
+        }
+        if client.accesslevels.isMod() {
+            levels.push("mod");
+        }
+        if client.accesslevels.isAdmin() {
+            levels.push("admin");
+        }
+        levels
+    }
+
+    // why a session may not use a command, None if it may
+    fn check_access(client: &Client, command: &str) -> Option<&'static str> {
+        let levels = Protocol::access_levels(client);
+        let allowed: Vec<&str> = RESTRICTED.iter()
+            .filter(|(_, commands)| commands.contains(&command))
+            .map(|(level, _)| *level)
+            .collect();
+        if allowed.iter().any(|level| levels.contains(level)) {
+            None
+        } else if client.is_logged() && allowed.contains(&"fresh") {
+            Some("Already logged in.")
+        } else if !client.is_logged() && client.accesslevels.isAgreement() {
+            Some("Please confirm the agreement first.")
+        } else if !client.is_logged() {
+            Some("Please log in first.")
+        } else {
+            Some("Permission denied.")
+        }
+    }
+
+    pub fn _handle(&self, client: &Client, msg: &str) -> Result<Box<dyn Command>, Rejected> {
+        let (command, args) = msg.split_once(' ').unwrap_or((msg, ""));
+        let command = command.to_uppercase();
+        if !RESTRICTED.iter().any(|(_, commands)| commands.contains(&command.as_str())) {
+            return Err(Rejected::new(&command, "Unknown command."));
+        }
+        if let Some(reason) = Protocol::check_access(client, &command) {
+            return Err(Rejected::new(&command, reason));
+        }
+
+        // TODO add error checking for max cargs size
+        let mut fun = Protocol::get_function(&command)
+            .ok_or_else(|| Rejected::new(&command, "Unknown command."))?;
+        fun.get_function_args(args).map_err(|reason| Rejected::new(&command, &reason))?;
+        Ok(fun)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chatserver::ServerState;
+    use crate::client::{AccessLevel, SharedServerState};
+    use crate::sqlusers::{establish_connection, UsersHandler};
+    use std::sync::{Arc, Mutex, RwLock};
+    use tokio::sync::mpsc;
+
+    fn get_client(access: &str, logged_in: bool) -> Client {
+        let users = UsersHandler::new(establish_connection(":memory:"));
+        let state = ServerState::new(users, "test".into(), 8201, vec![], Arc::new(RwLock::new(Default::default())));
+        let state: SharedServerState = Arc::new(Mutex::new(state));
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut client = Client::new(state, tx, 1, "127.0.0.1:8200".parse().unwrap());
+        client.accesslevels = AccessLevel::from_access(access, false);
+        client.logged_in = logged_in;
+        client
+    }
+
+    #[test]
+    fn test_check_access() {
+        let fresh = get_client("fresh", false);
+        assert_eq!(Protocol::check_access(&fresh, "LOGIN"), None);
+        assert_eq!(Protocol::check_access(&fresh, "PING"), None);
+        assert_eq!(Protocol::check_access(&fresh, "JOIN"), Some("Please log in first."));
+        assert_eq!(Protocol::check_access(&fresh, "CONFIRMAGREEMENT"), Some("Please log in first."));
+
+        let agreement = get_client("agreement", false);
+        assert_eq!(Protocol::check_access(&agreement, "CONFIRMAGREEMENT"), None);
+        assert_eq!(Protocol::check_access(&agreement, "JOIN"), Some("Please confirm the agreement first."));
+        assert_eq!(Protocol::check_access(&agreement, "LOGIN"), Some("Please confirm the agreement first."));
+
+        let user = get_client("user", true);
+        assert_eq!(Protocol::check_access(&user, "JOIN"), None);
+        assert_eq!(Protocol::check_access(&user, "LOGIN"), Some("Already logged in."));
+        assert_eq!(Protocol::check_access(&user, "REGISTER"), Some("Already logged in."));
+        assert_eq!(Protocol::check_access(&user, "RELOAD"), Some("Permission denied."));
+        assert_eq!(Protocol::check_access(&user, "LISTMODS"), Some("Permission denied."));
+
+        let moderator = get_client("mod", true);
+        assert_eq!(Protocol::check_access(&moderator, "LISTMODS"), None);
+        assert_eq!(Protocol::check_access(&moderator, "RELOAD"), Some("Permission denied."));
+
+        // a logged in session whose account has no user access gets nothing but the commands of everyone
+        let unknown = get_client("nonsense", true);
+        assert_eq!(Protocol::access_levels(&unknown), vec!["everyone"]);
+        assert_eq!(Protocol::check_access(&unknown, "PING"), None);
+        assert_eq!(Protocol::check_access(&unknown, "JOIN"), Some("Permission denied."));
+
+        let admin = get_client("admin", true);
+        assert_eq!(Protocol::access_levels(&admin), vec!["everyone", "user", "mod", "admin"]);
+        assert_eq!(Protocol::check_access(&admin, "RELOAD"), None);
+        assert_eq!(Protocol::check_access(&admin, "JOIN"), None);
+    }
+
+    #[test]
+    fn test_request_ids() {
+        let mut client = get_client("fresh", false);
+        client.Handle("#5 PING");
+        assert_eq!(client.message_queue, "#5 PONG\n");
+
+        // the id sent back in JSON mode, the #id prefix is used for an envelope without id
+        let reply_id = |client: &mut Client, msg: &str| {
+            client.message_queue.clear();
+            client.Handle(msg);
+            let reply: serde_json::Value = serde_json::from_str(client.message_queue.trim_end()).unwrap();
+            assert_eq!(reply["cmd"], "PONG");
+            reply["id"].as_u64()
+        };
+        assert_eq!(reply_id(&mut client, r#"#6 JSON {"cmd": "PING"}"#), Some(6));
+        assert_eq!(reply_id(&mut client, r#"#6 JSON {"cmd": "PING", "id": 7}"#), Some(7));
+        assert_eq!(reply_id(&mut client, "#8 PING"), Some(8));
+        assert_eq!(reply_id(&mut client, "PING"), None);
+    }
+
+    #[test]
+    fn test_join_topic() {
+        let mut client = get_client("user", true);
+        client.username = "test".into();
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut other = Client::new(client.server_state.clone(), tx, 2, "127.0.0.1:8201".parse().unwrap());
+        other.username = "other".into();
+        let clone = client.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        state.join_channel(&mut client, "main");
+        assert!(!client.message_queue.contains("CHANNELTOPIC"));
+
+        state.set_channel_topic("main", 0, "test", "welcome").unwrap();
+        state.join_channel(&mut other, "main");
+        assert!(other.message_queue.contains("CHANNELTOPIC main test welcome"));
+    }
+
+    #[test]
+    fn test_get_channel_messages() {
+        let mut client = get_client("user", true);
+        client.username = "test".into();
+        let clone = client.server_state.clone();
+        let (channel_id, first, second) = {
+            let mut state = clone.lock().unwrap();
+            state.users.register_user("test", "pass", "127.0.0.1", "").unwrap();
+            client.user_id = state.users.clientFromUsername("test").unwrap().id.unwrap();
+            state.join_channel(&mut client, "main");
+            state.set_channel_history("main", "test", true).unwrap();
+            let channel_id = state.get_channel("main").unwrap().id;
+            let channels = state.users.channels();
+            let first = channels.add_channel_message(channel_id, client.user_id, "hello", false).unwrap();
+            let second = channels.add_channel_message(channel_id, client.user_id, "waves", true).unwrap();
+            (channel_id, first, second)
+        };
+        let times: Vec<i64> = clone.lock().unwrap().users.channels().get_channel_messages(channel_id, 0, 2).unwrap()
+            .iter().map(|message| message.1.and_utc().timestamp()).collect();
+        client.message_queue.clear();
+
+        let mut command = GetChannelMessagesCommand::default();
+        command.get_function_args("main").unwrap();
+        command.execute(&mut client);
+        let lines: Vec<&str> = client.message_queue.lines().collect();
+        assert_eq!(lines, vec![
+            format!("CHANNELHISTORY main {} {}", first, times[0]).as_str(),
+            "SAID main test hello",
+            format!("CHANNELHISTORY main {} {}", second, times[1]).as_str(),
+            "SAIDEX main test waves",
+        ]);
+
+        client.message_queue.clear();
+        command.get_function_args(&format!("main {}", first)).unwrap();
+        command.execute(&mut client);
+        assert_eq!(client.message_queue.lines().count(), 2);
+    }
+
+    #[test]
+    fn test_ban_removes_from_channel() {
+        let mut op = get_client("user", true);
+        op.username = "op".into();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut target = Client::new(op.server_state.clone(), tx, 2, "127.0.0.1:8201".parse().unwrap());
+        target.username = "spammer".into();
+        target.user_id = 5;
+        let clone = op.server_state.clone();
+        let mut state = clone.lock().unwrap();
+        state.add_session(&target);
+        state.join_channel(&mut op, "main");
+        state.join_channel(&mut target, "main");
+        state.join_channel(&mut target, "other");
+
+        let ban = Ban {
+            username: "spammer".into(),
+            issuer: "op".into(),
+            ip_address: "127.0.0.1".into(),
+            expires: expires_after(chrono::Duration::minutes(10)),
+            reason: "spam".into(),
+        };
+        state.ban_channel_user("main", 0, 5, ban).unwrap();
+        assert!(!state.get_channel("main").unwrap().has_user(2));
+        let channels = target.channels.lock().unwrap().clone();
+        assert!(!channels.contains("main") && channels.contains("other"));
+        let mut received = vec![];
+        while let Ok(msg) = rx.try_recv() {
+            received.push(msg);
+        }
+        assert!(received.contains(&"FORCELEAVECHANNEL main op banned".to_string()));
+    }
 
-        match fun {
-            None => Err(format!(
-                "{} failed. Unknown command. (args='{}')",
-                command, msg
-            )),
-            Some(v) => {
-                Ok(v)
+    #[test]
+    fn test_restricted_commands_exist() {
+        let mut seen = std::collections::HashSet::new();
+        for (level, commands) in RESTRICTED {
+            for command in commands.iter() {
+                assert!(Protocol::get_function(command).is_some(), "{} of {} is not a command", command, level);
+                assert!(seen.insert(command), "{} is listed twice", command);
             }
         }
     }
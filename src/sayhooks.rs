@@ -1,15 +1,12 @@
-use log::{debug, error, info};
+use log::{error, info};
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{self, BufRead};
 use std::path::Path;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
-use std::time::{Duration, Instant};
+use std::sync::{Arc, RwLock};
 
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
+pub fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
 where
     P: AsRef<Path>,
 {
@@ -17,13 +14,54 @@ where
     Ok(io::BufReader::new(file).lines())
 }
 
+// digits used in place of letters, 1 is matched as both i and l in leet_contains
+const LEET: [(char, char); 7] = [('0', 'o'), ('3', 'e'), ('4', 'a'), ('5', 's'), ('7', 't'), ('8', 'b'), ('9', 'g')];
+
+// the lowercase username, without [] and _, and with leetspeak replaced
+fn leet_variants(username: &str) -> [String; 3] {
+    let lower = username.to_lowercase();
+    let cleaned: String = lower.chars().filter(|letter| !"[]_".contains(*letter)).collect();
+    let deleet: String = cleaned.chars()
+        .map(|letter| LEET.iter().find(|(digit, _)| *digit == letter).map_or(letter, |(_, replacement)| *replacement))
+        .collect();
+    [lower, cleaned, deleet]
+}
+
+fn leet_contains(name: &str, word: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    let word: Vec<char> = word.chars().collect();
+    !word.is_empty() && name.windows(word.len()).any(|window| {
+        window.iter().zip(&word).all(|(letter, expected)| letter == expected || (*letter == '1' && (*expected == 'i' || *expected == 'l')))
+    })
+}
+
+pub type SharedSayHooks = Arc<RwLock<SayHooks>>;
+
+#[derive(Default)]
 pub struct SayHooks {
+    // false with --no-censor, bad sites are filtered regardless
+    pub censor: bool,
     bad_word_dict: HashMap<String, String>,
     bad_site_list: HashSet<String>,
     bad_nick_list: HashSet<String>,
 }
 
+#[allow(non_snake_case)]
 impl SayHooks {
+    pub fn new(censor: bool) -> Self {
+        let mut hooks = Self { censor, ..Default::default() };
+        hooks.load();
+        hooks
+    }
+
+    // rereads the lists, entries removed from the files are forgotten
+    pub fn reload(&mut self) {
+        self.bad_word_dict.clear();
+        self.bad_site_list.clear();
+        self.bad_nick_list.clear();
+        self.load();
+    }
+
     fn load(&mut self) {
         self.load_bad_words("bad_words.txt");
         self.load_bad_sites("bad_sites.txt");
@@ -33,19 +71,17 @@ impl SayHooks {
     fn load_bad_words(&mut self, file_name: &str) {
         match read_lines(file_name) {
             Ok(lines) => {
-                for line in lines {
-                    if let Ok(line) = line {
-                        let line = line.trim();
-                        if line.is_empty() {
-                            continue;
+                for line in lines.map_while(Result::ok) {
+                    let line = line.trim();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    match line.split_once(' ') {
+                        Some((left, right)) => {
+                            self.bad_word_dict.insert(left.to_lowercase(), right.to_string());
                         }
-                        match line.split_once(' ') {
-                            Some((left, right)) => {
-                                self.bad_word_dict.insert(left.to_string(), right.to_string());
-                            }
-                            None => {
-                                self.bad_word_dict.insert(line.to_string(), "***".to_string());
-                            }
+                        None => {
+                            self.bad_word_dict.insert(line.to_lowercase(), "***".to_string());
                         }
                     }
                 }
@@ -60,14 +96,13 @@ impl SayHooks {
     fn load_bad_sites(&mut self, file_name: &str) {
         match read_lines(file_name) {
             Ok(lines) => {
-                for line in lines {
-                    if let Ok(line) = line {
-                        let line = line.trim().to_lowercase();
-                        if ! line.is_empty() {
-                            self.bad_site_list.insert(line);
-                        }
+                for line in lines.map_while(Result::ok) {
+                    let line = line.trim().to_lowercase();
+                    if ! line.is_empty() {
+                        self.bad_site_list.insert(line);
                     }
                 }
+                info!("Shock site list loaded with {} entries", self.bad_site_list.len());
             }
             Err(e) => {
                 error!("Error parsing shock site list: File {}: {}", file_name, e);
@@ -77,12 +112,10 @@ impl SayHooks {
     fn load_bad_nicks(&mut self, file_name: &str) {
         match read_lines(file_name) {
             Ok(lines) => {
-                for line in lines {
-                    if let Ok(line) = line {
-                        let line = line.trim().to_lowercase();
-                        if ! line.is_empty() {
-                            self.bad_nick_list.insert(line);
-                        }
+                for line in lines.map_while(Result::ok) {
+                    let line = line.trim().to_lowercase();
+                    if ! line.is_empty() {
+                        self.bad_nick_list.insert(line);
                     }
                 }
             }
@@ -91,72 +124,135 @@ impl SayHooks {
             }
         }
     }
-}
 
-struct HistoricalMessage {
-    time : Instant,
-    message_hash : u64,
-    message_length : usize
-}
-
-impl HistoricalMessage {
-    fn new(message : &str) -> Self {
-        let mut s = DefaultHasher::new();
-        message.hash(&mut s);
-        let hash = s.finish();
-        HistoricalMessage { time: Instant::now(), message_hash : hash, message_length : message.len()}
+    fn process_word(&self, word: &str) -> String {
+        match self.bad_word_dict.get(&word.to_lowercase()) {
+            None => word.to_string(),
+            Some(replacement) if word == word.to_uppercase() => replacement.to_uppercase(),
+            Some(replacement) => replacement.clone(),
+        }
     }
-}
-
-
-#[derive(Default)]
-pub struct SpamHandler {
-    lastsaid : HashMap<String, VecDeque<HistoricalMessage>>
-}
 
-impl SpamHandler {
-    pub fn new() -> Self {
-        Self::default()
+    // replaces listed words, words are runs of ascii letters and digits
+    pub fn word_censor(&self, msg: &str) -> String {
+        let mut censored = String::with_capacity(msg.len());
+        let mut word = String::new();
+        for letter in msg.chars() {
+            if letter.is_ascii_alphanumeric() {
+                word.push(letter);
+                continue;
+            }
+            if !word.is_empty() {
+                censored.push_str(&self.process_word(&word));
+                word.clear();
+            }
+            censored.push(letter);
+        }
+        censored.push_str(&self.process_word(&word));
+        censored
     }
 
-    pub fn spamrec(&mut self, channel : &str, message : &str) {
-        let msg = HistoricalMessage::new(message);
-        let chan_history = self.lastsaid.entry(channel.to_string()).or_default();
-        chan_history.push_back(msg);
+    // None if msg links to a shock site, also with the url obfuscated by other characters
+    pub fn site_censor(&self, msg: &str) -> Option<String> {
+        let lower = msg.to_lowercase();
+        let alnum: String = lower.chars().filter(char::is_ascii_alphanumeric).collect();
+        let url: String = lower.chars().filter(|letter| letter.is_ascii_alphanumeric() || "./%".contains(*letter)).collect();
+        if self.bad_site_list.iter().any(|site| lower.contains(site.as_str()) || alnum.contains(site.as_str()) || url.contains(site.as_str())) {
+            return None;
+        }
+        Some(msg.to_string())
     }
 
-    // TODO this is so much work for each message... Find instead of reallocating to 'already'?
-    // TODO write test for spamenum
-    pub fn spam_enum(&mut self, channel : &str) -> bool {
-        let mut already = HashMap::new(); // TODO reuse existing chan_history from beginning to current
-                                      // iter instead
-        let chan_history = self.lastsaid.entry(channel.to_string()).or_default();
-        let now = Instant::now();
-        chan_history.retain(|elem| now - elem.time < Duration::from_secs(5)); // TODO early exit as
-                                                                              // messages are
-                                                                              // sorted
+    // false if msg contains a listed word anywhere, not only as a whole word
+    pub fn nasty_word_censor(&self, msg: &str) -> bool {
+        let msg = msg.to_lowercase();
+        !self.bad_word_dict.keys().any(|word| msg.contains(word.as_str()))
+    }
 
-        let mut bonus : f32 = 0.0;
+    // true if username contains a bad nick, also when hidden by [], _ or leetspeak
+    pub fn isNasty(&self, username: &str) -> bool {
+        let variants = leet_variants(username);
+        self.bad_nick_list.iter().any(|nick| variants.iter().any(|variant| leet_contains(variant, nick)))
+    }
 
-        for msg in chan_history.iter() {
-            let counter = already.entry(&msg.message_hash).or_insert(0);
-            bonus += (2 * *counter) as f32;
-            if msg.message_length > 50 {
-                bonus += std::cmp::min(msg.message_length, 200) as f32 * 0.01;
-            }
-            bonus += 1.0; // something was said
-            *counter += 1;
+    // channel messages, profanity is only rewritten in channels with censoring enabled
+    pub fn hook_SAY(&self, censor_channel: bool, msg: &str) -> Option<String> {
+        let msg = self.site_censor(msg)?;
+        if self.censor && censor_channel {
+            return Some(self.word_censor(&msg));
         }
+        Some(msg)
+    }
 
-        let mut last_time = chan_history.get(0).unwrap().time; 
-        for msg in chan_history.iter().skip(1) {
-			let diff = msg.time - last_time;
-            if diff < Duration::from_secs(1) {
-				bonus += (1.0 - diff.as_secs_f32()) * 1.5;
-            }
-            last_time = msg.time;
+    // private messages are never rewritten, only links to shock sites are dropped
+    pub fn hook_SAYPRIVATE(&self, msg: &str) -> Option<String> {
+        self.site_censor(msg)
+    }
+
+    pub fn hook_OPENBATTLE(&self, title: &str) -> Option<String> {
+        let title = self.site_censor(title)?;
+        if self.censor {
+            return Some(self.word_censor(&title));
         }
-        bonus > 7.0
+        Some(title)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hooks(censor: bool) -> SayHooks {
+        let mut hooks = SayHooks { censor, ..Default::default() };
+        hooks.bad_word_dict.insert("darn".into(), "***".into());
+        hooks.bad_word_dict.insert("heck".into(), "hay".into());
+        hooks.bad_site_list.insert("shock.example".into());
+        hooks
+    }
+
+    #[test]
+    fn test_word_censor() {
+        let hooks = hooks(true);
+        assert_eq!(hooks.word_censor("darn it, what the heck!"), "*** it, what the hay!");
+        assert_eq!(hooks.word_censor("WHAT THE HECK"), "WHAT THE HAY");
+        assert_eq!(hooks.word_censor("Heck"), "hay");
+        assert_eq!(hooks.word_censor("darned hecks"), "darned hecks");
+        assert_eq!(hooks.word_censor(""), "");
+    }
+
+    #[test]
+    fn test_site_censor() {
+        let hooks = hooks(true);
+        assert_eq!(hooks.site_censor("see www.example.com"), Some("see www.example.com".to_string()));
+        assert_eq!(hooks.site_censor("see http://SHOCK.example/x"), None);
+        assert_eq!(hooks.site_censor("see s h o c k . e x a m p l e"), None);
+        assert_eq!(hooks.site_censor("see shock(.)example"), None);
+    }
+
+    #[test]
+    fn test_is_nasty() {
+        let mut hooks = hooks(true);
+        hooks.bad_nick_list.insert("villain".into());
+        assert!(!hooks.isNasty("Hero"));
+        assert!(hooks.isNasty("Villain"));
+        assert!(hooks.isNasty("TheVillain99"));
+        assert!(hooks.isNasty("[V]ill_ain"));
+        assert!(hooks.isNasty("V1LLA1N"));
+        assert!(hooks.isNasty("vi11a1n"));
+        assert!(hooks.isNasty("V1ll41n"));
+        assert!(!hooks.nasty_word_censor("Darnit"));
+        assert!(hooks.nasty_word_censor("Dart"));
+    }
+
+    #[test]
+    fn test_hook_say() {
+        assert_eq!(hooks(true).hook_SAY(true, "darn"), Some("***".to_string()));
+        assert_eq!(hooks(true).hook_SAY(false, "darn"), Some("darn".to_string()));
+        assert_eq!(hooks(false).hook_SAY(true, "darn"), Some("darn".to_string()));
+        assert_eq!(hooks(false).hook_SAY(false, "shock.example"), None);
+        assert_eq!(hooks(false).hook_OPENBATTLE("darn shock.example"), None);
+        assert_eq!(hooks(true).hook_OPENBATTLE("darn game"), Some("*** game".to_string()));
+        assert_eq!(hooks(true).hook_SAYPRIVATE("darn"), Some("darn".to_string()));
+        assert_eq!(hooks(true).hook_SAYPRIVATE("see shock(.)example"), None);
+    }
+}
@@ -11,7 +11,127 @@ table! {
         last_mac_id -> Text,
         ingame_time -> Integer,
         access -> Text,
-        email -> Text,
+        email -> Nullable<Text>,
         bot -> Integer,
     }
 }
+
+table! {
+    ignores (id) {
+        id -> Nullable<Integer>,
+        user_id -> Integer,
+        ignored_user_id -> Integer,
+        reason -> Nullable<Text>,
+        time -> Timestamp,
+    }
+}
+
+table! {
+    friends (id) {
+        id -> Nullable<Integer>,
+        first_user_id -> Integer,
+        second_user_id -> Integer,
+        time -> Timestamp,
+    }
+}
+
+table! {
+    friend_requests (id) {
+        id -> Nullable<Integer>,
+        user_id -> Integer,
+        friend_user_id -> Integer,
+        msg -> Nullable<Text>,
+        time -> Timestamp,
+    }
+}
+
+table! {
+    channels (id) {
+        id -> Nullable<Integer>,
+        name -> Text,
+        key -> Nullable<Text>,
+        owner_user_id -> Nullable<Integer>,
+        topic -> Nullable<Text>,
+        topic_user_id -> Nullable<Integer>,
+        antispam -> Bool,
+        censor -> Bool,
+        store_history -> Bool,
+        last_used -> Timestamp,
+        topic_time -> Nullable<Timestamp>,
+    }
+}
+
+table! {
+    channel_history (id) {
+        id -> Nullable<Integer>,
+        channel_id -> Integer,
+        user_id -> Nullable<Integer>,
+        time -> Timestamp,
+        msg -> Text,
+        ex_msg -> Bool,
+    }
+}
+
+table! {
+    channel_ops (id) {
+        id -> Nullable<Integer>,
+        channel_id -> Integer,
+        user_id -> Integer,
+    }
+}
+
+table! {
+    channel_mutes (id) {
+        id -> Nullable<Integer>,
+        channel_id -> Integer,
+        issuer_user_id -> Nullable<Integer>,
+        user_id -> Integer,
+        expires -> Timestamp,
+        reason -> Text,
+    }
+}
+
+table! {
+    channel_bans (id) {
+        id -> Nullable<Integer>,
+        channel_id -> Integer,
+        issuer_user_id -> Nullable<Integer>,
+        user_id -> Integer,
+        ip_address -> Text,
+        expires -> Timestamp,
+        reason -> Text,
+    }
+}
+
+table! {
+    channel_forwards (id) {
+        id -> Nullable<Integer>,
+        channel_from_id -> Integer,
+        channel_to_id -> Integer,
+    }
+}
+
+table! {
+    access_changes (id) {
+        id -> Nullable<Integer>,
+        user_id -> Integer,
+        issuer_user_id -> Nullable<Integer>,
+        old_access -> Text,
+        new_access -> Text,
+        time -> Timestamp,
+    }
+}
+
+allow_tables_to_appear_in_same_query!(
+    users,
+    ignores,
+    friends,
+    friend_requests,
+    channels,
+    channel_history,
+    channel_ops,
+    channel_mutes,
+    channel_bans,
+    channel_forwards,
+    access_changes,
+);
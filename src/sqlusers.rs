@@ -1,18 +1,25 @@
 use diesel::connection::Connection;
-use diesel::sql_types::Timestamp;
 use diesel::sqlite::SqliteConnection;
 //use diesel::sql_types::VarChar;
 //use diesel::sql_query;
 //use diesel::RunQueryDsl;
-use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
-use crate::schema::users;
+use crate::schema::{access_changes, channel_bans, channel_forwards, channel_history, channel_mutes, channel_ops, channels, friend_requests, friends, ignores, users};
 use chrono::Utc;
+
+use crate::client::Role;
 use chrono::NaiveDateTime;
 
+embed_migrations!();
+
+sql_function!(fn lower(x: diesel::sql_types::Text) -> diesel::sql_types::Text);
+
 pub fn establish_connection(database_url: &str) -> SqliteConnection {
-    SqliteConnection::establish(&database_url)
-        .expect(&format!("Error connecting sqlite to {}", database_url))
+    let conn = SqliteConnection::establish(database_url)
+        .unwrap_or_else(|e| panic!("Error connecting sqlite to {}: {}", database_url, e));
+    embedded_migrations::run(&conn)
+        .unwrap_or_else(|e| panic!("Error running migrations on {}: {}", database_url, e));
+    conn
 }
 
 #[derive(Queryable, Insertable)]
@@ -28,8 +35,8 @@ pub struct User {
     pub last_sys_id: String,
     pub last_mac_id: String,
     pub ingame_time: i32,
-    pub access: String, // user, moderator, admin, bot, agreement, fresh
-    pub email: String,
+    pub access: String, // see client::Role, bot status is the bot column
+    pub email: Option<String>,
     pub bot: i32,
 }
 
@@ -37,22 +44,25 @@ impl User {
     fn new(username: String, password: String, last_ip: String, email: String) -> Self {
         Self {
             id: None,
-            username: username,
-            password: password,
+            username,
+            password,
             register_date: Utc::now().naive_utc(),
             last_login: Utc::now().naive_utc(),
-            last_ip: last_ip,
+            last_ip,
             last_agent: "".into(),
             last_sys_id: "".into(),
             last_mac_id: "".into(),
             ingame_time: 0,
-            access: "agreement".into(),
-            email: email,
+            access: Role::Agreement.to_string(),
+            // avoid triggering uniqueness constraint with empty strings
+            email: if email.is_empty() { None } else { Some(email) },
             bot: 0,
         }
     }
 }
 
+// tables of the python server that nothing reads yet
+#[allow(dead_code)]
 pub struct Verification {
     pub id: i32,
     pub user_id: i32,
@@ -63,6 +73,7 @@ pub struct Verification {
     pub resends: i32,
     pub reason: String,
 }
+#[allow(dead_code)]
 pub struct Login {
     pub id: i32,
     pub user_id: i32,
@@ -75,6 +86,7 @@ pub struct Login {
     pub country: String,
     pub end: NaiveDateTime,
 }
+#[allow(dead_code)]
 pub struct Bridged {
     pub id: i32,
     pub external_id: i32,
@@ -82,61 +94,682 @@ pub struct Bridged {
     pub external_username: String,
     pub last_bridged: NaiveDateTime,
 }
+#[allow(dead_code)]
 pub struct Rename {
     pub id: i32,
     pub user_id: i32,
     pub original: String,
     pub time: NaiveDateTime,
 }
+#[derive(Queryable, Insertable)]
+#[table_name = "ignores"]
 pub struct Ignore {
-    pub id: i32,
+    pub id: Option<i32>,
     pub user_id: i32,
     pub ignored_user_id: i32,
-    pub reason: String,
+    pub reason: Option<String>,
     pub time: NaiveDateTime,
 }
+#[derive(Queryable, Insertable)]
+#[table_name = "friends"]
 pub struct Friend {
-    pub id: i32,
+    pub id: Option<i32>,
     pub first_user_id: i32,
     pub second_user_id: i32,
     pub time: NaiveDateTime,
 }
+#[derive(Queryable, Insertable)]
+#[table_name = "friend_requests"]
 pub struct FriendRequest {
-    pub id: i32,
+    pub id: Option<i32>,
     pub user_id: i32,
     pub friend_user_id: i32,
-    pub msg: String,
+    pub msg: Option<String>,
     pub time: NaiveDateTime,
 }
+#[derive(Queryable, Insertable)]
+#[table_name = "channels"]
 pub struct Channel {
-    pub id: i32,
+    pub id: Option<i32>,
     pub name: String,
-    pub key: String,
-    pub owner_user_id: i32,
-    pub topic: String,
-    pub topic_user_id: i32,
+    pub key: Option<String>,
+    pub owner_user_id: Option<i32>,
+    pub topic: Option<String>,
+    pub topic_user_id: Option<i32>,
     pub antispam: bool,
     pub censor: bool,
     pub store_history: bool,
     pub last_used: NaiveDateTime,
+    pub topic_time: Option<NaiveDateTime>,
 }
+#[derive(Queryable, Insertable)]
+#[table_name = "channel_history"]
 pub struct ChannelHistory {
-    pub id: i32,
+    pub id: Option<i32>,
     pub channel_id: i32,
-    pub user_id: i32,
-    pub bridged_id: i32,
+    pub user_id: Option<i32>,
     pub time: NaiveDateTime,
     pub msg: String,
     pub ex_msg: bool,
 }
-struct UsersHandler {
+#[derive(Queryable, Insertable)]
+#[table_name = "channel_ops"]
+pub struct ChannelOp {
+    pub id: Option<i32>,
+    pub channel_id: i32,
+    pub user_id: i32,
+}
+#[derive(Queryable, Insertable)]
+#[table_name = "channel_mutes"]
+pub struct ChannelMute {
+    pub id: Option<i32>,
+    pub channel_id: i32,
+    pub issuer_user_id: Option<i32>,
+    pub user_id: i32,
+    pub expires: NaiveDateTime,
+    pub reason: String,
+}
+#[derive(Queryable, Insertable)]
+#[table_name = "channel_bans"]
+pub struct ChannelBan {
+    pub id: Option<i32>,
+    pub channel_id: i32,
+    pub issuer_user_id: Option<i32>,
+    pub user_id: i32,
+    pub ip_address: String,
+    pub expires: NaiveDateTime,
+    pub reason: String,
+}
+#[derive(Queryable, Insertable)]
+#[table_name = "channel_forwards"]
+pub struct ChannelForward {
+    pub id: Option<i32>,
+    pub channel_from_id: i32,
+    pub channel_to_id: i32,
+}
+#[derive(Queryable, Insertable)]
+#[table_name = "access_changes"]
+pub struct AccessChange {
+    pub id: Option<i32>,
+    pub user_id: i32,
+    pub issuer_user_id: Option<i32>,
+    pub old_access: String,
+    pub new_access: String,
+    pub time: NaiveDateTime,
+}
+
+// a stored channel message as (id, time, username, msg, ex_msg)
+pub type ChannelMessage = (i32, NaiveDateTime, String, String, bool);
+type ChannelMessageRow = (Option<i32>, NaiveDateTime, Option<String>, String, bool);
+
+pub struct UsersHandler {
     conn : SqliteConnection,
 }
+#[allow(non_snake_case)]
 impl UsersHandler {
-    fn clientFromUsername(&self, name : &str) -> Option<User> {
+    pub fn new(conn : SqliteConnection) -> Self {
+        Self { conn }
+    }
+
+    pub fn channels(&self) -> ChannelsHandler<'_> {
+        ChannelsHandler { conn: &self.conn }
+    }
+
+    pub fn clientFromID(&self, user_id : i32) -> Option<User> {
+        use crate::schema::users::dsl::*;
+        users.filter(id.eq(user_id)).first(&self.conn).ok()
+    }
+
+    pub fn clientFromUsername(&self, name : &str) -> Option<User> {
         use crate::schema::users::dsl::*;
         users.filter(username.eq(name)).first(&self.conn).ok()
     }
+
+    pub fn check_login_user(&self, name : &str, pass : &str) -> Result<(), String> {
+        // password here is BASE64(MD5(...)), matches the register_user DB encoding
+        use crate::schema::users::dsl::*;
+        let dbuser : User = users
+            .filter(lower(username).eq(name.to_lowercase()))
+            .first(&self.conn)
+            .map_err(|_| "Invalid username or password".to_string())?;
+        if dbuser.username != name {
+            // user tried to login with wrong upper/lower case somewhere in their username
+            return Err(format!("Invalid username -- did you mean '{}'", dbuser.username));
+        }
+        if dbuser.password != pass {
+            return Err("Invalid username or password".into());
+        }
+        Ok(())
+    }
+
+    pub fn login_user(&self, name : &str, ip : &str, agent : &str, sys_id : &str, mac_id : &str) -> QueryResult<User> {
+        use crate::schema::users::dsl::*;
+        diesel::update(users.filter(username.eq(name)))
+            .set((
+                last_ip.eq(ip),
+                last_agent.eq(agent),
+                last_sys_id.eq(sys_id),
+                last_mac_id.eq(mac_id),
+                last_login.eq(Utc::now().naive_utc()),
+            ))
+            .execute(&self.conn)?;
+        users.filter(username.eq(name)).first(&self.conn)
+    }
+
+    pub fn end_session(&self, user_id : i32) {
+        use crate::schema::users::dsl::*;
+        // in real its last online / last seen
+        let _ = diesel::update(users.filter(id.eq(user_id)))
+            .set(last_login.eq(Utc::now().naive_utc()))
+            .execute(&self.conn);
+    }
+
+    // the lower user id is stored first, the unique index then covers both directions
+    pub fn friend_users(&self, user_id : i32, friend_user_id : i32) -> QueryResult<()> {
+        let entry = Friend {
+            id: None,
+            first_user_id: user_id.min(friend_user_id),
+            second_user_id: user_id.max(friend_user_id),
+            time: Utc::now().naive_utc(),
+        };
+        diesel::insert_into(friends::table).values(&entry).execute(&self.conn)?;
+        Ok(())
+    }
+
+    pub fn unfriend_users(&self, first_user_id : i32, second_user_id : i32) -> QueryResult<()> {
+        use crate::schema::friends::dsl;
+        diesel::delete(dsl::friends
+            .filter(dsl::first_user_id.eq(first_user_id).and(dsl::second_user_id.eq(second_user_id)))
+            .or_filter(dsl::first_user_id.eq(second_user_id).and(dsl::second_user_id.eq(first_user_id))))
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    pub fn are_friends(&self, first_user_id : i32, second_user_id : i32) -> bool {
+        use crate::schema::friends::dsl;
+        dsl::friends
+            .filter(dsl::first_user_id.eq(first_user_id).and(dsl::second_user_id.eq(second_user_id)))
+            .or_filter(dsl::first_user_id.eq(second_user_id).and(dsl::second_user_id.eq(first_user_id)))
+            .count()
+            .get_result::<i64>(&self.conn)
+            .map(|count| count > 0)
+            .unwrap_or(false)
+    }
+
+    pub fn get_friend_usernames(&self, user_id : i32) -> QueryResult<Vec<String>> {
+        use crate::schema::friends::dsl;
+        let friend_ids : Vec<i32> = dsl::friends
+            .filter(dsl::first_user_id.eq(user_id).or(dsl::second_user_id.eq(user_id)))
+            .select((dsl::first_user_id, dsl::second_user_id))
+            .load::<(i32, i32)>(&self.conn)?
+            .into_iter()
+            .map(|(first, second)| if first == user_id { second } else { first })
+            .collect();
+        users::table
+            .filter(users::id.eq_any(friend_ids))
+            .select(users::username)
+            .load(&self.conn)
+    }
+
+    pub fn has_friend_request(&self, user_id : i32, friend_user_id : i32) -> bool {
+        use crate::schema::friend_requests::dsl;
+        dsl::friend_requests
+            .filter(dsl::user_id.eq(user_id))
+            .filter(dsl::friend_user_id.eq(friend_user_id))
+            .count()
+            .get_result::<i64>(&self.conn)
+            .map(|count| count > 0)
+            .unwrap_or(false)
+    }
+
+    pub fn add_friend_request(&self, user_id : i32, friend_user_id : i32, msg : Option<&str>) -> QueryResult<()> {
+        let entry = FriendRequest {
+            id: None,
+            user_id,
+            friend_user_id,
+            msg: msg.map(|msg| msg.into()),
+            time: Utc::now().naive_utc(),
+        };
+        diesel::insert_into(friend_requests::table).values(&entry).execute(&self.conn)?;
+        Ok(())
+    }
+
+    pub fn remove_friend_request(&self, user_id : i32, friend_user_id : i32) -> QueryResult<()> {
+        use crate::schema::friend_requests::dsl;
+        diesel::delete(dsl::friend_requests
+            .filter(dsl::user_id.eq(user_id))
+            .filter(dsl::friend_user_id.eq(friend_user_id)))
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    // this returns all friend requests sent _to_ user_id as (username, msg)
+    pub fn get_friend_request_list(&self, user_id : i32) -> QueryResult<Vec<(String, Option<String>)>> {
+        friend_requests::table
+            .inner_join(users::table.on(users::id.eq(friend_requests::user_id.nullable())))
+            .filter(friend_requests::friend_user_id.eq(user_id))
+            .select((users::username, friend_requests::msg))
+            .load(&self.conn)
+    }
+
+    pub fn ignore_user(&self, user_id : i32, ignored_user_id : i32, reason : Option<&str>) -> QueryResult<()> {
+        let entry = Ignore {
+            id: None,
+            user_id,
+            ignored_user_id,
+            reason: reason.map(|reason| reason.into()),
+            time: Utc::now().naive_utc(),
+        };
+        diesel::insert_into(ignores::table).values(&entry).execute(&self.conn)?;
+        Ok(())
+    }
+
+    pub fn unignore_user(&self, user_id : i32, ignored_user_id : i32) -> QueryResult<()> {
+        use crate::schema::ignores::dsl;
+        diesel::delete(dsl::ignores
+            .filter(dsl::user_id.eq(user_id))
+            .filter(dsl::ignored_user_id.eq(ignored_user_id)))
+            .execute(&self.conn)?;
+        Ok(())
+    }
+
+    // returns (username, reason) of the users ignored by user_id
+    pub fn get_ignore_list(&self, user_id : i32) -> QueryResult<Vec<(String, Option<String>)>> {
+        ignores::table
+            .inner_join(users::table.on(users::id.eq(ignores::ignored_user_id.nullable())))
+            .filter(ignores::user_id.eq(user_id))
+            .select((users::username, ignores::reason))
+            .load(&self.conn)
+    }
+
+    pub fn get_ignored_user_ids(&self, user_id : i32) -> QueryResult<Vec<i32>> {
+        use crate::schema::ignores::dsl;
+        dsl::ignores
+            .filter(dsl::user_id.eq(user_id))
+            .select(dsl::ignored_user_id)
+            .load(&self.conn)
+    }
+
+    pub fn is_ignored(&self, user_id : i32, ignored_user_id : i32) -> bool {
+        use crate::schema::ignores::dsl;
+        dsl::ignores
+            .filter(dsl::user_id.eq(user_id))
+            .filter(dsl::ignored_user_id.eq(ignored_user_id))
+            .count()
+            .get_result::<i64>(&self.conn)
+            .map(|count| count > 0)
+            .unwrap_or(false)
+    }
+
+    // returns the new total of ingame minutes
+    pub fn add_ingame_time(&self, user_id : i32, minutes : i32) -> QueryResult<i32> {
+        use crate::schema::users::dsl::*;
+        diesel::update(users.filter(id.eq(user_id)))
+            .set(ingame_time.eq(ingame_time + minutes))
+            .execute(&self.conn)?;
+        users.filter(id.eq(user_id)).select(ingame_time).first(&self.conn)
+    }
+
+    pub fn check_register_user(&self, name : &str, mail : Option<&str>) -> Result<(), String> {
+        use crate::schema::users::dsl::*;
+        if name.len() > 20 {
+            return Err("Username too long".into());
+        }
+        if self.username_taken(name, 0).map_err(|e| e.to_string())? {
+            return Err("Username is already in use.".into());
+        }
+        if let Some(mail) = mail {
+            let taken : i64 = users
+                .filter(email.eq(mail))
+                .count()
+                .get_result(&self.conn)
+                .map_err(|e| e.to_string())?;
+            if taken > 0 {
+                return Err("Email address is already in use.".into());
+            }
+        }
+        Ok(())
+    }
+
+    // case insensitive, the user with except_user_id may change the case of their own name
+    pub fn username_taken(&self, name : &str, except_user_id : i32) -> QueryResult<bool> {
+        use crate::schema::users::dsl::*;
+        let taken : i64 = users
+            .filter(lower(username).eq(name.to_lowercase()))
+            .filter(id.ne(except_user_id))
+            .count()
+            .get_result(&self.conn)?;
+        Ok(taken > 0)
+    }
+
+    // assume the new name was already validated
+    pub fn rename_user(&self, user_id : i32, newname : &str) -> Result<(), String> {
+        use crate::schema::users::dsl::*;
+        let renamed = diesel::update(users.filter(id.eq(user_id)))
+            .set(username.eq(newname))
+            .execute(&self.conn)
+            .map_err(|e| e.to_string())?;
+        if renamed == 0 {
+            return Err("You don't seem to exist anymore. Contact an admin or moderator.".into());
+        }
+        Ok(())
+    }
+
+    pub fn register_user(&self, name : &str, pass : &str, ip : &str, mail : &str) -> Result<(), String> {
+        // note: password here is BASE64(MD5(...))
+        // assume check_register_user was already called
+        let user = User::new(name.into(), pass.into(), ip.into(), mail.into());
+        diesel::insert_into(users::table)
+            .values(&user)
+            .execute(&self.conn)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn confirm_agreement(&self, name : &str) {
+        if let Some(user_id) = self.clientFromUsername(name).and_then(|user| user.id) {
+            let _ = self.set_access(user_id, Role::User, Some(user_id));
+        }
+    }
+
+    // changes the role of an account and writes the audit entry, issuer None is the server
+    pub fn set_access(&self, user_id : i32, role : Role, issuer_user_id : Option<i32>) -> Result<(), String> {
+        self.conn.transaction(|| {
+            let old_access: String = users::table.filter(users::id.eq(user_id))
+                .select(users::access)
+                .first(&self.conn)?;
+            diesel::update(users::table.filter(users::id.eq(user_id)))
+                .set(users::access.eq(role.to_string()))
+                .execute(&self.conn)?;
+            diesel::insert_into(access_changes::table)
+                .values(&AccessChange {
+                    id: None,
+                    user_id,
+                    issuer_user_id,
+                    old_access,
+                    new_access: role.to_string(),
+                    time: Utc::now().naive_utc(),
+                })
+                .execute(&self.conn)?;
+            Ok(())
+        }).map_err(|e: diesel::result::Error| match e {
+            diesel::NotFound => "User does not exist".to_string(),
+            e => e.to_string(),
+        })
+    }
+
+    // accounts with the moderator or admin role, older rows may still say moderator or differ in case
+    pub fn moderators(&self) -> QueryResult<Vec<User>> {
+        users::table
+            .filter(lower(users::access).eq_any(vec!["mod", "moderator", "admin"]))
+            .order(lower(users::username))
+            .load(&self.conn)
+    }
+}
+
+// shares the connection of UsersHandler, in-memory databases only exist once per connection
+pub struct ChannelsHandler<'a> {
+    conn : &'a SqliteConnection,
+}
+
+#[allow(non_snake_case)]
+impl<'a> ChannelsHandler<'a> {
+    pub fn channel_from_name(&self, chan : &str) -> Option<Channel> {
+        use crate::schema::channels::dsl::*;
+        channels.filter(name.eq(chan)).first(self.conn).ok()
+    }
+
+    pub fn all_channels(&self) -> QueryResult<Vec<Channel>> {
+        channels::table.load(self.conn)
+    }
+
+    pub fn all_operators(&self) -> QueryResult<Vec<ChannelOp>> {
+        channel_ops::table.load(self.conn)
+    }
+
+    // creates the channel entry owned by founder_user_id, returns its id
+    pub fn register(&self, chan : &str, founder_user_id : i32, topic : Option<&str>) -> QueryResult<i32> {
+        let now = Utc::now().naive_utc();
+        let entry = Channel {
+            id: None,
+            name: chan.into(),
+            key: None,
+            owner_user_id: Some(founder_user_id),
+            topic: topic.map(Into::into),
+            topic_user_id: topic.map(|_| founder_user_id),
+            antispam: false,
+            censor: false,
+            store_history: false,
+            last_used: now,
+            topic_time: topic.map(|_| now),
+        };
+        diesel::insert_into(channels::table).values(&entry).execute(self.conn)?;
+        self.channel_from_name(chan)
+            .and_then(|channel| channel.id)
+            .ok_or(diesel::NotFound)
+    }
+
+    // removes the channel with its operators, mutes, bans, forwards and history
+    pub fn unRegister(&self, channel_id : i32) -> QueryResult<()> {
+        self.conn.transaction(|| {
+            diesel::delete(channel_forwards::table
+                .filter(channel_forwards::channel_from_id.eq(channel_id).or(channel_forwards::channel_to_id.eq(channel_id))))
+                .execute(self.conn)?;
+            diesel::delete(channel_ops::table.filter(channel_ops::channel_id.eq(channel_id))).execute(self.conn)?;
+            diesel::delete(channel_mutes::table.filter(channel_mutes::channel_id.eq(channel_id))).execute(self.conn)?;
+            diesel::delete(channel_bans::table.filter(channel_bans::channel_id.eq(channel_id))).execute(self.conn)?;
+            diesel::delete(channel_history::table.filter(channel_history::channel_id.eq(channel_id))).execute(self.conn)?;
+            diesel::delete(channels::table.filter(channels::id.eq(channel_id))).execute(self.conn)?;
+            Ok(())
+        })
+    }
+
+    pub fn setFounder(&self, channel_id : i32, founder_user_id : i32) -> QueryResult<()> {
+        diesel::update(channels::table.filter(channels::id.eq(channel_id)))
+            .set(channels::owner_user_id.eq(founder_user_id))
+            .execute(self.conn)?;
+        Ok(())
+    }
+
+    pub fn opUser(&self, channel_id : i32, user_id : i32) -> QueryResult<()> {
+        let entry = ChannelOp {
+            id: None,
+            channel_id,
+            user_id,
+        };
+        diesel::insert_into(channel_ops::table).values(&entry).execute(self.conn)?;
+        Ok(())
+    }
+
+    pub fn deopUser(&self, channel_id : i32, user_id : i32) -> QueryResult<()> {
+        diesel::delete(channel_ops::table
+            .filter(channel_ops::channel_id.eq(channel_id))
+            .filter(channel_ops::user_id.eq(user_id)))
+            .execute(self.conn)?;
+        Ok(())
+    }
+
+    pub fn all_mutes(&self) -> QueryResult<Vec<ChannelMute>> {
+        channel_mutes::table.load(self.conn)
+    }
+
+    pub fn all_bans(&self) -> QueryResult<Vec<ChannelBan>> {
+        channel_bans::table.load(self.conn)
+    }
+
+    pub fn muteUser(&self, mute : &ChannelMute) -> QueryResult<()> {
+        diesel::insert_into(channel_mutes::table).values(mute).execute(self.conn)?;
+        Ok(())
+    }
+
+    pub fn unmuteUser(&self, channel_id : i32, user_id : i32) -> QueryResult<()> {
+        diesel::delete(channel_mutes::table
+            .filter(channel_mutes::channel_id.eq(channel_id))
+            .filter(channel_mutes::user_id.eq(user_id)))
+            .execute(self.conn)?;
+        Ok(())
+    }
+
+    pub fn banUser(&self, ban : &ChannelBan) -> QueryResult<()> {
+        diesel::insert_into(channel_bans::table).values(ban).execute(self.conn)?;
+        Ok(())
+    }
+
+    pub fn unbanUser(&self, channel_id : i32, user_id : i32) -> QueryResult<()> {
+        diesel::delete(channel_bans::table
+            .filter(channel_bans::channel_id.eq(channel_id))
+            .filter(channel_bans::user_id.eq(user_id)))
+            .execute(self.conn)?;
+        Ok(())
+    }
+
+    // deletes all expired channel bans/mutes, returns the number of deleted entries
+    pub fn clean(&self) -> QueryResult<usize> {
+        let now = Utc::now().naive_utc();
+        let mutes = diesel::delete(channel_mutes::table.filter(channel_mutes::expires.lt(now))).execute(self.conn)?;
+        let bans = diesel::delete(channel_bans::table.filter(channel_bans::expires.lt(now))).execute(self.conn)?;
+        Ok(mutes + bans)
+    }
+
+    pub fn all_forwards(&self) -> QueryResult<Vec<ChannelForward>> {
+        channel_forwards::table.load(self.conn)
+    }
+
+    pub fn addForward(&self, channel_from_id : i32, channel_to_id : i32) -> QueryResult<()> {
+        let entry = ChannelForward {
+            id: None,
+            channel_from_id,
+            channel_to_id,
+        };
+        diesel::insert_into(channel_forwards::table).values(&entry).execute(self.conn)?;
+        Ok(())
+    }
+
+    pub fn removeForward(&self, channel_from_id : i32, channel_to_id : i32) -> QueryResult<()> {
+        diesel::delete(channel_forwards::table
+            .filter(channel_forwards::channel_from_id.eq(channel_from_id))
+            .filter(channel_forwards::channel_to_id.eq(channel_to_id)))
+            .execute(self.conn)?;
+        Ok(())
+    }
+
+    // None removes the key
+    pub fn setKey(&self, channel_id : i32, key : Option<&str>) -> QueryResult<()> {
+        diesel::update(channels::table.filter(channels::id.eq(channel_id)))
+            .set(channels::key.eq(key))
+            .execute(self.conn)?;
+        Ok(())
+    }
+
+    // adds the channel if it is not in the table yet, returns its id
+    pub fn setHistory(&self, chan : &str, enable : bool) -> QueryResult<i32> {
+        use crate::schema::channels::dsl::*;
+        if self.channel_from_name(chan).is_none() {
+            diesel::insert_into(channels)
+                .values((name.eq(chan), last_used.eq(Utc::now().naive_utc())))
+                .execute(self.conn)?;
+        }
+        diesel::update(channels.filter(name.eq(chan)))
+            .set(store_history.eq(enable))
+            .execute(self.conn)?;
+        channels.filter(name.eq(chan))
+            .select(id)
+            .first::<Option<i32>>(self.conn)
+            .map(|channel_id| channel_id.unwrap_or_default())
+    }
+
+    pub fn setAntispam(&self, chan : &str, enable : bool) -> QueryResult<()> {
+        use crate::schema::channels::dsl::*;
+        diesel::update(channels.filter(name.eq(chan)))
+            .set(antispam.eq(enable))
+            .execute(self.conn)?;
+        Ok(())
+    }
+
+    pub fn setCensor(&self, chan : &str, enable : bool) -> QueryResult<()> {
+        use crate::schema::channels::dsl::*;
+        diesel::update(channels.filter(name.eq(chan)))
+            .set(censor.eq(enable))
+            .execute(self.conn)?;
+        Ok(())
+    }
+
+    // an empty topic removes it
+    pub fn setTopic(&self, chan : &str, new_topic : &str, user_id : i32) -> QueryResult<()> {
+        use crate::schema::channels::dsl::*;
+        let new_topic = (!new_topic.is_empty()).then_some(new_topic);
+        diesel::update(channels.filter(name.eq(chan)))
+            .set((topic.eq(new_topic), topic_user_id.eq(user_id), topic_time.eq(Utc::now().naive_utc())))
+            .execute(self.conn)?;
+        Ok(())
+    }
+
+    // returns the id of the stored message
+    pub fn add_channel_message(&self, channel_id : i32, user_id : i32, msg : &str, ex_msg : bool) -> QueryResult<i32> {
+        let entry = ChannelHistory {
+            id: None,
+            channel_id,
+            user_id: Some(user_id),
+            time: Utc::now().naive_utc(),
+            msg: msg.into(),
+            ex_msg,
+        };
+        diesel::insert_into(channel_history::table).values(&entry).execute(self.conn)?;
+        channel_history::table
+            .select(channel_history::id)
+            .order(channel_history::id.desc())
+            .first::<Option<i32>>(self.conn)
+            .map(|id| id.unwrap_or_default())
+    }
+
+    // messages of the channel newer than last_msg_id, oldest first
+    pub fn get_channel_messages(&self, channel_id : i32, last_msg_id : i32, limit : i64) -> QueryResult<Vec<ChannelMessage>> {
+        let mut messages : Vec<ChannelMessageRow> = channel_history::table
+            .left_join(users::table.on(users::id.eq(channel_history::user_id)))
+            .filter(channel_history::channel_id.eq(channel_id))
+            .filter(channel_history::id.gt(last_msg_id))
+            .order(channel_history::id.desc())
+            .limit(limit)
+            .select((channel_history::id, channel_history::time, users::username.nullable(), channel_history::msg, channel_history::ex_msg))
+            .load(self.conn)?;
+        messages.reverse();
+        Ok(messages
+            .into_iter()
+            .map(|(id, time, username, msg, ex_msg)| {
+                (id.unwrap_or_default(), time, username.unwrap_or_else(|| "?".into()), msg, ex_msg)
+            })
+            .collect())
+    }
+
+    // deletes messages older than max_age and all but the newest max_messages of each channel,
+    // returns the number of deleted messages
+    pub fn clean_history(&self, max_age : chrono::Duration, max_messages : i64) -> QueryResult<usize> {
+        let oldest = Utc::now().naive_utc() - max_age;
+        let mut deleted = diesel::delete(channel_history::table.filter(channel_history::time.lt(oldest)))
+            .execute(self.conn)?;
+        let channel_ids : Vec<i32> = channel_history::table
+            .select(channel_history::channel_id)
+            .distinct()
+            .load(self.conn)?;
+        for channel_id in channel_ids {
+            // the newest message over the cap, it and everything older goes
+            let cutoff = channel_history::table
+                .filter(channel_history::channel_id.eq(channel_id))
+                .order(channel_history::id.desc())
+                .offset(max_messages)
+                .select(channel_history::id)
+                .first::<Option<i32>>(self.conn)
+                .optional()?
+                .flatten();
+            if let Some(cutoff) = cutoff {
+                deleted += diesel::delete(channel_history::table
+                    .filter(channel_history::channel_id.eq(channel_id))
+                    .filter(channel_history::id.le(cutoff)))
+                    .execute(self.conn)?;
+            }
+        }
+        Ok(deleted)
+    }
 }
 
 #[cfg(test)]
@@ -145,6 +778,7 @@ mod tests {
     // embed_migrations!("./migrations/sqlite");
 
     use super::*;
+    use diesel::connection::SimpleConnection;
 
     #[test]
     fn test_sqlite_connection() {
@@ -153,9 +787,7 @@ mod tests {
 
     fn get_db() -> SqliteConnection {
         let database_url = ":memory:";
-        let database_url = "example.db";
         let db = establish_connection(database_url);
-        // embedded_migrations::run(&db); TODO enable auto-migrations for tests?
         db.batch_execute("PRAGMA journal_mode = MEMORY; PRAGMA synchronous = OFF;")
             .unwrap();
         db
@@ -197,4 +829,309 @@ mod tests {
         assert!(handler.clientFromUsername("test").is_some());
         assert!(handler.clientFromUsername("test2").is_some());
     }
+
+    #[test]
+    fn test_register_login() {
+        let handler = UsersHandler::new(get_db());
+
+        assert!(handler.check_register_user("test", None).is_ok());
+        handler.register_user("test", "pass", "192.168.1.1", "").unwrap();
+        handler.register_user("test2", "pass", "192.168.1.2", "").unwrap();
+        assert!(handler.check_register_user("test", None).is_err());
+        assert!(handler.check_register_user("TEST", None).is_err());
+
+        let user_id = handler.clientFromUsername("test").unwrap().id.unwrap();
+        assert!(handler.username_taken("Test", 0).unwrap());
+        assert!(!handler.username_taken("Test", user_id).unwrap());
+        assert!(handler.username_taken("TEST2", user_id).unwrap());
+
+        assert!(handler.check_login_user("test", "pass").is_ok());
+        assert!(handler.check_login_user("test", "wrong").is_err());
+        assert!(handler.check_login_user("Test", "pass").is_err());
+        assert!(handler.check_login_user("nobody", "pass").is_err());
+
+        let user = handler.login_user("test", "10.0.0.1", "lobby 1.0", "0", "0").unwrap();
+        assert_eq!(user.last_ip, "10.0.0.1");
+        assert_eq!(user.last_agent, "lobby 1.0");
+        assert_eq!(user.access, "agreement");
+
+        handler.confirm_agreement("test");
+        assert_eq!(handler.clientFromUsername("test").unwrap().access, "user");
+    }
+
+    #[test]
+    fn test_rename_user() {
+        let handler = UsersHandler::new(get_db());
+        handler.register_user("test", "pass", "192.168.1.1", "").unwrap();
+        let user_id = handler.clientFromUsername("test").unwrap().id.unwrap();
+
+        handler.rename_user(user_id, "renamed").unwrap();
+        assert!(handler.clientFromUsername("test").is_none());
+        assert_eq!(handler.clientFromUsername("renamed").unwrap().id, Some(user_id));
+        assert!(handler.check_login_user("renamed", "pass").is_ok());
+        assert!(handler.rename_user(user_id + 1, "other").is_err());
+    }
+
+    #[test]
+    fn test_access_changes() {
+        // the audit entries of an account, oldest first
+        fn access_changes(handler: &UsersHandler, user_id: i32) -> Vec<AccessChange> {
+            access_changes::table
+                .filter(access_changes::user_id.eq(user_id))
+                .order(access_changes::id)
+                .load(&handler.conn)
+                .unwrap()
+        }
+
+        let handler = UsersHandler::new(get_db());
+        handler.register_user("admin", "pass", "192.168.1.1", "").unwrap();
+        handler.register_user("test", "pass", "192.168.1.2", "").unwrap();
+        let admin_id = handler.clientFromUsername("admin").unwrap().id.unwrap();
+        let user_id = handler.clientFromUsername("test").unwrap().id.unwrap();
+        assert!(handler.moderators().unwrap().is_empty());
+
+        handler.confirm_agreement("test");
+        handler.set_access(admin_id, Role::Admin, None).unwrap();
+        handler.set_access(user_id, Role::Moderator, Some(admin_id)).unwrap();
+        assert_eq!(handler.clientFromUsername("test").unwrap().access, "mod");
+        let moderators: Vec<String> = handler.moderators().unwrap().into_iter().map(|user| user.username).collect();
+        assert_eq!(moderators, vec!["admin", "test"]);
+        // written by older servers or by hand
+        diesel::update(users::table.filter(users::id.eq(user_id))).set(users::access.eq("Moderator")).execute(&handler.conn).unwrap();
+        assert_eq!(handler.moderators().unwrap().len(), 2);
+
+        let changes = access_changes(&handler, user_id);
+        assert_eq!(changes.len(), 2);
+        assert_eq!((changes[0].issuer_user_id, changes[0].old_access.as_str(), changes[0].new_access.as_str()), (Some(user_id), "agreement", "user"));
+        assert_eq!((changes[1].issuer_user_id, changes[1].old_access.as_str(), changes[1].new_access.as_str()), (Some(admin_id), "user", "mod"));
+        assert_eq!(access_changes(&handler, admin_id)[0].issuer_user_id, None);
+
+        assert!(handler.set_access(user_id + 100, Role::User, None).is_err());
+    }
+
+    #[test]
+    fn test_ingame_time() {
+        let handler = UsersHandler::new(get_db());
+        handler.register_user("test", "pass", "192.168.1.1", "").unwrap();
+        let user_id = handler.clientFromUsername("test").unwrap().id.unwrap();
+
+        assert_eq!(handler.add_ingame_time(user_id, 30).unwrap(), 30);
+        assert_eq!(handler.add_ingame_time(user_id, 45).unwrap(), 75);
+        assert_eq!(handler.clientFromUsername("test").unwrap().ingame_time, 75);
+    }
+
+    #[test]
+    fn test_is_ignored() {
+        let handler = UsersHandler::new(get_db());
+        handler.register_user("test", "pass", "192.168.1.1", "").unwrap();
+        handler.register_user("test2", "pass", "192.168.1.2", "").unwrap();
+        let first = handler.clientFromUsername("test").unwrap().id.unwrap();
+        let second = handler.clientFromUsername("test2").unwrap().id.unwrap();
+
+        handler.ignore_user(first, second, Some("spam")).unwrap();
+        assert!(handler.is_ignored(first, second));
+        assert!(!handler.is_ignored(second, first));
+        assert_eq!(handler.get_ignored_user_ids(first).unwrap(), vec![second]);
+        assert_eq!(handler.get_ignore_list(first).unwrap(), vec![("test2".to_string(), Some("spam".to_string()))]);
+
+        handler.unignore_user(first, second).unwrap();
+        assert!(!handler.is_ignored(first, second));
+        assert!(handler.get_ignore_list(first).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_friends() {
+        let handler = UsersHandler::new(get_db());
+        handler.register_user("test", "pass", "192.168.1.1", "").unwrap();
+        handler.register_user("test2", "pass", "192.168.1.2", "").unwrap();
+        handler.register_user("test3", "pass", "192.168.1.3", "").unwrap();
+        let first = handler.clientFromUsername("test").unwrap().id.unwrap();
+        let second = handler.clientFromUsername("test2").unwrap().id.unwrap();
+        let third = handler.clientFromUsername("test3").unwrap().id.unwrap();
+
+        handler.add_friend_request(first, second, Some("hi")).unwrap();
+        handler.add_friend_request(third, second, None).unwrap();
+        assert!(handler.has_friend_request(first, second));
+        assert!(!handler.has_friend_request(second, first));
+        let mut requests = handler.get_friend_request_list(second).unwrap();
+        requests.sort();
+        assert_eq!(requests, vec![("test".to_string(), Some("hi".to_string())), ("test3".to_string(), None)]);
+
+        handler.friend_users(second, first).unwrap();
+        handler.remove_friend_request(first, second).unwrap();
+        assert!(!handler.has_friend_request(first, second));
+        assert!(handler.are_friends(first, second));
+        assert!(handler.are_friends(second, first));
+        assert!(!handler.are_friends(first, third));
+        assert_eq!(handler.get_friend_usernames(first).unwrap(), vec!["test2".to_string()]);
+        assert_eq!(handler.get_friend_usernames(second).unwrap(), vec!["test".to_string()]);
+
+        assert!(handler.friend_users(first, second).is_err());
+        assert_eq!(handler.get_friend_usernames(first).unwrap(), vec!["test2".to_string()]);
+
+        handler.unfriend_users(first, second).unwrap();
+        assert!(!handler.are_friends(second, first));
+        assert!(handler.get_friend_usernames(second).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_channel_history() {
+        let handler = UsersHandler::new(get_db());
+        handler.register_user("test", "pass", "192.168.1.1", "").unwrap();
+        let user_id = handler.clientFromUsername("test").unwrap().id.unwrap();
+        let channels = handler.channels();
+        channels.register("main", user_id, None).unwrap();
+
+        channels.setHistory("main", true).unwrap();
+        channels.setAntispam("main", true).unwrap();
+        channels.setCensor("main", true).unwrap();
+        let channel = channels.channel_from_name("main").unwrap();
+        assert!(channel.store_history && channel.antispam && channel.censor);
+        let channel_id = channel.id.unwrap();
+
+        let first = channels.add_channel_message(channel_id, user_id, "hello", false).unwrap();
+        let second = channels.add_channel_message(channel_id, user_id, "waves", true).unwrap();
+        assert!(second > first);
+
+        let messages = channels.get_channel_messages(channel_id, 0, 100).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!((messages[0].2.as_str(), messages[0].3.as_str(), messages[0].4), ("test", "hello", false));
+        assert_eq!((messages[1].0, messages[1].4), (second, true));
+        assert_eq!(channels.get_channel_messages(channel_id, first, 100).unwrap().len(), 1);
+        // the limit keeps the newest messages
+        assert_eq!(channels.get_channel_messages(channel_id, 0, 1).unwrap()[0].0, second);
+
+        assert_eq!(channels.clean_history(chrono::Duration::days(14), 100).unwrap(), 0);
+        // over the cap the oldest messages of a channel go first
+        let third = channels.add_channel_message(channel_id, user_id, "bye", false).unwrap();
+        assert_eq!(channels.clean_history(chrono::Duration::days(14), 2).unwrap(), 1);
+        let ids: Vec<i32> = channels.get_channel_messages(channel_id, 0, 100).unwrap().iter().map(|message| message.0).collect();
+        assert_eq!(ids, vec![second, third]);
+        assert_eq!(channels.clean_history(chrono::Duration::seconds(-1), 100).unwrap(), 2);
+        assert!(channels.get_channel_messages(channel_id, 0, 100).unwrap().is_empty());
+
+        // channels missing from the table are added
+        assert_eq!(channels.setHistory("main", false).unwrap(), channel_id);
+        let other_id = channels.setHistory("other", true).unwrap();
+        assert_ne!(other_id, channel_id);
+        assert!(channels.channel_from_name("other").unwrap().store_history);
+    }
+
+    #[test]
+    fn test_channel_topic() {
+        let handler = UsersHandler::new(get_db());
+        handler.register_user("test", "pass", "192.168.1.1", "").unwrap();
+        let user_id = handler.clientFromUsername("test").unwrap().id.unwrap();
+        let channels = handler.channels();
+        channels.register("main", user_id, None).unwrap();
+
+        channels.setTopic("main", "welcome", user_id).unwrap();
+        let channel = channels.channel_from_name("main").unwrap();
+        assert_eq!(channel.topic.as_deref(), Some("welcome"));
+        assert_eq!(channel.topic_user_id, Some(user_id));
+        assert!(channel.topic_time.is_some());
+        assert_eq!(handler.clientFromID(user_id).unwrap().username, "test");
+
+        channels.setTopic("main", "", user_id).unwrap();
+        assert!(channels.channel_from_name("main").unwrap().topic.is_none());
+    }
+
+    #[test]
+    fn test_channel_registration() {
+        let handler = UsersHandler::new(get_db());
+        handler.register_user("founder", "pass", "192.168.1.1", "").unwrap();
+        handler.register_user("op", "pass", "192.168.1.1", "").unwrap();
+        let founder_id = handler.clientFromUsername("founder").unwrap().id.unwrap();
+        let op_id = handler.clientFromUsername("op").unwrap().id.unwrap();
+
+        let channels = handler.channels();
+        let channel_id = channels.register("main", founder_id, Some("welcome")).unwrap();
+        assert!(channels.register("main", op_id, None).is_err());
+        let channel = channels.channel_from_name("main").unwrap();
+        assert_eq!((channel.owner_user_id, channel.topic.as_deref()), (Some(founder_id), Some("welcome")));
+
+        channels.opUser(channel_id, op_id).unwrap();
+        assert!(channels.opUser(channel_id, op_id).is_err());
+        let ops = channels.all_operators().unwrap();
+        assert_eq!((ops.len(), ops[0].channel_id, ops[0].user_id), (1, channel_id, op_id));
+
+        channels.setFounder(channel_id, op_id).unwrap();
+        assert_eq!(channels.all_channels().unwrap()[0].owner_user_id, Some(op_id));
+        channels.deopUser(channel_id, op_id).unwrap();
+        assert!(channels.all_operators().unwrap().is_empty());
+
+        channels.opUser(channel_id, founder_id).unwrap();
+        channels.unRegister(channel_id).unwrap();
+        assert!(channels.channel_from_name("main").is_none());
+        assert!(channels.all_operators().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_channel_mutes_bans() {
+        let handler = UsersHandler::new(get_db());
+        handler.register_user("founder", "pass", "192.168.1.1", "").unwrap();
+        handler.register_user("spammer", "pass", "192.168.1.2", "").unwrap();
+        let founder_id = handler.clientFromUsername("founder").unwrap().id.unwrap();
+        let spammer_id = handler.clientFromUsername("spammer").unwrap().id.unwrap();
+        let channels = handler.channels();
+        let channel_id = channels.register("main", founder_id, None).unwrap();
+
+        let now = Utc::now().naive_utc();
+        let mute = ChannelMute {
+            id: None,
+            channel_id,
+            issuer_user_id: Some(founder_id),
+            user_id: spammer_id,
+            expires: now + chrono::Duration::minutes(10),
+            reason: "spam".into(),
+        };
+        channels.muteUser(&mute).unwrap();
+        assert!(channels.muteUser(&mute).is_err());
+        let ban = ChannelBan {
+            id: None,
+            channel_id,
+            issuer_user_id: None,
+            user_id: spammer_id,
+            ip_address: "192.168.1.2".into(),
+            expires: now - chrono::Duration::minutes(1),
+            reason: "spam".into(),
+        };
+        channels.banUser(&ban).unwrap();
+        assert_eq!(channels.all_bans().unwrap()[0].ip_address, "192.168.1.2");
+
+        // only the expired ban is removed
+        assert_eq!(channels.clean().unwrap(), 1);
+        assert!(channels.all_bans().unwrap().is_empty());
+        assert_eq!(channels.all_mutes().unwrap()[0].reason, "spam");
+
+        channels.unmuteUser(channel_id, spammer_id).unwrap();
+        assert!(channels.all_mutes().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_channel_keys_forwards() {
+        let handler = UsersHandler::new(get_db());
+        handler.register_user("founder", "pass", "192.168.1.1", "").unwrap();
+        let founder_id = handler.clientFromUsername("founder").unwrap().id.unwrap();
+        let channels = handler.channels();
+        let main_id = channels.register("main", founder_id, None).unwrap();
+        let newbies_id = channels.register("newbies", founder_id, None).unwrap();
+
+        channels.setKey(main_id, Some("secret")).unwrap();
+        assert_eq!(channels.channel_from_name("main").unwrap().key.as_deref(), Some("secret"));
+        channels.setKey(main_id, None).unwrap();
+        assert!(channels.channel_from_name("main").unwrap().key.is_none());
+
+        channels.addForward(main_id, newbies_id).unwrap();
+        assert!(channels.addForward(main_id, newbies_id).is_err());
+        let forwards = channels.all_forwards().unwrap();
+        assert_eq!((forwards[0].channel_from_id, forwards[0].channel_to_id), (main_id, newbies_id));
+        channels.removeForward(main_id, newbies_id).unwrap();
+        assert!(channels.all_forwards().unwrap().is_empty());
+
+        // forwards from and to unregistered channels are dropped
+        channels.addForward(newbies_id, main_id).unwrap();
+        channels.unRegister(main_id).unwrap();
+        assert!(channels.all_forwards().unwrap().is_empty());
+    }
 }
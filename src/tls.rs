@@ -0,0 +1,123 @@
+use log::info;
+use std::fs;
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+// used when no certificate is configured, generated on the first start
+pub const DEFAULT_CERT: &str = "server.crt";
+pub const DEFAULT_KEY: &str = "server.key";
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/**Acceptor for STLS and the TLS port.
+
+Without configured paths the self-signed certificate in server.crt and
+server.key is used, it is generated when neither file exists yet.
+Configured files have to exist.
+*/
+pub fn acceptor(cert: Option<&str>, key: Option<&str>) -> io::Result<TlsAcceptor> {
+    let config = match (cert, key) {
+        (Some(cert), Some(key)) => load(cert, key)?,
+        (None, None) => {
+            if !Path::new(DEFAULT_CERT).exists() && !Path::new(DEFAULT_KEY).exists() {
+                generate(DEFAULT_CERT, DEFAULT_KEY)?;
+            }
+            load(DEFAULT_CERT, DEFAULT_KEY)?
+        }
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "both a certificate and a key are needed for TLS")),
+    };
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// writes a self-signed certificate and its key, the key is only readable by the owner
+pub fn generate(cert: &str, key: &str) -> io::Result<()> {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])
+        .map_err(|e| invalid(e.to_string()))?;
+    let cert_pem = generated.serialize_pem().map_err(|e| invalid(e.to_string()))?;
+    // created with its permissions, so the key is never readable by others
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(key)?.write_all(generated.serialize_private_key_pem().as_bytes())?;
+    fs::write(cert, cert_pem)?;
+    info!("Generated self-signed TLS certificate {} with key {}", cert, key);
+    Ok(())
+}
+
+// the certificate chain and first private key of the PEM files
+pub fn load(cert: &str, key: &str) -> io::Result<ServerConfig> {
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut BufReader::new(fs::File::open(cert)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate found in {}", cert)));
+    }
+    let key_der = rustls_pemfile::read_all(&mut BufReader::new(fs::File::open(key)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(der) | rustls_pemfile::Item::RSAKey(der) | rustls_pemfile::Item::ECKey(der) => Some(der),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(format!("no private key found in {}", key)))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, PrivateKey(key_der))
+        .map_err(|e| invalid(format!("{} / {}: {}", cert, key, e)))?;
+    info!("Loaded TLS certificate {}", cert);
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("uberserver-{}-{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn test_generate_and_load() {
+        let (cert, key) = (temp_path("test.crt"), temp_path("test.key"));
+        let _ = fs::remove_file(&key);
+        generate(&cert, &key).unwrap();
+        assert!(load(&cert, &key).is_ok());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&key).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        // an existing key is never overwritten
+        assert!(generate(&cert, &key).is_err());
+        assert!(fs::read_to_string(&cert).unwrap().starts_with("-----BEGIN CERTIFICATE-----"));
+        assert!(acceptor(Some(&cert), Some(&key)).is_ok());
+        let _ = fs::remove_file(cert);
+        let _ = fs::remove_file(key);
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let (cert, key) = (temp_path("invalid.crt"), temp_path("invalid.key"));
+        assert!(load(&cert, &key).is_err());
+        fs::write(&cert, "not a certificate").unwrap();
+        fs::write(&key, "not a key").unwrap();
+        assert!(load(&cert, &key).is_err());
+        assert!(acceptor(Some(&cert), None).is_err());
+        let _ = fs::remove_file(cert);
+        let _ = fs::remove_file(key);
+    }
+}
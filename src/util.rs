@@ -0,0 +1,40 @@
+use chrono::Duration;
+
+// human readable duration like "2 hours 5 minutes"
+pub fn pretty_time_delta(duration : Duration) -> String {
+    let days = duration.num_days();
+    let hours = duration.num_hours() % 24;
+    let minutes = duration.num_minutes() % 60;
+    let seconds = duration.num_seconds() % 60;
+    if days > 900 {
+        return "a long time".into();
+    }
+    let mut pretty = String::new();
+    if days > 0 {
+        pretty += &format!("{} days ", days);
+    }
+    if (days > 0 && minutes > 0) || hours > 0 {
+        pretty += &format!("{} hours ", hours);
+    }
+    if (days > 0 && hours > 0) || minutes > 0 {
+        pretty += &format!("{} minutes ", minutes);
+    }
+    if days == 0 && hours == 0 && minutes == 0 {
+        pretty += &format!("{} seconds ", seconds.max(0));
+    }
+    pretty.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pretty_time_delta() {
+        assert_eq!(pretty_time_delta(Duration::seconds(42)), "42 seconds");
+        assert_eq!(pretty_time_delta(Duration::minutes(125)), "2 hours 5 minutes");
+        assert_eq!(pretty_time_delta(Duration::days(3)), "3 days");
+        assert_eq!(pretty_time_delta(Duration::days(1000)), "a long time");
+        assert_eq!(pretty_time_delta(Duration::seconds(-5)), "0 seconds");
+    }
+}
@@ -0,0 +1,117 @@
+use futures::{SinkExt, StreamExt};
+use log::{debug, info};
+use tokio::io::{self, DuplexStream};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::StatusCode;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+
+// larger frames are refused by the handshake layer already, lines are limited again by process
+const MAX_MESSAGE_SIZE: usize = 64 << 10;
+const BUFFER_SIZE: usize = 64 << 10;
+
+/**Whether a handshake with this Origin header is accepted.
+
+Browsers always send the origin of the page, so only the configured ones may
+connect, "*" allows all. Other clients don't send one, they are only accepted
+while no origin is configured.
+*/
+pub fn origin_allowed(origin: Option<&str>, allowed: &[String]) -> bool {
+    match origin {
+        None => allowed.is_empty(),
+        Some(origin) => allowed.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin)),
+    }
+}
+
+/**Does the WebSocket handshake and returns the connection as a line stream.
+
+Each text frame is one command, every line sent back is one text frame. The
+frames are relayed by a separate task, so the returned stream can be used by
+process like a TCP connection.
+*/
+pub async fn accept(stream: TcpStream, allowed_origins: &[String]) -> Result<DuplexStream, Error> {
+    // the error type is tungstenite's
+    #[allow(clippy::result_large_err)]
+    let check_origin = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+        let origin = request.headers().get("Origin").map(|origin| origin.to_str().unwrap_or("?"));
+        if origin_allowed(origin, allowed_origins) {
+            return Ok(response);
+        }
+        info!("WebSocket connection from origin {:?} refused", origin);
+        let mut error = ErrorResponse::new(Some("Origin not allowed".into()));
+        *error.status_mut() = StatusCode::FORBIDDEN;
+        Err(error)
+    };
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_MESSAGE_SIZE),
+        ..Default::default()
+    };
+    let ws = tokio_tungstenite::accept_hdr_async_with_config(stream, check_origin, Some(config)).await?;
+    let (lines, relayed) = io::duplex(BUFFER_SIZE);
+    tokio::spawn(relay(ws, relayed));
+    Ok(lines)
+}
+
+// copies frames to lines and back until either side is closed
+async fn relay(ws: WebSocketStream<TcpStream>, lines: DuplexStream) {
+    let (mut ws_sink, mut ws_stream) = ws.split();
+    let (reader, writer) = io::split(lines);
+    let mut outgoing = FramedRead::new(reader, LinesCodec::new());
+    let mut incoming = FramedWrite::new(writer, LinesCodec::new());
+
+    loop {
+        tokio::select! {
+            frame = ws_stream.next() => match frame {
+                Some(Ok(Message::Text(text))) => {
+                    let command = text.trim_end_matches(['\r', '\n']);
+                    if command.contains('\n') {
+                        debug!("WebSocket frame with several commands, closing");
+                        break;
+                    }
+                    if incoming.send(command).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(Message::Binary(_))) => {
+                    debug!("binary WebSocket frame, closing");
+                    break;
+                }
+                // pings are answered by tungstenite
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+            },
+            line = outgoing.next() => match line {
+                Some(Ok(line)) => {
+                    if ws_sink.send(Message::Text(line)).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Err(_)) | None => break,
+            },
+        }
+    }
+    let _ = ws_sink.send(Message::Close(None)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_origin_allowed() {
+        let allowed = vec!["https://lobby.example.com".to_string()];
+        assert!(!origin_allowed(None, &allowed));
+        assert!(!origin_allowed(None, &["*".to_string()]));
+        assert!(origin_allowed(None, &[]));
+        assert!(origin_allowed(Some("https://lobby.example.com"), &allowed));
+        assert!(origin_allowed(Some("HTTPS://Lobby.Example.com"), &allowed));
+        assert!(!origin_allowed(Some("https://evil.example.com"), &allowed));
+        assert!(!origin_allowed(Some("https://lobby.example.com.evil.com"), &allowed));
+        assert!(!origin_allowed(Some("https://lobby.example.com"), &[]));
+        assert!(origin_allowed(Some("https://anywhere.example.com"), &["*".to_string()]));
+    }
+}